@@ -0,0 +1,269 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Throughput and round-trip latency of handshaked connections over loopback, side by side with the framing
+//! the crate had before records(`baseline`). Both use sockets with the default options. Run with `cargo bench --bench framing`
+
+use korneplod::Message;
+use korneplod::client::Client;
+use korneplod::server::Server;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const SIZES: [usize; 5] = [5, 100, 1000, 64 * 1024, 1024 * 1024];
+
+const LOOPBACK: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+//A message that doesn't come within this means the framing broke and the receiver waits for bytes nobody sends
+const STALL: Duration = Duration::from_secs(10);
+
+///What both framings do in the benchmarks
+trait Connection: Send + 'static {
+	async fn send(&mut self, mes: Message) -> io::Result<()>;
+	async fn receive(&mut self) -> io::Result<Message>;
+}
+
+impl Connection for Client {
+	#[inline]
+	async fn send(&mut self, mes: Message) -> io::Result<()> {
+		self.send_message(mes).await
+	}
+
+	#[inline]
+	async fn receive(&mut self) -> io::Result<Message> {
+		self.get_message().await
+	}
+}
+
+///The read and write loops of `Client` before records, copied as they were: the message is sent in 1 KiB pieces,
+///the last one whole whatever is left in it, and copied byte by byte on both sides.
+///A short read loses the rest of its piece, after that the framing is broken and the size is reported as such
+mod baseline {
+	use super::*;
+
+	use async_net::TcpStream;
+	use chacha20::ChaCha20;
+	use chacha20::cipher::StreamCipher;
+	use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+	pub struct OldClient {
+		stream: TcpStream,
+		cipher: ChaCha20
+	}
+
+	///Connects a pair over loopback, both with the default cipher as there's no handshake
+	pub fn pair() -> (OldClient, OldClient) {
+		futures::executor::block_on(async {
+			let listener = async_net::TcpListener::bind(LOOPBACK).await.unwrap();
+			let addr = listener.local_addr().unwrap();
+
+			let (accepted, connected) = futures::join!(listener.accept(), TcpStream::connect(addr));
+			let new = |stream| OldClient{ stream, cipher: korneplod::default_chacha20_cipher() };
+
+			(new(accepted.unwrap().0), new(connected.unwrap()))
+		})
+	}
+
+	impl Connection for OldClient {
+		async fn send(&mut self, mes: Message) -> io::Result<()> {
+			let mut mib = mes.as_bytes_once();
+			self.cipher.apply_keystream(&mut mib);
+			let data_size: [u8; 8] = (mib.len() as u64).to_be_bytes();
+
+			self.stream.write_all(&data_size).await?;
+
+			let mut buf: [u8; 1024] = [0u8; 1024];
+
+			if mib.len() >= 1024 {
+				for i in 0..mib.len() >> 10 {
+					for (data_ind, buf_ind) in (1024 * i..1024 * (i + 1)).zip(0..1024usize) {
+						buf[buf_ind] = mib[data_ind];
+					}
+
+					self.stream.write_all(&buf).await?;
+				}
+			}
+
+			if mib.len() != 1024 {
+				let remaining = mib.len() % 1024;
+				for (data_ind, buf_ind) in (mib.len() - remaining..mib.len()).zip(0..remaining) {
+					buf[buf_ind] = mib[data_ind];
+				}
+
+				self.stream.write_all(&buf).await?;
+			}
+
+			Ok(())
+		}
+
+		#[allow(clippy::needless_range_loop, clippy::unused_io_amount)]
+		async fn receive(&mut self) -> io::Result<Message> {
+			let mut buf = [0u8; 1024];
+			let mut data_size = [0u8; 8];
+
+			self.stream.read(&mut data_size).await?;
+
+			let data_size = u64::from_be_bytes(data_size);
+			let mut raw_message: Vec<u8> = Vec::new();
+
+			if data_size >= 1024 {
+				for _ in 0..data_size >> 10 {
+					self.stream.read(&mut buf).await?;
+					for ind in 0..1024usize {
+						raw_message.push(buf[ind]);
+					}
+				}
+			}
+
+			if data_size != 1024 {
+				self.stream.read(&mut buf).await?;
+
+				for ind in 0..data_size % 1024 {
+					raw_message.push(buf[ind as usize]);
+				}
+			}
+
+			self.cipher.apply_keystream(&mut raw_message);
+
+			Message::from_bytes(&raw_message[..])
+		}
+	}
+}
+
+///Connects a pair of handshaked clients over loopback
+fn pair() -> (Client, Client) {
+	let mut server = futures::executor::block_on(Server::new(LOOPBACK)).unwrap();
+	let addr = server.local_addr().unwrap();
+
+	let accepted = std::thread::spawn(move ||{
+		futures::executor::block_on(server.listen_handshaked(true, None)).unwrap()
+	});
+
+	let connected = futures::executor::block_on(async {
+		let mut client = Client::connect(addr, None).await.unwrap();
+		client.handshake(None).await.unwrap();
+		client
+	});
+
+	(accepted.join().unwrap(), connected)
+}
+
+///Receives a message of `size` bytes, fails if it's broken or doesn't come
+async fn receive_checked<C: Connection>(connection: &mut C, size: usize) -> io::Result<Message> {
+	let mes = match async_std::future::timeout(STALL, connection.receive()).await {
+		Ok(mes) => mes?,
+		Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no message came"))
+	};
+
+	match mes.get_content().len() == size {
+		true => Ok(mes),
+		false => Err(io::Error::new(io::ErrorKind::InvalidData, "the message came broken"))
+	}
+}
+
+///Sends as many messages of `size` bytes as fit in about a second, returns (messages, elapsed)
+fn throughput<C: Connection>((mut receiver, mut sender): (C, C), size: usize) -> io::Result<(usize, Duration)> {
+	let count = (256 * 1024 * 1024 / size).clamp(64, 100_000);
+
+	let reader = std::thread::spawn(move ||{
+		futures::executor::block_on(async {
+			for _ in 0..count {
+				receive_checked(&mut receiver, size).await?;
+			}
+
+			io::Result::Ok(())
+		})
+	});
+
+	let start = Instant::now();
+	let sent = futures::executor::block_on(async {
+		for _ in 0..count {
+			sender.send(Message::new(vec![78u8; size], 0)).await?;
+		}
+
+		io::Result::Ok(())
+	});
+
+	reader.join().unwrap()?;
+	sent?;
+
+	Ok((count, start.elapsed()))
+}
+
+///Measures the average time of a request/reply exchange with messages of `size` bytes, over 2000 of them or about a second
+fn latency<C: Connection>((mut echo, mut client): (C, C), size: usize) -> io::Result<Duration> {
+	const ROUNDS: u32 = 2000;
+
+	//echoes until the client hangs up, a broken message shows on the client's side
+	let echo = std::thread::spawn(move ||{
+		futures::executor::block_on(async {
+			while let Ok(mes) = echo.receive().await {
+				if echo.send(mes).await.is_err() {
+					return;
+				}
+			}
+		});
+	});
+
+	let start = Instant::now();
+	let exchanged = futures::executor::block_on(async {
+		let mut rounds = 0;
+
+		while rounds < ROUNDS && start.elapsed() < Duration::from_secs(1) {
+			client.send(Message::new(vec![78u8; size], 0)).await?;
+			receive_checked(&mut client, size).await?;
+			rounds += 1;
+		}
+
+		io::Result::Ok(rounds)
+	});
+	let elapsed = start.elapsed();
+
+	drop(client);
+	echo.join().unwrap();
+
+	Ok(elapsed / exchanged?)
+}
+
+fn main() {
+	println!("{:>10} {:>9} {:>14} {:>14} {:>14}", "size", "framing", "messages/s", "payload MiB/s", "round trip");
+
+	for size in SIZES {
+		let rtt_size = size.min(64 * 1024);
+
+		let rows = [
+			("records", throughput(pair(), size), latency(pair(), rtt_size)),
+			("baseline", throughput(baseline::pair(), size), latency(baseline::pair(), rtt_size))
+		];
+
+		for (name, throughput, rtt) in rows {
+			let (per_sec, mib_per_sec) = match throughput {
+				Ok((count, elapsed)) => {
+					let per_sec = count as f64 / elapsed.as_secs_f64();
+					(format!("{:.0}", per_sec), format!("{:.1}", per_sec * size as f64 / (1024.0 * 1024.0)))
+				},
+				Err(e) => (format!("broke: {}", e.kind()), "-".to_string())
+			};
+			let rtt = rtt.map_or_else(|e| format!("broke: {}", e.kind()), |rtt| format!("{:?}", rtt));
+
+			println!("{:>10} {:>9} {:>14} {:>14} {:>14}", size, name, per_sec, mib_per_sec, rtt);
+		}
+	}
+}
@@ -0,0 +1,182 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::net::SocketAddr;
+
+use futures::future::BoxFuture;
+
+///Decision of an `Admission` hook
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict{
+	Accept,
+	///The peer gets `Alert::Rejected` with the reason and the connection is closed
+	Reject(String),
+}
+
+///What the server knows about a peer once the handshake is done
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity{
+	pub addr: SocketAddr,
+	///True if the server asked for a password and the peer gave the right one
+	pub authenticated: bool
+}
+
+///Decides which connections the server takes. Both checks accept everything by default.
+///
+///A plain `Fn(SocketAddr) -> Verdict` closure is an `Admission` that only checks the peer address
+pub trait Admission: Send + Sync{
+	///Called as soon as a connection is accepted, before any handshake work is done.
+	///It has one step of the server's handshake deadlines to decide, after that the peer is rejected
+	fn check_peer(&self, addr: SocketAddr) -> BoxFuture<'_, Verdict>{
+		let _ = addr;
+		Box::pin(async { Verdict::Accept })
+	}
+
+	///Called after the handshake, once the peer has proven its password(if the server asks for one)
+	fn check_identity<'a>(&'a self, identity: &'a Identity) -> BoxFuture<'a, Verdict>{
+		let _ = identity;
+		Box::pin(async { Verdict::Accept })
+	}
+}
+
+impl<F> Admission for F where F: Fn(SocketAddr) -> Verdict + Send + Sync{
+	fn check_peer(&self, addr: SocketAddr) -> BoxFuture<'_, Verdict>{
+		let verdict = self(addr);
+		Box::pin(async move { verdict })
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn admission_test(){
+		use crate::{client::Client, alert::Alert};
+		use futures::StreamExt;
+		use futures::future::BoxFuture;
+
+		struct OnlyOnce(std::sync::atomic::AtomicBool);
+
+		impl Admission for OnlyOnce{
+			fn check_identity<'a>(&'a self, identity: &'a Identity) -> BoxFuture<'a, Verdict>{
+				assert!(identity.authenticated);
+				assert!(identity.addr.ip().is_loopback());
+
+				let seen = self.0.swap(true, std::sync::atomic::Ordering::SeqCst);
+				Box::pin(async move {
+					match seen{
+						false => Verdict::Accept,
+						true => Verdict::Reject("only once".to_string())
+					}
+				})
+			}
+		}
+
+		let (mut server, addr) = testing::bind();
+		server.set_admission(OnlyOnce(std::sync::atomic::AtomicBool::new(false)));
+		//rejected handshakes never come out of the stream, so it's left running
+		let incoming = server.incoming(Some([78u8; 32]));
+		let _server_side = testing::spawn(incoming.collect::<Vec<_>>());
+
+		let handshake = |addr, password| futures::executor::block_on(async move {
+			let mut client = Client::connect(addr, None).await.unwrap();
+			client.handshake(password).await
+		});
+
+		handshake(addr, Some([78u8; 32])).unwrap();
+
+		let err = handshake(addr, Some([78u8; 32])).err().unwrap();
+		assert_eq!(Alert::from_error(&err), Some(&Alert::Rejected("only once".to_string())));
+
+		let err = handshake(addr, Some([77u8; 32])).err().unwrap();
+		assert_eq!(Alert::from_error(&err), Some(&Alert::Rejected("wrong password".to_string())));
+
+		//a plain closure checks peers before any handshake work
+		let (mut server, other_addr) = testing::bind();
+		server.set_admission(|addr: std::net::SocketAddr| match addr.ip().is_loopback(){
+			true => Verdict::Reject("no loopback".to_string()),
+			false => Verdict::Accept
+		});
+		let incoming = server.incoming(None);
+		let _server_side = testing::spawn(incoming.collect::<Vec<_>>());
+
+		let err = handshake(other_addr, None).err().unwrap();
+		assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+		assert_eq!(Alert::from_error(&err), Some(&Alert::Rejected("no loopback".to_string())));
+	}
+
+	#[test]
+	fn listen_rejection_test(){
+		use crate::{client::Client, alert::Alert, Message};
+
+		let (mut server, addr) = testing::bind();
+		let seen = std::sync::atomic::AtomicBool::new(false);
+		server.set_admission(move |_| match seen.swap(true, std::sync::atomic::Ordering::SeqCst){
+			false => Verdict::Reject("not the first".to_string()),
+			true => Verdict::Accept
+		});
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen().await;
+			client.send_message(Message::new("welcome", 1)).await.unwrap();
+		});
+
+		futures::executor::block_on(async {
+			//a peer of `listen` doesn't handshake, it reads the alert as a record
+			let mut client = Client::connect(addr, None).await.unwrap();
+			let err = client.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::Rejected("not the first".to_string())));
+
+			let mut client = Client::connect(addr, None).await.unwrap();
+			assert_eq!(client.get_message().await.unwrap().get_code(), 1);
+		});
+
+		server_side.join().unwrap();
+	}
+
+	#[test]
+	fn slow_check_test(){
+		use crate::{client::Client, alert::Alert};
+		use crate::deadline::HandshakeDeadlines;
+		use futures::StreamExt;
+		use std::time::Duration;
+
+		struct Stalled;
+
+		impl Admission for Stalled{
+			fn check_peer(&self, _: SocketAddr) -> BoxFuture<'_, Verdict>{
+				Box::pin(std::future::pending())
+			}
+		}
+
+		let (mut server, addr) = testing::bind();
+		server.set_admission(Stalled);
+		server.set_handshake_deadlines(HandshakeDeadlines{ step: Duration::from_millis(300), ..HandshakeDeadlines::default() });
+		let incoming = server.incoming(None);
+		let _server_side = testing::spawn(incoming.collect::<Vec<_>>());
+
+		let err = futures::executor::block_on(async {
+			let mut client = Client::connect(addr, None).await.unwrap();
+			client.handshake(None).await
+		}).err().unwrap();
+
+		assert_eq!(Alert::from_error(&err), Some(&Alert::Rejected("admission check timed out".to_string())));
+	}
+}
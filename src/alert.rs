@@ -0,0 +1,146 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::io::{Error, ErrorKind};
+
+///Alerts are sent to the peer to tell it why the connection can't go on.
+///The same value is wrapped into `std::io::Error` on both sides, so it can be restored with `Alert::from_error`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Alert{
+	///The announced message is bigger than the receiver's limit. Contains the limit
+	MessageTooLarge(u64),
+	///The server refused the connection. Contains the reason
+	Rejected(String),
+	///The server has as many connections as it takes, try again later
+	ServerBusy,
+	///The peer is going away, nothing follows this alert
+	CloseNotify,
+	///The server dropped this connection. Contains the reason
+	Disconnected(String),
+	///The peer missed too many pongs in a row, see `Keepalive`
+	PeerUnresponsive,
+}
+
+impl Alert{
+	#[inline]
+	pub fn get_code(&self) -> u8{
+		match self{
+			Alert::MessageTooLarge(_) => 1,
+			Alert::Rejected(_) => 2,
+			Alert::ServerBusy => 3,
+			Alert::CloseNotify => 4,
+			Alert::Disconnected(_) => 5,
+			Alert::PeerUnresponsive => 6,
+		}
+	}
+
+	///`ErrorKind` of the `std::io::Error` this alert is turned into
+	#[inline]
+	pub fn error_kind(&self) -> ErrorKind{
+		match self{
+			Alert::MessageTooLarge(_) => ErrorKind::InvalidData,
+			Alert::Rejected(_) => ErrorKind::ConnectionRefused,
+			Alert::ServerBusy => ErrorKind::ResourceBusy,
+			Alert::CloseNotify | Alert::Disconnected(_) => ErrorKind::ConnectionAborted,
+			Alert::PeerUnresponsive => ErrorKind::TimedOut,
+		}
+	}
+
+	pub fn as_bytes(&self) -> Vec<u8>{
+		let mut res = vec![self.get_code()];
+
+		match self{
+			Alert::MessageTooLarge(limit) => res.extend_from_slice(&limit.to_be_bytes()),
+			Alert::Rejected(reason) | Alert::Disconnected(reason) => res.extend_from_slice(reason.as_bytes()),
+			Alert::ServerBusy | Alert::CloseNotify | Alert::PeerUnresponsive => {}
+		}
+
+		res
+	}
+
+	///Restores an alert from bytes. Returns None if the code is unknown or the payload is malformed
+	pub fn from_bytes(bytes: &[u8]) -> Option<Alert>{
+		let (code, payload) = bytes.split_first()?;
+
+		match code{
+			1 => Some(Alert::MessageTooLarge(u64::from_be_bytes(payload.try_into().ok()?))),
+			2 => Some(Alert::Rejected(String::from_utf8(payload.to_vec()).ok()?)),
+			3 if payload.is_empty() => Some(Alert::ServerBusy),
+			4 if payload.is_empty() => Some(Alert::CloseNotify),
+			5 => Some(Alert::Disconnected(String::from_utf8(payload.to_vec()).ok()?)),
+			6 if payload.is_empty() => Some(Alert::PeerUnresponsive),
+			_ => None
+		}
+	}
+
+	///Returns the alert if the error was made from one
+	#[inline]
+	pub fn from_error(err: &Error) -> Option<&Alert>{
+		err.get_ref()?.downcast_ref::<Alert>()
+	}
+}
+
+impl std::fmt::Display for Alert{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+		match self{
+			Alert::MessageTooLarge(limit) => write!(f, "message is too large, the limit is {} bytes", limit),
+			Alert::Rejected(reason) => write!(f, "connection rejected: {}", reason),
+			Alert::ServerBusy => write!(f, "server is busy"),
+			Alert::CloseNotify => write!(f, "the peer closed the connection"),
+			Alert::Disconnected(reason) => write!(f, "disconnected by the server: {}", reason),
+			Alert::PeerUnresponsive => write!(f, "the peer stopped answering pings"),
+		}
+	}
+}
+
+impl std::error::Error for Alert{}
+
+impl From<Alert> for Error{
+	#[inline]
+	fn from(alert: Alert) -> Error{
+		Error::new(alert.error_kind(), alert)
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	#[test]
+	fn ser_de_test(){
+		let alert = Alert::MessageTooLarge(78);
+		assert_eq!(Alert::from_bytes(&alert.as_bytes()), Some(alert.clone()));
+
+		let err: Error = alert.clone().into();
+		assert_eq!(err.kind(), ErrorKind::InvalidData);
+		assert_eq!(Alert::from_error(&err), Some(&alert));
+
+		assert_eq!(Alert::from_bytes(&[1, 2, 3]), None);
+
+		let alert = Alert::Rejected("go away".to_string());
+		assert_eq!(Alert::from_bytes(&alert.as_bytes()), Some(alert));
+
+		assert_eq!(Alert::from_bytes(&Alert::ServerBusy.as_bytes()), Some(Alert::ServerBusy));
+		assert_eq!(Alert::from_bytes(&Alert::CloseNotify.as_bytes()), Some(Alert::CloseNotify));
+
+		let alert = Alert::Disconnected("kicked".to_string());
+		assert_eq!(Alert::from_bytes(&alert.as_bytes()), Some(alert));
+
+		assert_eq!(Alert::from_bytes(&Alert::PeerUnresponsive.as_bytes()), Some(Alert::PeerUnresponsive));
+		assert_eq!(Error::from(Alert::PeerUnresponsive).kind(), ErrorKind::TimedOut);
+	}
+}
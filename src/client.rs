@@ -18,57 +18,477 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::kem;
 use crate::Message;
+use crate::alert::Alert;
+use crate::deadline::{Deadline, HandshakeDeadlines};
+use crate::keepalive::{Heartbeat, Keepalive};
+use crate::lanes::{Lanes, Priority, LANES};
+use crate::compression::{self, Compression, MIN_COMPRESSED_SIZE};
+use crate::padding::{self, Padding, MIN_FIXED_RECORD_SIZE, PADDING_TRAILER};
+use crate::cover::{Cover, CoverTraffic, MIN_COVER_INTERVAL};
+use crate::throttle::{Throttle, Throttles};
+use crate::codec::{Codec, CodecError};
+use crate::cookie::COOKIE_LEN;
+use crate::registry::{ConnectionId, Registration};
+use crate::puzzle::{self, CHALLENGE_LEN, DEFAULT_MAX_PUZZLE_DIFFICULTY};
 
 use std::io;
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, IoSlice};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use async_net::TcpStream;
+use async_std::sync::Mutex;
+use bytes::{Bytes, BytesMut};
 
 use chacha20::ChaCha20;
 use chacha20::cipher::{KeyIvInit, StreamCipher};
+use event_listener::Event;
 use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+///Default limit for the size of a single incoming serialized message, 16 MiB
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+//A record is an 8-byte big-endian length followed by exactly that many encrypted bytes.
+//The first encrypted byte is one of these
+const RECORD_MESSAGE: u8 = 0;
+const RECORD_ALERT: u8 = 1;
+//The last step of a handshake, the server has let the client in
+const RECORD_WELCOME: u8 = 2;
+//Keepalive, a ping is answered with a pong. Neither is seen by `get_message`
+const RECORD_PING: u8 = 3;
+const RECORD_PONG: u8 = 4;
+//A piece of a split message: lane, `FRAGMENT_FIRST`/`FRAGMENT_LAST`/`FRAGMENT_COMPRESSED` flags, piece of the serialized message
+const RECORD_FRAGMENT: u8 = 5;
+//Right after the handshake's password, the client's 8-byte big-endian `max_message_size` and ids of the compression algorithms it offers.
+//The welcome carries the server's `max_message_size` the same way and the id the server took, or nothing
+const RECORD_COMPRESSION_OFFER: u8 = 6;
+//Compression id, 8-byte big-endian length of the serialized message, compressed serialized message
+const RECORD_COMPRESSED: u8 = 7;
+//Cover traffic, thrown away by the receiver
+const RECORD_COVER: u8 = 8;
+//Set in the kind byte of a padded record, see `padding`
+const RECORD_PADDED: u8 = 0x80;
+//Kind, lane and flags of a fragment
+const FRAGMENT_OVERHEAD: usize = 3;
+
+const FRAGMENT_FIRST: u8 = 1;
+const FRAGMENT_LAST: u8 = 2;
+//set on the first fragment, the pieces make up a `RECORD_COMPRESSED` payload
+const FRAGMENT_COMPRESSED: u8 = 4;
+
+//Messages below `Priority::High` bigger than this are split
+pub(crate) const FRAGMENT_SIZE: usize = 64 * 1024;
+
+//What a record may have besides the message: the kind byte, the id and length of a compressed message
+//(a fragment's lane and flags are shorter) and the padding trailer
+const RECORD_OVERHEAD: u64 = 1 + 9 + PADDING_TRAILER as u64;
+//Records this short are always taken, so the handshake's records and alerts fit whatever the limit is
+const MIN_RECORD_LIMIT: u64 = MIN_FIXED_RECORD_SIZE as u64;
+
+//The server's first answer in a handshake starts with one of these.
+//Alerts at this point go unencrypted: `HANDSHAKE_ALERT`, 2-byte big-endian length, alert.
+//`HANDSHAKE_RETRY` is followed by a cookie, the client has to reconnect and start with `GREETING_WITH_COOKIE` and the cookie.
+//`HANDSHAKE_PUZZLE` is followed by a challenge, the client reconnects with `GREETING_WITH_SOLUTION`, the challenge and an 8-byte big-endian solution
+pub(crate) const HANDSHAKE_PROCEED: u8 = 0;
+pub(crate) const HANDSHAKE_ALERT: u8 = 1;
+pub(crate) const HANDSHAKE_RETRY: u8 = 2;
+pub(crate) const HANDSHAKE_PUZZLE: u8 = 3;
+
+pub(crate) const GREETING: [u8; 3] = [2, 2, 8];
+pub(crate) const GREETING_WITH_COOKIE: [u8; 3] = [2, 2, 9];
+pub(crate) const GREETING_WITH_SOLUTION: [u8; 3] = [2, 2, 10];
+
+//How many times a handshake follows the server to a cookie or a puzzle
+const MAX_REDIRECTS: usize = 2;
+
+//Reusable buffers bigger than this are released after use, so one huge message doesn't pin its memory forever
+const KEPT_BUFFER_CAPACITY: usize = 64 * 1024;
 
 pub struct Client{
+	stream: TcpStream,
+	writer: Arc<Writer>,
+	recv_cipher: ChaCha20,
+	max_message_size: u64,
+	handshake_deadlines: HandshakeDeadlines,
+	max_puzzle_difficulty: u8,
+	read_buf: BytesMut,
+	//split messages being put back together, by lane, and whether they're compressed
+	fragments: [Option<(bool, Vec<u8>)>; LANES],
+	//offered by the client, accepted by the server
+	compression: Vec<Compression>,
+	//what the handshake settled on
+	negotiated: Option<Compression>,
+	padding: Padding,
+	cover_traffic: Option<CoverTraffic>,
+	//runs while `cover_traffic` is set, started by the first `send_message` or `get_message`
+	cover: Option<Arc<Cover>>,
+	keepalive: Option<Keepalive>,
+	//shared with the writer
+	throttles: Arc<Throttles>,
+	//size of the last record read, it's paid for before the next one
+	unpaid: u64,
+	//runs while `keepalive` is set, started by the first `get_message`
+	heartbeat: Option<Arc<Heartbeat>>,
+	//the server's bookkeeping of this connection, undone when the client is dropped
+	registration: Option<Registration>
+}
+
+///Longest record a side with `max_message_size` takes. Longer ones are refused before they're read
+#[inline]
+fn record_limit(max_message_size: u64) -> u64 {
+	max_message_size.saturating_add(RECORD_OVERHEAD).max(MIN_RECORD_LIMIT)
+}
+
+///Send half of a connection. It's shared, so the server can tell a live connection it's going away while its owner is sending.
+///Records take turns by priority, see `lanes`
+pub(crate) struct Writer{
+	records: Mutex<Records>,
+	lanes: Lanes,
+	padding: std::sync::Mutex<Padding>,
+	//longest record the peer takes, see `record_limit`. The handshake tells it
+	peer_limit: AtomicUsize,
+	//set once by the handshake
+	compression: OnceLock<Compression>,
+	//set by `finish`, sends that haven't started yet fail
+	closing: AtomicBool,
+	//messages, pings and cover records on their way out, `finish` waits for them
+	sending: AtomicUsize,
+	idle: Event
+}
+
+//A send `finish` has to wait for, until it's dropped
+struct Sending<'a>{
+	writer: &'a Writer
+}
+
+impl Drop for Sending<'_>{
+	fn drop(&mut self){
+		if self.writer.sending.fetch_sub(1, Ordering::SeqCst) == 1 {
+			self.writer.idle.notify(usize::MAX);
+		}
+	}
+}
+
+struct Records{
 	stream: TcpStream,
 	cipher: ChaCha20,
-	cipher_backup: ChaCha20
+	throttles: Arc<Throttles>,
+	buf: Vec<u8>,
+	//set once a closing alert went out, nothing may follow it
+	closed: bool
+}
+
+//The shared secret is never used as a key itself. Every use gets its own key derived with one of these labels,
+//so the handshake and the two directions of the session never share a keystream
+const LABEL_HANDSHAKE: &[u8] = b"korneplod handshake";
+const LABEL_PASSWORD: &[u8] = b"korneplod password";
+const LABEL_CLIENT_TO_SERVER: &[u8] = b"korneplod client to server";
+const LABEL_SERVER_TO_CLIENT: &[u8] = b"korneplod server to client";
+
+///HKDF-Expand (RFC 5869) of the shared secret into one 32-byte key. The secret comes out of ML-KEM uniform already, so there's no extract step
+fn derive_key(secret: &[u8; 32], label: &[u8]) -> [u8; 32] {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any length");
+	mac.update(label);
+	mac.update(&[1]);
+
+	mac.finalize().into_bytes().into()
+}
+
+///Creates the cipher of the handshake's check, both sides use it once and throw it away
+pub(crate) fn handshake_cipher(key: &[u8; 32], nonce: &[u8; 12]) -> ChaCha20 {
+	ChaCha20::new(&derive_key(key, LABEL_HANDSHAKE).into(), nonce.into())
+}
+
+///Creates the cipher the password is sent with, it encrypts nothing else
+pub(crate) fn password_cipher(key: &[u8; 32], nonce: &[u8; 12]) -> ChaCha20 {
+	ChaCha20::new(&derive_key(key, LABEL_PASSWORD).into(), nonce.into())
+}
+
+///Creates (send, receive) ciphers of a session. Each direction gets its own key, so the keystreams never overlap and both sides can send at the same time
+pub(crate) fn session_ciphers(key: &[u8; 32], nonce: &[u8; 12], initiator: bool) -> (ChaCha20, ChaCha20) {
+	let forth = ChaCha20::new(&derive_key(key, LABEL_CLIENT_TO_SERVER).into(), nonce.into());
+	let back = ChaCha20::new(&derive_key(key, LABEL_SERVER_TO_CLIENT).into(), nonce.into());
+
+	match initiator{
+		true => (forth, back),
+		false => (back, forth)
+	}
 }
 
 impl Client {
 	pub async fn connect(addr: std::net::SocketAddr, cipher: Option<ChaCha20>) -> io::Result<Client> {
 		let cipher = cipher.unwrap_or(crate::default_chacha20_cipher());
 
-		Ok(Client{
-			stream: TcpStream::connect(addr).await?,
-			cipher_backup: cipher.clone(),
-			cipher,
-		})
+		Ok(Client::from_stream(TcpStream::connect(addr).await?, cipher))
 	}
 
 	pub fn from_stream(stream: TcpStream, cipher: ChaCha20) -> Client {
-		Client{ stream, cipher_backup: cipher.clone(), cipher }
+		let throttles = Throttles::new();
+
+		Client{
+			writer: Writer::new(stream.clone(), cipher.clone(), throttles.clone(), Padding::None, DEFAULT_MAX_MESSAGE_SIZE),
+			stream,
+			recv_cipher: cipher,
+			max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+			handshake_deadlines: HandshakeDeadlines::default(),
+			max_puzzle_difficulty: DEFAULT_MAX_PUZZLE_DIFFICULTY,
+			read_buf: BytesMut::new(),
+			fragments: Default::default(),
+			compression: Vec::new(),
+			negotiated: None,
+			padding: Padding::None,
+			cover_traffic: None,
+			cover: None,
+			keepalive: None,
+			throttles,
+			unpaid: 0,
+			heartbeat: None,
+			registration: None
+		}
+	}
+
+	///Creates a `Client` over a handshaked stream. `initiator` is true on the side that called `handshake`
+	pub(crate) fn from_session(stream: TcpStream, key: &[u8; 32], nonce: &[u8; 12], initiator: bool) -> Client {
+		let mut client = Client::from_stream(stream, crate::default_chacha20_cipher());
+		client.set_session(key, nonce, initiator);
+
+		client
+	}
+
+	#[inline]
+	pub(crate) fn set_registration(&mut self, registration: Registration){
+		self.registration = Some(registration);
+	}
+
+	///Id the server gave this connection, None on the connecting side
+	#[inline]
+	pub fn get_connection_id(&self) -> Option<ConnectionId>{
+		self.registration.as_ref().map(|registration| registration.get_id())
+	}
+
+	///Bandwidth limits of this connection alone. They can be changed at any time, also after the client was handed to a `Router` or an `RpcClient`
+	#[inline]
+	pub fn throttle(&self) -> Arc<Throttle>{
+		self.throttles.get_connection()
+	}
+
+	///Makes the connection pay to the server's throttle too
+	#[inline]
+	pub(crate) fn set_server_throttle(&mut self, throttle: Arc<Throttle>){
+		self.throttles.set_server(throttle);
+	}
+
+	#[inline]
+	pub(crate) fn get_writer(&self) -> Arc<Writer>{
+		self.writer.clone()
+	}
+
+	#[inline]
+	pub(crate) fn get_stream(&self) -> &TcpStream{
+		&self.stream
+	}
+
+	//the stream may have changed during the handshake, so the writer is made anew.
+	//Until the peer's limit comes, records are kept as short as any peer takes
+	fn set_session(&mut self, key: &[u8; 32], nonce: &[u8; 12], initiator: bool){
+		let (send_cipher, recv_cipher) = session_ciphers(key, nonce, initiator);
+
+		self.writer = Writer::new(self.stream.clone(), send_cipher, self.throttles.clone(), self.padding, 0);
+		self.recv_cipher = recv_cipher;
+	}
+
+	#[inline]
+	pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr>{
+		self.stream.peer_addr()
+	}
+
+	#[inline]
+	pub fn get_max_message_size(&self) -> u64{
+		self.max_message_size
+	}
+
+	///Sets the limit for incoming messages. Bigger messages are refused with `Alert::MessageTooLarge`, no record longer than the limit and a few bytes is read.
+	///The peer learns it in the handshake and keeps its records within it, padding and cover traffic included, so it should be set before.
+	///Lowering it later may get records the peer is allowed to send refused
+	#[inline]
+	pub fn set_max_message_size(&mut self, size: u64){
+		self.max_message_size = size;
+	}
+
+	#[inline]
+	pub fn get_handshake_deadlines(&self) -> HandshakeDeadlines{
+		self.handshake_deadlines
+	}
+
+	///Sets time limits for `handshake`
+	#[inline]
+	pub fn set_handshake_deadlines(&mut self, deadlines: HandshakeDeadlines){
+		self.handshake_deadlines = deadlines;
+	}
+
+	#[inline]
+	pub fn get_max_puzzle_difficulty(&self) -> u8{
+		self.max_puzzle_difficulty
+	}
+
+	///Sets the hardest puzzle `handshake` agrees to solve, in leading zero bits. Harder ones fail the handshake with `ErrorKind::ConnectionRefused`
+	#[inline]
+	pub fn set_max_puzzle_difficulty(&mut self, difficulty: u8){
+		self.max_puzzle_difficulty = difficulty;
+	}
+
+	#[inline]
+	pub fn get_keepalive(&self) -> Option<Keepalive>{
+		self.keepalive
+	}
+
+	///Turns pings on or off, off by default. Pings start with the next `get_message`. The peer answers them only inside its own `get_message`,
+	///so they may only be turned on when the peer keeps reading, see `Keepalive`
+	#[inline]
+	pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>){
+		self.keepalive = keepalive;
+		self.heartbeat = None;
+	}
+
+	#[inline]
+	pub fn get_padding(&self) -> Padding{
+		self.padding
+	}
+
+	///Sets how the records sent from now on are padded, none by default. The peer strips padding whatever its own policy is
+	#[inline]
+	pub fn set_padding(&mut self, padding: Padding){
+		self.padding = padding;
+		self.writer.set_padding(padding);
+	}
+
+	#[inline]
+	pub fn get_cover_traffic(&self) -> Option<CoverTraffic>{
+		self.cover_traffic
+	}
+
+	///Turns cover traffic on or off, off by default. It starts with the next `send_message` or `get_message` and stops once the client is dropped.
+	///An interval shorter than `MIN_COVER_INTERVAL` is raised to it
+	#[inline]
+	pub fn set_cover_traffic(&mut self, cover_traffic: Option<CoverTraffic>){
+		self.cover_traffic = cover_traffic.map(|cover| CoverTraffic{ interval: cover.interval.max(MIN_COVER_INTERVAL), ..cover });
+		self.cover = None;
 	}
 
-	///Performes hadshaking and thus prepares a `Client` instance for message transmission. Use this function only if the Client instance is created with `connect` method
+	#[inline]
+	fn start_cover(&mut self){
+		if let (Some(cover_traffic), None) = (self.cover_traffic, &self.cover){
+			self.cover = Some(Cover::start(cover_traffic, self.writer.clone()));
+		}
+	}
+
+	#[inline]
+	pub fn get_compression(&self) -> &[Compression]{
+		&self.compression
+	}
+
+	///Sets the compression algorithms offered in the next `handshake`, in order of preference. None by default.
+	///Algorithms whose feature is off are left out
+	#[inline]
+	pub fn set_compression(&mut self, algorithms: Vec<Compression>){
+		self.compression = algorithms.into_iter().filter(|compression| compression.is_available()).collect();
+	}
+
+	///The algorithm both sides compress their messages with, None until a handshake settled on one
+	#[inline]
+	pub fn get_negotiated_compression(&self) -> Option<Compression>{
+		self.negotiated
+	}
+
+	///Performes hadshaking and thus prepares a `Client` instance for message transmission. Use this function only if the Client instance is created with `connect` method.
+	///If the server doesn't answer within `get_handshake_deadlines`, the connection is shut down and `ErrorKind::TimedOut` is returned.
+	///If the server refuses the connection, the error carries `Alert::Rejected`.
+	///A busy server may send the client away for a retry cookie or a puzzle, then it reconnects by itself. Time spent on a puzzle isn't counted against the deadlines
 	pub async fn handshake(&mut self, password: Option<[u8; 32]>) -> io::Result<()> {
-		self.stream.write_all(&[2u8, 2u8, 8u8]).await?;//w1
+		let res = self.handshake_steps(password).await;
+
+		if res.is_err(){
+			let _ = self.stream.shutdown(std::net::Shutdown::Both);
+		}
+
+		res
+	}
 
-		let mut rng = rand::thread_rng();
+	async fn handshake_steps(&mut self, password: Option<[u8; 32]>) -> io::Result<()> {
+		let mut deadline = Deadline::start(self.handshake_deadlines);
 
-		let (dk, ek) = kem::create_keypair(&mut rng);
+		//the rng isn't kept across awaits, so the handshake can run on any task
+		let (dk, ek) = kem::create_keypair(&mut rand::thread_rng());
 		let ek_bytes = kem::enc_key_to_bytes(&ek);
 
-		self.stream.write_all(&ek_bytes[..]).await?;//w2
+		deadline.write_all(&mut self.stream, &[&GREETING[..], &ek_bytes[..]].concat()).await?;//w1, w2
 
-		let mut ek_bytes = [0u8; 1568];
-		self.stream.read(&mut ek_bytes).await?;//r1
+		let mut status = [0u8; 1];
+		deadline.read_exact(&mut self.stream, &mut status).await?;
 
-		let mut random_bytes: [u8; 16] = [0u8; 16];
+		//the server is busy and wants to see that we're really here, maybe even some work from us.
+		//A cookie can be followed by a puzzle if the load grows meanwhile, a server that sends us away more often is broken
+		for _ in 0..MAX_REDIRECTS {
+			let first_flight = match status[0]{
+				HANDSHAKE_RETRY => {
+					let mut cookie = [0u8; COOKIE_LEN];
+					deadline.read_exact(&mut self.stream, &mut cookie).await?;
 
-		for i in 0..16usize {
-			random_bytes[i] = rand::random::<u8>();
+					[&GREETING_WITH_COOKIE[..], &cookie[..], &ek_bytes[..]].concat()
+				}
+				HANDSHAKE_PUZZLE => {
+					let mut challenge = [0u8; CHALLENGE_LEN];
+					deadline.read_exact(&mut self.stream, &mut challenge).await?;
+
+					if puzzle::get_difficulty(&challenge) > self.max_puzzle_difficulty {
+						return Err(Error::new(ErrorKind::ConnectionRefused, "the server's puzzle is harder than allowed"));
+					}
+
+					let solution = async_std::task::spawn_blocking(move || puzzle::solve(&challenge)).await;
+					deadline = Deadline::start(self.handshake_deadlines);
+
+					[&GREETING_WITH_SOLUTION[..], &challenge[..], &solution.to_be_bytes()[..], &ek_bytes[..]].concat()
+				}
+				_ => break
+			};
+
+			let addr = self.stream.peer_addr()?;
+			let _ = self.stream.shutdown(std::net::Shutdown::Both);
+			self.stream = deadline.run(TcpStream::connect(addr)).await?;
+
+			deadline.write_all(&mut self.stream, &first_flight).await?;
+			deadline.read_exact(&mut self.stream, &mut status).await?;
+		}
+
+		if status[0] == HANDSHAKE_RETRY || status[0] == HANDSHAKE_PUZZLE {
+			return Err(Error::new(ErrorKind::InvalidData, "the server keeps sending the client away"));
+		}
+
+		if status[0] == HANDSHAKE_ALERT {
+			let mut len = [0u8; 2];
+			deadline.read_exact(&mut self.stream, &mut len).await?;
+
+			let mut alert = vec![0u8; u16::from_be_bytes(len) as usize];
+			deadline.read_exact(&mut self.stream, &mut alert).await?;
+
+			return Err(match Alert::from_bytes(&alert){
+				Some(alert) => alert.into(),
+				None => Error::new(ErrorKind::InvalidData, "unknown alert")
+			});
 		}
-		let mut random_bytes: Vec<u8> = random_bytes.to_vec();
+
+		if status[0] != HANDSHAKE_PROCEED {
+			return Err(Error::new(ErrorKind::InvalidData, "unknown handshake status"));
+		}
+
+		let mut ek_bytes = [0u8; 1568];
+		deadline.read_exact(&mut self.stream, &mut ek_bytes).await?;//r1
+
+		let mut random_bytes: Vec<u8> = rand::random::<[u8; 16]>().to_vec();
 
 		let mut chph_ = vec![2u8, 2u8, 8u8];
 		chph_.append(&mut random_bytes);
@@ -80,126 +500,287 @@ impl Client {
 
 		let decapsulated_key: Option<[u8; 32]> = kem::decapsulate(&ek_bytes, &dk);
 
-		if let None = decapsulated_key {
+		if decapsulated_key.is_none() {
 			return Err(Error::new(ErrorKind::InvalidData, "Cannot decapsulate encapsulated key"));
 		}
 
 		let decapsulated_key: [u8; 32] = decapsulated_key.unwrap();
 
-		self.stream.read(&mut ek_bytes).await?;//r2
+		deadline.read_exact(&mut self.stream, &mut ek_bytes).await?;//r2
 
 		let decapsulated: Option<[u8; 32]> = kem::decapsulate(&ek_bytes, &dk);
 
-		if let None = decapsulated {
+		if decapsulated.is_none() {
 			return Err(Error::new(ErrorKind::InvalidData, "Cannot decapsulate encapsulated nonce"));
 		}
 
 		let nonce: [u8; 12] = crate::tools::derive_nonce(&decapsulated.unwrap());
-		let mut cipher = chacha20::ChaCha20::new(&decapsulated_key.into(), &nonce.into());
+		let mut cipher = handshake_cipher(&decapsulated_key, &nonce);
 		cipher.apply_keystream(&mut chph);
 
-		let cph = chph.clone();
+		let cph = chph;
 
-		self.stream.write_all(&chph).await?;//w3
-		self.stream.read(&mut chph).await?;//r3
+		deadline.write_all(&mut self.stream, &chph).await?;//w3
+		deadline.read_exact(&mut self.stream, &mut chph).await?;//r3
 
 		if cph != chph {
 			return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, format!("the server's answer doesn't match the sent data. Expected: {:?}, have: {:?}", cph, chph)));
 		}
 
-		if password.is_none(){
-			self . cipher = chacha20::ChaCha20::new(&decapsulated_key.into(), &nonce.into());
-			return Ok(());
+		if let Some(mut password) = password{
+			password_cipher(&decapsulated_key, &nonce).apply_keystream(&mut password);
+			deadline.write_all(&mut self.stream, &password).await?;//w4
 		}
 
-		let mut password = password.unwrap();
-		self.cipher.apply_keystream(&mut password);
+		self.set_session(&decapsulated_key, &nonce, true);
+
+		//w5
+		let offer = compression::to_ids(&self.compression);
+		deadline.run(self.writer.send_record(Priority::High, RECORD_COMPRESSION_OFFER, |buf| {
+			buf.extend_from_slice(&self.max_message_size.to_be_bytes());
+			buf.extend_from_slice(&offer);
+		})).await?;
 
-		self.stream.write_all(&password).await?;//w4
-		self.stream.read(&mut password).await?;//r4
+		//r5, the server either lets us in or says why not
+		let welcome = match deadline.run(self.read_record()).await?{
+			(RECORD_WELCOME, welcome) => welcome,
+			_ => return Err(Error::new(ErrorKind::InvalidData, "unexpected record during handshake"))
+		};
 
-		self.cipher = chacha20::ChaCha20::new(&decapsulated_key.into(), &nonce.into());
-		return Ok(());
+		let Some((max_message_size, taken)) = welcome.split_first_chunk::<8>() else {
+			return Err(Error::new(ErrorKind::InvalidData, "malformed welcome"));
+		};
+		self.writer.set_peer_max_message_size(u64::from_be_bytes(*max_message_size));
 
+		match taken.first().map(|&id| Compression::from_id(id).filter(|compression| self.compression.contains(compression))){
+			None => Ok(()),
+			Some(Some(compression)) => {
+				self.set_negotiated_compression(compression);
+				Ok(())
+			},
+			Some(None) => Err(Error::new(ErrorKind::InvalidData, "the server took a compression that wasn't offered"))
+		}
+	}
+
+	///Server side: reads the client's offer, keeps the records within its limit and takes the first offered algorithm that is `accepted`
+	pub(crate) async fn take_offer(&mut self, accepted: &[Compression]) -> io::Result<()> {
+		let offer = match self.read_record().await?{
+			(RECORD_COMPRESSION_OFFER, offer) => offer,
+			_ => return Err(Error::new(ErrorKind::InvalidData, "unexpected record during handshake"))
+		};
+
+		let Some((max_message_size, offer)) = offer.split_first_chunk::<8>() else {
+			return Err(Error::new(ErrorKind::InvalidData, "malformed compression offer"));
+		};
+		self.writer.set_peer_max_message_size(u64::from_be_bytes(*max_message_size));
+
+		if let Some(compression) = compression::choose(&compression::from_ids(offer), accepted){
+			self.set_negotiated_compression(compression);
+		}
+
+		Ok(())
 	}
 
+	#[inline]
+	fn set_negotiated_compression(&mut self, compression: Compression){
+		self.negotiated = Some(compression);
+		let _ = self.writer.compression.set(compression);
+	}
+
+	///Sends a message with `Priority::Normal`. It's serialized straight into the send buffer and encrypted there, without intermediate copies.
+	///Fails with `ErrorKind::BrokenPipe` once the connection was closed by the server going down
 	#[inline]
 	pub async fn send_message(&mut self, mes: crate::Message) -> io::Result<()> {
-		let mut mib = mes.as_bytes_once();
-		self.cipher.apply_keystream(&mut mib);
-		let data_size: [u8; 8] = (mib.len() as u64).to_be_bytes();
+		self.start_cover();
+		self.writer.send_message(&mes).await
+	}
 
-		if let Err(e) = self.stream.write_all(&data_size).await{
-			self.cipher = self.cipher_backup.clone();
-			return Err(e);
-		}
+	///Sends a message ahead of everything of a lower priority waiting to go out on this connection, including the rest of split messages.
+	///Messages below `Priority::High` bigger than 64 KiB are split. With padding on, messages of any priority longer than one record are split too.
+	///The peer gets them whole
+	#[inline]
+	pub async fn send_message_with_priority(&mut self, mes: crate::Message, priority: Priority) -> io::Result<()> {
+		self.start_cover();
+		self.writer.send_message_with_priority(&mes, priority).await
+	}
 
-		let mut buf: [u8; 1024] = [0u8; 1024];
+	///Encodes `value` with `codec` and sends it as a message with the given code
+	pub async fn send_typed<C: Codec, T: Serialize + ?Sized>(&mut self, codec: &C, value: &T, code: u16) -> Result<(), CodecError> {
+		let mes = Message::from_typed(codec, value, code)?;
+		Ok(self.send_message(mes).await?)
+	}
 
-		if mib.len() >= 1024{
-			for i in 0.. mib.len() >> 10{
-				for (data_ind, buf_ind) in (1024 * i .. 1024 * (i + 1)).zip( 0..1024usize ) {
-					buf[buf_ind] = mib[data_ind];
-				}
+	///Receives a message and decodes its content with `codec`
+	pub async fn get_typed<C: Codec, T: DeserializeOwned>(&mut self, codec: &C) -> Result<T, CodecError> {
+		self.get_message().await?.to_typed(codec)
+	}
 
-				if let Err(e) = self.stream.write_all(&buf).await {
-					self.cipher = self.cipher_backup.clone();
-					return Err(e);
-				}
-			}
+	///Tells the peer why the connection is going to be dropped
+	pub(crate) async fn send_alert(&mut self, alert: &Alert) -> io::Result<()> {
+		self.writer.send_record(Priority::High, RECORD_ALERT, |buf| buf.extend_from_slice(&alert.as_bytes())).await
+	}
+
+	///Finishes the server side of a handshake, tells the client our limit and the compression that was taken
+	pub(crate) async fn send_welcome(&mut self) -> io::Result<()> {
+		let taken = self.negotiated.map(|compression| compression.id());
+		self.writer.send_record(Priority::High, RECORD_WELCOME, |buf| {
+			buf.extend_from_slice(&self.max_message_size.to_be_bytes());
+			buf.extend(taken);
+		}).await
+	}
+
+	///Receives a message. If the peer announces a message bigger than `get_max_message_size`, it gets `Alert::MessageTooLarge` and the same alert is returned as the error.
+	///After that the connection is out of sync and has to be dropped.
+	///With keepalive on, a peer that stays silent too long while it waits makes it fail with `Alert::PeerUnresponsive`.
+	///Pings are answered only here, so while the peer has keepalive on it has to be called all the time, see `Keepalive`
+	pub async fn get_message(&mut self) -> io::Result<Message> {
+		if let (Some(keepalive), None) = (self.keepalive, &self.heartbeat){
+			self.heartbeat = Some(Heartbeat::start(keepalive, self.writer.clone(), self.stream.clone()));
 		}
 
-		if mib.len() != 1024 {
-			let remaining = mib.len() % 1024;
-			for (data_ind, buf_ind) in (mib.len() - remaining .. mib.len()).zip(0..remaining){
-				buf[buf_ind] = mib[data_ind];
+		let heartbeat = self.heartbeat.clone();
+		let _reading = heartbeat.as_ref().map(|heartbeat| heartbeat.reading());
+
+		self.start_cover();
+
+		loop {
+			let record = match self.read_record().await{
+				Ok(record) => record,
+				Err(_) if self.heartbeat.as_ref().is_some_and(|heartbeat| heartbeat.is_unresponsive()) => return Err(Alert::PeerUnresponsive.into()),
+				Err(e) => return Err(e)
+			};
+
+			if let Some(heartbeat) = &self.heartbeat{
+				heartbeat.heard();
 			}
-	
-			if let Err(e) = self.stream.write_all(&buf).await{
-				self.cipher = self.cipher_backup.clone();
-				return Err(e);
+
+			match record{
+				(RECORD_MESSAGE, mes) if mes.len() as u64 > self.max_message_size => return Err(self.refuse_too_large().await),
+				(RECORD_MESSAGE, mes) => return crate::message::Message::from_shared(mes),
+				(RECORD_COMPRESSED, payload) => return self.decompress(&payload).await,
+				(RECORD_FRAGMENT, fragment) => match self.put_together(&fragment).await?{
+					Some((true, payload)) => return self.decompress(&payload).await,
+					Some((false, mes)) => return crate::message::Message::from_shared(mes.into()),
+					None => {}
+				},
+				(RECORD_PING, _) => self.writer.send_record(Priority::High, RECORD_PONG, |_| {}).await?,
+				(RECORD_PONG, _) | (RECORD_COVER, _) => {}
+				_ => return Err(Error::new(ErrorKind::InvalidData, "unexpected record"))
 			}
 		}
+	}
 
-		self.cipher_backup = self.cipher.clone();
+	///Checks the announced length against `max_message_size` first, then decompresses no more than it
+	async fn decompress(&mut self, payload: &[u8]) -> io::Result<Message> {
+		let &[id, ref rest @ ..] = payload else {
+			return Err(Error::new(ErrorKind::InvalidData, "malformed compressed message"));
+		};
 
-		Ok(())
+		let Some((len, compressed)) = rest.split_first_chunk::<8>() else {
+			return Err(Error::new(ErrorKind::InvalidData, "malformed compressed message"));
+		};
+
+		let Some(compression) = Compression::from_id(id).filter(|&compression| Some(compression) == self.negotiated) else {
+			return Err(Error::new(ErrorKind::InvalidData, "unexpected compression"));
+		};
+
+		let len = u64::from_be_bytes(*len);
+		if len > self.max_message_size {
+			return Err(self.refuse_too_large().await);
+		}
+
+		Message::from_shared(compression.decompress(compressed, len as usize)?.into())
 	}
 
-	#[inline]
-	pub async fn get_message(&mut self) -> io::Result<Message> {
-		let mut buf = [0u8; 1024];
-		let mut data_size = [0u8; 8];
+	///Adds a fragment to its lane, returns the payload and whether it's compressed once its last fragment came
+	async fn put_together(&mut self, fragment: &[u8]) -> io::Result<Option<(bool, Vec<u8>)>> {
+		let &[lane, flags, ref piece @ ..] = fragment else {
+			return Err(Error::new(ErrorKind::InvalidData, "malformed fragment"));
+		};
+
+		let Some(parts) = self.fragments.get_mut(lane as usize) else {
+			return Err(Error::new(ErrorKind::InvalidData, "unknown lane"));
+		};
+
+		//a first fragment drops whatever a cancelled send left behind
+		if flags & FRAGMENT_FIRST != 0 {
+			*parts = Some((flags & FRAGMENT_COMPRESSED != 0, Vec::new()));
+		}
 
-		self.stream.read(&mut data_size).await?;
+		let Some((_, buf)) = parts else {
+			return Err(Error::new(ErrorKind::InvalidData, "fragment out of order"));
+		};
+
+		if (buf.len() + piece.len()) as u64 > self.max_message_size {
+			return Err(self.refuse_too_large().await);
+		}
+
+		buf.extend_from_slice(piece);
+
+		Ok(if flags & FRAGMENT_LAST != 0 { parts.take() } else { None })
+	}
+
+	///Tells the peer its message is bigger than `max_message_size`, returns the same alert as an error
+	async fn refuse_too_large(&mut self) -> Error {
+		let alert = Alert::MessageTooLarge(self.max_message_size);
+		let _ = self.send_alert(&alert).await;
+
+		alert.into()
+	}
+
+	///Reads a record and returns its kind and payload without padding. Alert records are returned as errors
+	async fn read_record(&mut self) -> io::Result<(u8, Bytes)> {
+		//the size of a record is only known once it's being read, so the throttles are paid for the previous one.
+		//Nothing is read before it's paid, so giving up on the wait leaves the connection as it was
+		if self.unpaid > 0 {
+			self.throttles.received(self.unpaid).await;
+			self.unpaid = 0;
+		}
+
+		let mut data_size = [0u8; 8];
+		self.stream.read_exact(&mut data_size).await?;
 
 		let data_size = u64::from_be_bytes(data_size);
-		let mut raw_message: Vec<u8> = Vec::new();
 
-		if data_size >= 1024{
-			for _ in 0..data_size >> 10{
-				self.stream.read(&mut buf).await?;
-				for ind in 0..1024usize{
-					raw_message.push(buf[ind]);
-				}
-			}
+		//the peer keeps its records within our limit, padding and cover traffic included.
+		//Messages bigger than a record are checked while their fragments are put together
+		if data_size > record_limit(self.max_message_size) {
+			return Err(self.refuse_too_large().await);
 		}
 
-		if data_size != 1024{
-			self.stream.read(&mut buf).await?;
+		self.unpaid = 8 + data_size;
 
-			for ind in 0..data_size % 1024 {
-				raw_message.push(buf[ind as usize]);
-				
-			}
+		//The buffer is split off and handed to the message, so the content isn't copied.
+		//BytesMut gets its memory back on the next resize once the previous message is dropped,
+		//unless the record was big: then the buffer is forgotten, so the memory goes away with the message
+		self.read_buf.resize(data_size as usize, 0);
+		self.stream.read_exact(&mut self.read_buf).await?;
+		self.recv_cipher.apply_keystream(&mut self.read_buf);
+
+		let record = self.read_buf.split().freeze();
+		if record.len() > KEPT_BUFFER_CAPACITY {
+			self.read_buf = BytesMut::new();
 		}
 
-		self.cipher.apply_keystream(&mut raw_message);
-		self.cipher_backup = self.cipher.clone();
+		let Some(&kind) = record.first() else {
+			return Err(Error::new(ErrorKind::InvalidData, "malformed record"));
+		};
 
-		let mes = crate::message::Message::from_bytes(&raw_message[..]);
+		let payload = match kind & RECORD_PADDED != 0{
+			true => match padding::unpadded_len(&record){
+				Some(len) if len >= 1 => record.slice(1..len),
+				_ => return Err(Error::new(ErrorKind::InvalidData, "malformed padding"))
+			},
+			false => record.slice(1..)
+		};
 
-		Ok(mes)
+		match kind & !RECORD_PADDED{
+			RECORD_ALERT => match Alert::from_bytes(&payload){
+				Some(alert) => Err(alert.into()),
+				None => Err(Error::new(ErrorKind::InvalidData, "unknown alert"))
+			},
+			kind => Ok((kind, payload))
+		}
 	}
 
 	#[inline]
@@ -221,4 +802,353 @@ impl Client {
 
 		res.unwrap()
 	}
-}
\ No newline at end of file
+}
+
+impl Writer{
+	#[inline]
+	fn new(stream: TcpStream, cipher: ChaCha20, throttles: Arc<Throttles>, padding: Padding, peer_max_message_size: u64) -> Arc<Writer>{
+		let writer = Arc::new(Writer{
+			records: Mutex::new(Records{ stream, cipher, throttles, buf: Vec::new(), closed: false }),
+			lanes: Lanes::new(),
+			padding: std::sync::Mutex::new(padding),
+			peer_limit: AtomicUsize::new(0),
+			compression: OnceLock::new(),
+			closing: AtomicBool::new(false),
+			sending: AtomicUsize::new(0),
+			idle: Event::new()
+		});
+		writer.set_peer_max_message_size(peer_max_message_size);
+
+		writer
+	}
+
+	fn start_sending(&self) -> io::Result<Sending<'_>> {
+		self.sending.fetch_add(1, Ordering::SeqCst);
+		let sending = Sending{ writer: self };
+
+		if self.closing.load(Ordering::SeqCst) {
+			return Err(Error::new(ErrorKind::BrokenPipe, "the connection is closing"));
+		}
+
+		Ok(sending)
+	}
+
+	///Waits for the turn of `priority`, then sends a record of `kind` filled with `fill`
+	async fn send_record(&self, priority: Priority, kind: u8, fill: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+		let padding = self.get_padding();
+		let peer_limit = self.get_peer_limit();
+		self.lanes.turn(priority, || self.records.lock()).await.send(kind, padding, peer_limit, fill).await
+	}
+
+	#[inline]
+	fn get_peer_limit(&self) -> usize{
+		self.peer_limit.load(Ordering::Relaxed)
+	}
+
+	///Keeps the records within what a peer with `max_message_size` takes
+	#[inline]
+	fn set_peer_max_message_size(&self, max_message_size: u64){
+		let limit = usize::try_from(record_limit(max_message_size)).unwrap_or(usize::MAX);
+		self.peer_limit.store(limit, Ordering::Relaxed);
+	}
+
+	#[inline]
+	fn get_padding(&self) -> Padding{
+		*self.padding.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	#[inline]
+	fn set_padding(&self, padding: Padding){
+		*self.padding.lock().unwrap_or_else(|e| e.into_inner()) = padding;
+	}
+
+	///Sends a cover traffic record with `size` bytes before padding, or as many as fit in a record.
+	///That's the padded record limit, or the size messages are split at, but never more than the peer takes
+	pub(crate) async fn send_cover(&self, size: usize) -> io::Result<()> {
+		let _sending = self.start_sending()?;
+		let padding = self.get_padding();
+		let peer_limit = self.get_peer_limit();
+
+		let max = match padding.get_record_limit(peer_limit){
+			Some(limit) => limit - 1 - PADDING_TRAILER,
+			None => FRAGMENT_SIZE.min(peer_limit) - 1
+		};
+		let size = size.min(max);
+
+		self.lanes.turn(Priority::Low, || self.records.lock()).await.send(RECORD_COVER, padding, peer_limit, |buf| buf.resize(buf.len() + size, 0)).await
+	}
+
+	#[inline]
+	pub(crate) async fn send_message(&self, mes: &Message) -> io::Result<()> {
+		self.send_message_with_priority(mes, Priority::Normal).await
+	}
+
+	///Compresses the message if a compression was negotiated and it isn't `secret_mixed`.
+	///Sends a big message below `Priority::High` in fragments, each taking its own turn
+	pub(crate) async fn send_message_with_priority(&self, mes: &Message, priority: Priority) -> io::Result<()> {
+		let _sending = self.start_sending()?;
+		let len = mes.encoded_len();
+		let compression = self.compression.get().filter(|_| !mes.is_secret_mixed() && len >= MIN_COMPRESSED_SIZE);
+
+		//padded records have a limit whatever the priority, the kind byte and the trailer are in it.
+		//Unpadded ones can't be longer than the peer takes either, messages that don't fit are refused once their fragments add up
+		let peer_limit = self.get_peer_limit();
+		let limit = self.get_padding().get_record_limit(peer_limit);
+		let fits = |len: usize| match limit{
+			Some(limit) => 1 + len + PADDING_TRAILER <= limit,
+			None => len < peer_limit && (priority == Priority::High || len <= FRAGMENT_SIZE)
+		};
+		let piece_size = match limit{
+			Some(limit) => limit - FRAGMENT_OVERHEAD - PADDING_TRAILER,
+			None => FRAGMENT_SIZE.min(peer_limit - FRAGMENT_OVERHEAD)
+		};
+
+		if compression.is_none() && fits(len) {
+			return self.send_record(priority, RECORD_MESSAGE, |buf| mes.write_to(buf)).await;
+		}
+
+		let mut serialized = Vec::with_capacity(len);
+		mes.write_to(&mut serialized);
+
+		let (kind, payload) = match compression.and_then(|compression| Some((compression, compression.compress(&serialized)?))){
+			Some((compression, compressed)) => {
+				let mut payload = Vec::with_capacity(9 + compressed.len());
+				payload.push(compression.id());
+				payload.extend_from_slice(&(len as u64).to_be_bytes());
+				payload.extend_from_slice(&compressed);
+
+				(RECORD_COMPRESSED, payload)
+			},
+			None => (RECORD_MESSAGE, serialized)
+		};
+
+		if fits(payload.len()) {
+			return self.send_record(priority, kind, |buf| buf.extend_from_slice(&payload)).await;
+		}
+
+		let _lane = self.lanes.split(priority).await;
+
+		let mut flags = FRAGMENT_FIRST;
+		if kind == RECORD_COMPRESSED {
+			flags |= FRAGMENT_COMPRESSED;
+		}
+
+		let mut pieces = payload.chunks(piece_size).peekable();
+
+		while let Some(piece) = pieces.next() {
+			if pieces.peek().is_none() {
+				flags |= FRAGMENT_LAST;
+			}
+
+			self.send_record(priority, RECORD_FRAGMENT, |buf| {
+				buf.extend_from_slice(&[priority.lane() as u8, flags]);
+				buf.extend_from_slice(piece);
+			}).await?;
+
+			flags = 0;
+		}
+
+		Ok(())
+	}
+
+	#[inline]
+	pub(crate) async fn ping(&self) -> io::Result<()> {
+		let _sending = self.start_sending()?;
+		self.send_record(Priority::High, RECORD_PING, |_| {}).await
+	}
+
+	///Sends `alert` as the last record and shuts the sending side down. Records sent before it reach the peer first,
+	///the rest of split messages doesn't go out
+	pub(crate) async fn close(&self, alert: &Alert) -> io::Result<()> {
+		let padding = self.get_padding();
+		let peer_limit = self.get_peer_limit();
+		let mut records = self.lanes.turn(Priority::High, || self.records.lock()).await;
+
+		let res = records.send(RECORD_ALERT, padding, peer_limit, |buf| buf.extend_from_slice(&alert.as_bytes())).await;
+		records.closed = true;
+		let _ = records.stream.shutdown(std::net::Shutdown::Write);
+
+		res
+	}
+
+	///Like `close`, but lets the messages already being sent go out first, the rest of split messages too.
+	///Sends started after it fail with `ErrorKind::BrokenPipe`
+	pub(crate) async fn finish(&self, alert: &Alert) -> io::Result<()> {
+		self.closing.store(true, Ordering::SeqCst);
+
+		loop {
+			let idle = self.idle.listen();
+
+			if self.sending.load(Ordering::SeqCst) == 0 {
+				break;
+			}
+
+			idle.await;
+		}
+
+		self.close(alert).await
+	}
+}
+
+impl Records{
+	///Builds a record of `kind` with `fill`, pads it no further than `peer_limit`, encrypts it in place and sends it
+	async fn send(&mut self, kind: u8, padding: Padding, peer_limit: usize, fill: impl FnOnce(&mut Vec<u8>)) -> io::Result<()> {
+		if self.closed {
+			return Err(Error::new(ErrorKind::BrokenPipe, "the connection is closed"));
+		}
+
+		self.buf.clear();
+		self.buf.push(kind);
+		fill(&mut self.buf);
+
+		if padding != Padding::None {
+			self.buf[0] |= RECORD_PADDED;
+			padding::pad(&mut self.buf, padding, peer_limit);
+		}
+
+		//paid for before it's encrypted, so giving up on the wait doesn't take keystream the peer won't see
+		self.throttles.sent(8 + self.buf.len() as u64).await;
+
+		self.cipher.apply_keystream(&mut self.buf);
+		let data_size: [u8; 8] = (self.buf.len() as u64).to_be_bytes();
+
+		let res = write_all_vectored(&mut self.stream, &mut [IoSlice::new(&data_size), IoSlice::new(&self.buf)]).await;
+		release_if_big(&mut self.buf);
+
+		res
+	}
+}
+
+///Writes all the slices, going on after short writes
+async fn write_all_vectored(stream: &mut TcpStream, mut bufs: &mut [IoSlice<'_>]) -> io::Result<()> {
+	while !bufs.is_empty() {
+		match stream.write_vectored(bufs).await {
+			Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to write whole record")),
+			Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+			Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e)
+		}
+	}
+
+	Ok(())
+}
+
+#[inline]
+fn release_if_big(buf: &mut Vec<u8>){
+	if buf.capacity() > KEPT_BUFFER_CAPACITY {
+		*buf = Vec::new();
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn keys_test(){
+		let (key, nonce) = ([78u8; 32], [7u8; 12]);
+		let keystream = |mut cipher: ChaCha20| {
+			let mut buf = [0u8; 64];
+			cipher.apply_keystream(&mut buf);
+			buf
+		};
+
+		let (client_send, client_receive) = session_ciphers(&key, &nonce, true);
+		let (server_send, server_receive) = session_ciphers(&key, &nonce, false);
+		assert_eq!(keystream(client_send.clone()), keystream(server_receive));
+		assert_eq!(keystream(server_send.clone()), keystream(client_receive));
+
+		//the handshake, the password, the directions and the raw secret all differ
+		let streams = [
+			keystream(handshake_cipher(&key, &nonce)),
+			keystream(password_cipher(&key, &nonce)),
+			keystream(client_send),
+			keystream(server_send),
+			keystream(ChaCha20::new(&key.into(), &nonce.into()))
+		];
+		for (i, a) in streams.iter().enumerate(){
+			assert!(streams[i + 1..].iter().all(|b| a != b));
+		}
+	}
+
+	#[test]
+	fn pipelined_messages_test(){
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+
+			for i in 0..300usize {
+				let mes = client.get_message().await.unwrap();
+				assert_eq!(mes.get_code(), i as u16);
+				assert_eq!(mes.get_content(), &vec![i as u8; i * 37][..]);
+			}
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+
+			for i in 0..300usize {
+				client.send_message(Message::new(vec![i as u8; i * 37], i as u16)).await.unwrap();
+			}
+		});
+
+		server_side.join().unwrap();
+	}
+
+	#[test]
+	fn max_message_size_test(){
+		let (mut server, addr) = testing::bind();
+		server.set_max_message_size(10);
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+			assert_eq!(client.get_max_message_size(), 10);
+
+			let err = client.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::MessageTooLarge(10)));
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+
+			client.send_message(Message::new(vec![78u8; 100], 0)).await.unwrap();
+
+			let err = client.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::MessageTooLarge(10)));
+		});
+
+		server_side.join().unwrap();
+	}
+
+	#[test]
+	fn record_limit_test(){
+		let (mut server, addr) = testing::bind();
+		server.set_max_message_size(1000);
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+
+			//padded no further than we take
+			assert_eq!(client.get_message().await.unwrap().get_content().len(), 900);
+
+			//refused from the length alone, nobody sends the rest
+			let err = client.get_message_with_timeout(std::time::Duration::from_secs(5)).await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::MessageTooLarge(1000)));
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			client.set_padding(Padding::Fixed(FRAGMENT_SIZE));
+
+			client.send_message(Message::new(vec![78u8; 900], 0)).await.unwrap();
+			client.get_stream().clone().write_all(&2000u64.to_be_bytes()).await.unwrap();
+
+			let err = client.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::MessageTooLarge(1000)));
+		});
+
+		server_side.join().unwrap();
+	}
+}
@@ -0,0 +1,239 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Codecs turn serde values into message content and back.
+//!
+//! Every codec is behind its own feature: `bincode`, `postcard`, `cbor` and `json`.
+//! Messages made by `Message::from_typed` carry the codec's name in the `content-type` header,
+//! so decoding a message with a different codec fails with `CodecError::UnexpectedContentType` instead of garbage
+
+use crate::Message;
+
+use std::io;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+///Header that keeps the name of the codec a message was encoded with
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+pub trait Codec{
+	///Name of the codec, it's put into the `content-type` header
+	fn content_type(&self) -> &'static str;
+	fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+	fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug)]
+pub enum CodecError{
+	///The message couldn't be sent or received
+	Io(io::Error),
+	Encode{ content_type: &'static str, reason: String },
+	Decode{ content_type: &'static str, reason: String },
+	///The message was encoded with another codec
+	UnexpectedContentType{ expected: &'static str, found: String },
+}
+
+impl std::fmt::Display for CodecError{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+		match self{
+			CodecError::Io(e) => write!(f, "{}", e),
+			CodecError::Encode{ content_type, reason } => write!(f, "cannot encode {}: {}", content_type, reason),
+			CodecError::Decode{ content_type, reason } => write!(f, "cannot decode {}: {}", content_type, reason),
+			CodecError::UnexpectedContentType{ expected, found } => write!(f, "expected {} content, found {}", expected, found),
+		}
+	}
+}
+
+impl std::error::Error for CodecError{
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+		match self{
+			CodecError::Io(e) => Some(e),
+			_ => None
+		}
+	}
+}
+
+impl From<io::Error> for CodecError{
+	#[inline]
+	fn from(e: io::Error) -> CodecError{
+		CodecError::Io(e)
+	}
+}
+
+impl From<CodecError> for io::Error{
+	#[inline]
+	fn from(e: CodecError) -> io::Error{
+		match e{
+			CodecError::Io(e) => e,
+			e => io::Error::new(io::ErrorKind::InvalidData, e)
+		}
+	}
+}
+
+impl Message{
+	///Encodes `value` with `codec` into a new message with the given code
+	pub fn from_typed<C: Codec, T: Serialize + ?Sized>(codec: &C, value: &T, code: u16) -> Result<Message, CodecError>{
+		Ok(Message::new(codec.encode(value)?, code).with_header(CONTENT_TYPE_HEADER, codec.content_type()))
+	}
+
+	///Decodes the content with `codec`. Messages without the `content-type` header are decoded as is
+	pub fn to_typed<C: Codec, T: DeserializeOwned>(&self, codec: &C) -> Result<T, CodecError>{
+		if let Some(found) = self.get_header(CONTENT_TYPE_HEADER) && found != codec.content_type().as_bytes() {
+			return Err(CodecError::UnexpectedContentType{
+				expected: codec.content_type(),
+				found: String::from_utf8_lossy(found).into_owned()
+			});
+		}
+
+		codec.decode(self.get_content())
+	}
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode{
+	#[inline]
+	fn content_type(&self) -> &'static str{
+		"application/x-bincode"
+	}
+
+	fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>{
+		bincode::serialize(value).map_err(|e| CodecError::Encode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+
+	fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>{
+		bincode::deserialize(bytes).map_err(|e| CodecError::Decode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+}
+
+#[cfg(feature = "postcard")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard{
+	#[inline]
+	fn content_type(&self) -> &'static str{
+		"application/x-postcard"
+	}
+
+	fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>{
+		postcard::to_allocvec(value).map_err(|e| CodecError::Encode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+
+	fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>{
+		postcard::from_bytes(bytes).map_err(|e| CodecError::Decode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor{
+	#[inline]
+	fn content_type(&self) -> &'static str{
+		"application/cbor"
+	}
+
+	fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>{
+		let mut res = Vec::new();
+		ciborium::into_writer(value, &mut res).map_err(|e| CodecError::Encode{ content_type: self.content_type(), reason: e.to_string() })?;
+		Ok(res)
+	}
+
+	fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>{
+		ciborium::from_reader(bytes).map_err(|e| CodecError::Decode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+}
+
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json{
+	#[inline]
+	fn content_type(&self) -> &'static str{
+		"application/json"
+	}
+
+	fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError>{
+		serde_json::to_vec(value).map_err(|e| CodecError::Encode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+
+	fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>{
+		serde_json::from_slice(bytes).map_err(|e| CodecError::Decode{ content_type: self.content_type(), reason: e.to_string() })
+	}
+}
+
+#[cfg(all(test, any(feature = "bincode", feature = "postcard", feature = "cbor", feature = "json")))]
+mod tests{
+	use super::*;
+
+	#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+	struct Point{
+		x: i32,
+		y: i32,
+		name: String
+	}
+
+	fn round_trip<C: Codec>(codec: &C){
+		let point = Point{ x: 78, y: -78, name: "korneplod".to_string() };
+		let message = Message::from_typed(codec, &point, 5).unwrap();
+
+		assert_eq!(message.get_code(), 5);
+		assert_eq!(message.get_header(CONTENT_TYPE_HEADER), Some(codec.content_type().as_bytes()));
+		assert_eq!(message.to_typed::<C, Point>(codec).unwrap(), point);
+
+		let garbage = Message::new(vec![0xffu8; 3], 5);
+		assert!(matches!(garbage.to_typed::<C, Point>(codec), Err(CodecError::Decode{ .. })));
+
+		let foreign = garbage.with_header(CONTENT_TYPE_HEADER, "text/plain");
+		assert!(matches!(foreign.to_typed::<C, Point>(codec), Err(CodecError::UnexpectedContentType{ .. })));
+	}
+
+	#[cfg(feature = "bincode")]
+	#[test]
+	fn bincode_test(){
+		round_trip(&Bincode);
+	}
+
+	#[cfg(feature = "postcard")]
+	#[test]
+	fn postcard_test(){
+		round_trip(&Postcard);
+	}
+
+	#[cfg(feature = "cbor")]
+	#[test]
+	fn cbor_test(){
+		round_trip(&Cbor);
+	}
+
+	#[cfg(feature = "json")]
+	#[test]
+	fn json_test(){
+		round_trip(&Json);
+	}
+}
@@ -0,0 +1,200 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Compression of messages before they're encrypted.
+//!
+//! Every algorithm is behind its own feature: `zstd` and `lz4`.
+//! The client offers the algorithms of `Client::set_compression` in the handshake, in order of preference,
+//! the server takes the first one it has in `Server::set_compression`. Then both sides compress the messages they send with it.
+//! Every record says whether its message is compressed, small messages and messages that don't get smaller go as they are.
+//!
+//! Compressed length leaks how much the content repeats itself. If a message mixes secrets with data the peer's enemy chooses,
+//! it can find the secrets out byte by byte(CRIME), such messages have to be marked with `Message::secret_mixed` and are never compressed.
+//!
+//! A compressed message carries its original length, a length over the receiver's `get_max_message_size` is refused
+//! with `Alert::MessageTooLarge` before anything is decompressed, and the output is never let grow past it
+
+use std::io;
+use std::io::{Error, ErrorKind};
+
+//Messages shorter than this aren't worth it
+pub(crate) const MIN_COMPRESSED_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression{
+	Zstd,
+	Lz4
+}
+
+impl Compression{
+	///Returns true if the algorithm's feature is on
+	#[inline]
+	pub fn is_available(self) -> bool{
+		match self{
+			Compression::Zstd => cfg!(feature = "zstd"),
+			Compression::Lz4 => cfg!(feature = "lz4")
+		}
+	}
+
+	#[inline]
+	pub(crate) fn id(self) -> u8{
+		match self{
+			Compression::Zstd => 1,
+			Compression::Lz4 => 2
+		}
+	}
+
+	#[inline]
+	pub(crate) fn from_id(id: u8) -> Option<Compression>{
+		match id{
+			1 => Some(Compression::Zstd),
+			2 => Some(Compression::Lz4),
+			_ => None
+		}
+	}
+
+	///Returns None if the data doesn't get smaller
+	pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>>{
+		let compressed: Option<Vec<u8>> = match self{
+			#[cfg(feature = "zstd")]
+			Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL).ok(),
+			#[cfg(feature = "lz4")]
+			Compression::Lz4 => Some(lz4_flex::block::compress(data)),
+			#[allow(unreachable_patterns)]
+			_ => None
+		};
+
+		compressed.filter(|compressed| compressed.len() < data.len())
+	}
+
+	///Decompresses exactly `len` bytes, anything else is an error
+	#[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+	pub(crate) fn decompress(self, data: &[u8], len: usize) -> io::Result<Vec<u8>>{
+		let decompressed: io::Result<Vec<u8>> = match self{
+			#[cfg(feature = "zstd")]
+			Compression::Zstd => zstd::bulk::decompress(data, len),
+			#[cfg(feature = "lz4")]
+			Compression::Lz4 => lz4_flex::block::decompress(data, len).map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+			#[allow(unreachable_patterns)]
+			_ => Err(Error::new(ErrorKind::Unsupported, "compression isn't available"))
+		};
+
+		match decompressed?{
+			decompressed if decompressed.len() == len => Ok(decompressed),
+			_ => Err(Error::new(ErrorKind::InvalidData, "decompressed length doesn't match"))
+		}
+	}
+}
+
+///Picks the first offered algorithm that is accepted and available
+pub(crate) fn choose(offered: &[Compression], accepted: &[Compression]) -> Option<Compression>{
+	offered.iter().copied().find(|compression| compression.is_available() && accepted.contains(compression))
+}
+
+#[inline]
+pub(crate) fn to_ids(algorithms: &[Compression]) -> Vec<u8>{
+	algorithms.iter().map(|compression| compression.id()).collect()
+}
+
+///Unknown ids are skipped, they may come from a newer peer
+#[inline]
+pub(crate) fn from_ids(ids: &[u8]) -> Vec<Compression>{
+	ids.iter().filter_map(|&id| Compression::from_id(id)).collect()
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn compression_test(){
+		let data = br#"{"name":"korneplod","tags":["a","b","c"]}"#.repeat(100);
+
+		for compression in [Compression::Zstd, Compression::Lz4]{
+			assert_eq!(Compression::from_id(compression.id()), Some(compression));
+
+			if !compression.is_available(){
+				assert!(compression.compress(&data).is_none());
+				assert!(compression.decompress(&data, data.len()).is_err());
+				continue;
+			}
+
+			let compressed = compression.compress(&data).unwrap();
+			assert!(compressed.len() < data.len());
+			assert_eq!(compression.decompress(&compressed, data.len()).unwrap(), data);
+
+			//the declared length is all the output gets
+			assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+
+			//random bytes don't get smaller
+			let noise: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+			assert!(compression.compress(&noise).is_none());
+		}
+
+		assert_eq!(from_ids(&[2, 77, 1]), vec![Compression::Lz4, Compression::Zstd]);
+		assert_eq!(choose(&[Compression::Lz4, Compression::Zstd], &[Compression::Zstd]), Some(Compression::Zstd).filter(|zstd| zstd.is_available()));
+		assert_eq!(choose(&[Compression::Lz4], &[]), None);
+	}
+
+	#[test]
+	fn negotiation_test(){
+		use crate::{client::Client, alert::Alert, Message};
+
+		const MAX_MESSAGE_SIZE: u64 = 1_000_000;
+
+		//the client's preference wins, as far as the features allow
+		let expected = [Compression::Lz4, Compression::Zstd].into_iter().find(|compression| compression.is_available());
+		let json = br#"{"id":78,"name":"korneplod","tags":["a","b","c"]},"#.repeat(5000);
+
+		let (mut server, addr) = testing::bind();
+		server.set_compression(vec![Compression::Zstd, Compression::Lz4]);
+		server.set_max_message_size(MAX_MESSAGE_SIZE);
+
+		let server_side = testing::spawn({
+			let json = json.clone();
+			async move {
+				let mut client = server.listen_handshaked(true, None).await.unwrap();
+				assert_eq!(client.get_negotiated_compression(), expected);
+
+				assert_eq!(client.get_message().await.unwrap(), Message::new(json.clone(), 1).with_id(2));
+				assert_eq!(client.get_message().await.unwrap().get_content(), &json[..1000]);
+
+				//much more than allowed once decompressed, however small it came
+				let err = client.get_message().await.err().unwrap();
+				assert_eq!(Alert::from_error(&err), Some(&Alert::MessageTooLarge(MAX_MESSAGE_SIZE)));
+			}
+		});
+
+		futures::executor::block_on(async {
+			let mut client = Client::connect(addr, None).await.unwrap();
+			client.set_compression(vec![Compression::Lz4, Compression::Zstd]);
+			client.handshake(None).await.unwrap();
+			assert_eq!(client.get_negotiated_compression(), expected);
+
+			client.send_message(Message::new(json.clone(), 1).with_id(2)).await.unwrap();
+			client.send_message(Message::new(json[..1000].to_vec(), 1).secret_mixed()).await.unwrap();
+			//uncompressed, the server hangs up before it's all sent
+			let _ = client.send_message(Message::new(vec![0u8; 2 * MAX_MESSAGE_SIZE as usize], 1)).await;
+
+			let err = client.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::MessageTooLarge(MAX_MESSAGE_SIZE)));
+			server_side.join().unwrap();
+		});
+	}
+}
@@ -0,0 +1,178 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+///8-byte big-endian unix time in seconds followed by HMAC-SHA256 of the time and the client address
+pub const COOKIE_LEN: usize = 8 + 32;
+
+//How long a cookie or a puzzle is taken back after it was issued, in seconds
+const COOKIE_LIFETIME: u64 = 30;
+
+const MAC_COOKIE: u8 = 0;
+pub(crate) const MAC_PUZZLE: u8 = 1;
+
+///When the server asks clients to come back with a retry cookie before it does any KEM work.
+///
+///A client that gets a cookie reconnects and sends it with its first flight. The server keeps nothing between the two connections,
+///so a flood of first flights costs it one HMAC each. Note that a retried client connects twice, so it's seen twice by the firewall and the admission hook
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RetryCookies{
+	#[default]
+	Never,
+	Always,
+	///Only while more than this many handshakes are running at the same time
+	Above(usize)
+}
+
+impl RetryCookies{
+	#[inline]
+	pub(crate) fn required(&self, running: usize) -> bool{
+		match self{
+			RetryCookies::Never => false,
+			RetryCookies::Always => true,
+			RetryCookies::Above(limit) => running > *limit
+		}
+	}
+}
+
+///Issues and checks cookies with a secret that lives as long as the server
+pub(crate) struct CookieJar{
+	secret: [u8; 32]
+}
+
+impl CookieJar{
+	#[inline]
+	pub(crate) fn new() -> CookieJar{
+		CookieJar{ secret: rand::random() }
+	}
+
+	///`kind` keeps cookies and puzzles apart, `extra` is whatever else the value is bound to
+	pub(crate) fn mac(&self, kind: u8, time: u64, addr: &IpAddr, extra: &[u8]) -> Hmac<Sha256>{
+		let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac takes keys of any length");
+		mac.update(&[kind]);
+		mac.update(&time.to_be_bytes());
+		mac.update(extra);
+
+		match addr{
+			IpAddr::V4(v4) => mac.update(&v4.octets()),
+			IpAddr::V6(v6) => mac.update(&v6.octets())
+		}
+
+		mac
+	}
+
+	pub(crate) fn issue(&self, addr: &IpAddr) -> [u8; COOKIE_LEN]{
+		let time = now();
+		let mut cookie = [0u8; COOKIE_LEN];
+
+		cookie[..8].copy_from_slice(&time.to_be_bytes());
+		cookie[8..].copy_from_slice(&self.mac(MAC_COOKIE, time, addr, &[]).finalize().into_bytes());
+
+		cookie
+	}
+
+	///True if the cookie was issued by this jar to `addr` and hasn't expired yet
+	pub(crate) fn check(&self, addr: &IpAddr, cookie: &[u8; COOKIE_LEN]) -> bool{
+		let time = u64::from_be_bytes(cookie[..8].try_into().unwrap());
+
+		if !fresh(time) {
+			return false;
+		}
+
+		self.mac(MAC_COOKIE, time, addr, &[]).verify_slice(&cookie[8..]).is_ok()
+	}
+}
+
+///True if something issued at `time` may still be taken back
+#[inline]
+pub(crate) fn fresh(time: u64) -> bool{
+	now().saturating_sub(time) <= COOKIE_LIFETIME && time <= now() + 1
+}
+
+#[inline]
+pub(crate) fn now() -> u64{
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn cookie_test(){
+		let jar = CookieJar::new();
+		let addr: IpAddr = "10.0.0.78".parse().unwrap();
+		let cookie = jar.issue(&addr);
+
+		assert!(jar.check(&addr, &cookie));
+		assert!(!jar.check(&"10.0.0.77".parse().unwrap(), &cookie));
+		assert!(!CookieJar::new().check(&addr, &cookie));
+
+		let mut forged = cookie;
+		forged[8] ^= 1;
+		assert!(!jar.check(&addr, &forged));
+
+		let mut stale = [0u8; COOKIE_LEN];
+		let time = now() - COOKIE_LIFETIME - 1;
+		stale[..8].copy_from_slice(&time.to_be_bytes());
+		stale[8..].copy_from_slice(&jar.mac(MAC_COOKIE, time, &addr, &[]).finalize().into_bytes());
+		assert!(!jar.check(&addr, &stale));
+
+		assert!(!RetryCookies::Never.required(100));
+		assert!(RetryCookies::Above(2).required(3));
+		assert!(!RetryCookies::Above(2).required(2));
+	}
+
+	#[test]
+	fn retry_cookie_test(){
+		use crate::Message;
+		use futures::StreamExt;
+		use std::io::{Read, Write};
+
+		let (mut server, addr) = testing::bind();
+		server.set_retry_cookies(RetryCookies::Always);
+
+		//the stream is left running for the raw connection below
+		let incoming = server.incoming(None);
+		let _server_side = testing::spawn(incoming.for_each(|mut client| async move {
+			client.send_message(Message::new("after the cookie", 78)).await.unwrap();
+		}));
+
+		//the client goes for the cookie and comes back by itself
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			assert_eq!(client.get_message().await.unwrap().get_content(), b"after the cookie");
+		});
+
+		//a made up cookie is answered with a new one, not with KEM work
+		let mut raw = std::net::TcpStream::connect(addr).unwrap();
+		raw.write_all(&[2, 2, 9]).unwrap();
+		raw.write_all(&[78u8; crate::cookie::COOKIE_LEN]).unwrap();
+		raw.write_all(&[0u8; 1568]).unwrap();
+
+		let mut answer = Vec::new();
+		raw.read_to_end(&mut answer).unwrap();
+		assert_eq!(answer.len(), 1 + crate::cookie::COOKIE_LEN);
+		assert_eq!(answer[0], 2);
+	}
+}
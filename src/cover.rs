@@ -0,0 +1,118 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Cover traffic: dummy records sent at random moments, so an observer can't tell when real messages go and how often.
+//!
+//! A cover record is encrypted and padded like any other record, only its kind tells the peer to throw it away.
+//! It's never longer than the records real messages are split into, so its size doesn't give it away either
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::client::Writer;
+
+///Shorter intervals are raised to this one, a zero interval would keep the task sending cover records nonstop
+pub const MIN_COVER_INTERVAL: Duration = Duration::from_millis(10);
+
+///Dummy records sent so the timing of real messages is harder to tell. One goes out on average every `interval`,
+///at random moments, with up to `max_size` bytes in it before padding, but no more than fit in one record.
+///They're sent with `Priority::Low` and count against the bandwidth limits. The peer's `get_message` never sees them.
+///`interval` is at least `MIN_COVER_INTERVAL`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoverTraffic{
+	pub interval: Duration,
+	pub max_size: usize
+}
+
+impl Default for CoverTraffic{
+	#[inline]
+	fn default() -> CoverTraffic{
+		CoverTraffic{ interval: Duration::from_secs(1), max_size: 1024 }
+	}
+}
+
+///Keeps the cover traffic task running. The task stops once it's dropped or the connection fails
+pub(crate) struct Cover;
+
+impl Cover{
+	pub(crate) fn start(cover: CoverTraffic, writer: Arc<Writer>) -> Arc<Cover>{
+		let running = Arc::new(Cover);
+		async_std::task::spawn(send(cover, Arc::downgrade(&running), writer));
+
+		running
+	}
+}
+
+async fn send(cover: CoverTraffic, running: Weak<Cover>, writer: Arc<Writer>){
+	loop {
+		//anywhere from half to one and a half of the interval
+		let (wait, size) = {
+			let mut rng = rand::thread_rng();
+			(cover.interval.mul_f64(rng.gen_range(0.5..1.5)), rng.gen_range(0..=cover.max_size))
+		};
+
+		async_std::task::sleep(wait).await;
+
+		if running.upgrade().is_none() || writer.send_cover(size).await.is_err(){
+			return;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn cover_size_test(){
+		use crate::Message;
+		use crate::padding::Padding;
+
+		let (mut server, addr) = testing::bind();
+		//far below the size asked for, so an unclamped cover record gets the connection refused
+		server.set_max_message_size(1000);
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+
+			for i in 0..10 {
+				assert_eq!(client.get_message().await.unwrap().get_code(), i);
+			}
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			client.set_cover_traffic(Some(CoverTraffic{ interval: Duration::ZERO, max_size: 1_000_000 }));
+			assert_eq!(client.get_cover_traffic().map(|cover| cover.interval), Some(MIN_COVER_INTERVAL));
+
+			for i in 0..10 {
+				if i == 5 {
+					client.set_padding(Padding::Fixed(256));
+				}
+
+				client.send_message(Message::new("real", i)).await.unwrap();
+				async_std::task::sleep(Duration::from_millis(30)).await;
+			}
+
+			server_side.join().unwrap();
+		});
+	}
+}
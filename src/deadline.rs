@@ -0,0 +1,197 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use async_net::TcpStream;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+//How long a step may go before the peer's data rate is checked at all
+const RATE_GRACE: Duration = Duration::from_secs(1);
+
+///Time limits of a handshake. They're the same on both sides, so a peer that goes quiet can't hold a connection forever
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandshakeDeadlines{
+	///Limit for every single read or write of the handshake
+	pub step: Duration,
+	///Limit for the whole handshake
+	pub total: Duration,
+	///Bytes per second the peer has to keep up after the first second of a read. 0 turns the check off
+	pub min_rate: u64
+}
+
+impl Default for HandshakeDeadlines{
+	#[inline]
+	fn default() -> HandshakeDeadlines{
+		HandshakeDeadlines{
+			step: Duration::from_secs(5),
+			total: Duration::from_secs(15),
+			min_rate: 1024
+		}
+	}
+}
+
+///Runs reads and writes of a handshake within `HandshakeDeadlines`
+pub(crate) struct Deadline{
+	limits: HandshakeDeadlines,
+	end: Instant
+}
+
+impl Deadline{
+	#[inline]
+	pub(crate) fn start(limits: HandshakeDeadlines) -> Deadline{
+		Deadline{ limits, end: Instant::now() + limits.total }
+	}
+
+	#[inline]
+	fn step_end(&self) -> Instant{
+		(Instant::now() + self.limits.step).min(self.end)
+	}
+
+	///Runs `fut` within the current step
+	pub(crate) async fn run<T>(&self, fut: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T>{
+		let remaining = self.step_end().saturating_duration_since(Instant::now());
+
+		match async_std::future::timeout(remaining, fut).await{
+			Err(_) => Err(timed_out(true)),
+			Ok(res) => res
+		}
+	}
+
+	pub(crate) async fn read_exact(&self, stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<()>{
+		let start = Instant::now();
+		let step_end = self.step_end();
+		let mut pos = 0;
+
+		while pos < buf.len(){
+			let mut until = step_end;
+
+			if self.limits.min_rate != 0{
+				let rate_end = start + RATE_GRACE + Duration::from_secs_f64(pos as f64 / self.limits.min_rate as f64);
+				until = until.min(rate_end);
+			}
+
+			let read = async_std::future::timeout(until.saturating_duration_since(Instant::now()), stream.read(&mut buf[pos..])).await;
+
+			match read{
+				Err(_) => return Err(timed_out(Instant::now() >= step_end)),
+				Ok(Ok(0)) => return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed during handshake")),
+				Ok(Ok(n)) => pos += n,
+				Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => continue,
+				Ok(Err(e)) => return Err(e)
+			}
+		}
+
+		Ok(())
+	}
+
+	#[inline]
+	pub(crate) async fn write_all(&self, stream: &mut TcpStream, buf: &[u8]) -> io::Result<()>{
+		self.run(stream.write_all(buf)).await
+	}
+}
+
+#[inline]
+fn timed_out(deadline: bool) -> Error{
+	match deadline{
+		true => Error::new(ErrorKind::TimedOut, "handshake deadline expired"),
+		false => Error::new(ErrorKind::TimedOut, "peer sends handshake data too slowly")
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn min_rate_test(){
+		use std::io::Write;
+
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		//1 byte every 200ms, far below 100 bytes per second
+		let trickle = std::thread::spawn(move ||{
+			let mut sock = std::net::TcpStream::connect(addr).unwrap();
+			for _ in 0..20 {
+				if sock.write_all(&[78u8]).is_err(){
+					break;
+				}
+				std::thread::sleep(Duration::from_millis(200));
+			}
+		});
+
+		let (sock, _) = listener.accept().unwrap();
+		let mut sock = TcpStream::try_from(sock).unwrap();
+
+		let deadline = Deadline::start(HandshakeDeadlines{ step: Duration::from_secs(10), total: Duration::from_secs(10), min_rate: 100 });
+		let start = Instant::now();
+		let err = futures::executor::block_on(deadline.read_exact(&mut sock, &mut [0u8; 64])).err().unwrap();
+
+		assert_eq!(err.kind(), ErrorKind::TimedOut);
+		assert!(start.elapsed() < Duration::from_secs(3));
+
+		drop(sock);
+		trickle.join().unwrap();
+	}
+
+	#[test]
+	fn handshake_deadline_test(){
+		use crate::client::Client;
+		use std::io::{Read, Write};
+		use std::time::{Duration, Instant};
+
+		let deadlines = HandshakeDeadlines{ step: Duration::from_millis(200), total: Duration::from_millis(500), min_rate: 0 };
+
+		let (mut server, addr) = testing::bind();
+		server.set_handshake_deadlines(deadlines);
+
+		let server_side = std::thread::spawn(move ||{
+			let start = Instant::now();
+			assert!(futures::executor::block_on(server.listen_handshaked(true, None)).is_none());
+			assert!(start.elapsed() < Duration::from_secs(2));
+		});
+
+		//greets the server and goes quiet, the server must hang up on it
+		let mut stalled = std::net::TcpStream::connect(addr).unwrap();
+		stalled.write_all(&[2u8, 2u8, 8u8]).unwrap();
+		stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		assert_eq!(stalled.read(&mut [0u8; 16]).unwrap(), 0);
+
+		server_side.join().unwrap();
+
+		//and the other way round: a server that never answers
+		let quiet = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let quiet_addr = quiet.local_addr().unwrap();
+
+		futures::executor::block_on(async {
+			let mut client = Client::connect(quiet_addr, None).await.unwrap();
+			client.set_handshake_deadlines(deadlines);
+
+			let start = Instant::now();
+			let err = client.handshake(None).await.err().unwrap();
+
+			assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+			assert!(start.elapsed() < Duration::from_secs(2));
+		});
+
+		drop(quiet);
+	}
+}
@@ -0,0 +1,476 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+//Buckets of this many sources are kept at most. Past that, the ones that have refilled are forgotten
+const MAX_TRACKED_SOURCES: usize = 65536;
+//If nobody has refilled, this many of the longest idle ones are forgotten at once
+const EVICTED_SOURCES: usize = MAX_TRACKED_SOURCES / 16;
+
+///A network written as `address/prefix`, e.g. `10.0.0.0/8` or `2001:db8::/32`. A plain address means the address alone
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr{
+	addr: IpAddr,
+	prefix: u8
+}
+
+impl Cidr{
+	///Returns None if the prefix is longer than the address
+	pub fn new(addr: IpAddr, prefix: u8) -> Option<Cidr>{
+		if prefix > max_prefix(&addr) {
+			return None;
+		}
+
+		Some(Cidr{ addr: mask(addr, prefix), prefix })
+	}
+
+	#[inline]
+	pub fn get_addr(&self) -> IpAddr{
+		self.addr
+	}
+
+	#[inline]
+	pub fn get_prefix(&self) -> u8{
+		self.prefix
+	}
+
+	#[inline]
+	pub fn contains(&self, addr: &IpAddr) -> bool{
+		addr.is_ipv4() == self.addr.is_ipv4() && mask(*addr, self.prefix) == self.addr
+	}
+}
+
+///Why a string isn't a `Cidr`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CidrParseError{
+	Address(std::net::AddrParseError),
+	///Not a number or longer than the address
+	Prefix
+}
+
+impl std::fmt::Display for CidrParseError{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+		match self{
+			CidrParseError::Address(e) => e.fmt(f),
+			CidrParseError::Prefix => f.write_str("invalid network prefix")
+		}
+	}
+}
+
+impl std::error::Error for CidrParseError{
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+		match self{
+			CidrParseError::Address(e) => Some(e),
+			CidrParseError::Prefix => None
+		}
+	}
+}
+
+impl From<std::net::AddrParseError> for CidrParseError{
+	#[inline]
+	fn from(e: std::net::AddrParseError) -> CidrParseError{
+		CidrParseError::Address(e)
+	}
+}
+
+impl std::str::FromStr for Cidr{
+	type Err = CidrParseError;
+
+	fn from_str(s: &str) -> Result<Cidr, Self::Err>{
+		let (addr, prefix) = match s.split_once('/'){
+			Some((addr, prefix)) => (addr.parse::<IpAddr>()?, prefix.parse::<u8>().ok()),
+			None => {
+				let addr = s.parse::<IpAddr>()?;
+				(addr, Some(max_prefix(&addr)))
+			}
+		};
+
+		prefix.and_then(|prefix| Cidr::new(addr, prefix)).ok_or(CidrParseError::Prefix)
+	}
+}
+
+impl std::fmt::Display for Cidr{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+		write!(f, "{}/{}", self.addr, self.prefix)
+	}
+}
+
+#[inline]
+fn max_prefix(addr: &IpAddr) -> u8{
+	match addr{
+		IpAddr::V4(_) => 32,
+		IpAddr::V6(_) => 128
+	}
+}
+
+#[inline]
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr{
+	match addr{
+		IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)).into()),
+		IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)).into())
+	}
+}
+
+///Token bucket: `burst` connections at once, refilled at `per_second`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit{
+	pub burst: u32,
+	pub per_second: f64
+}
+
+struct Bucket{
+	tokens: f64,
+	updated: Instant
+}
+
+impl Bucket{
+	#[inline]
+	fn refill(&mut self, limit: &RateLimit, now: Instant){
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+		self.updated = now;
+	}
+}
+
+///Buckets for every source(address or subnet) of one rate limit
+struct Limiter{
+	limit: RateLimit,
+	buckets: HashMap<IpAddr, Bucket>
+}
+
+impl Limiter{
+	///Checks that `source` has a token without taking it
+	fn has_token(&mut self, source: IpAddr, now: Instant) -> bool{
+		if self.buckets.len() >= MAX_TRACKED_SOURCES && !self.buckets.contains_key(&source) {
+			self.make_room(now);
+		}
+
+		let bucket = self.buckets.entry(source).or_insert(Bucket{ tokens: self.limit.burst as f64, updated: now });
+		bucket.refill(&self.limit, now);
+		bucket.tokens >= 1.0
+	}
+
+	///Takes the token `has_token` has just found
+	#[inline]
+	fn take(&mut self, source: &IpAddr){
+		if let Some(bucket) = self.buckets.get_mut(source){
+			bucket.tokens -= 1.0;
+		}
+	}
+
+	fn make_room(&mut self, now: Instant){
+		let limit = self.limit;
+		//left as they are, so `updated` still tells when the source was last seen
+		self.buckets.retain(|_, bucket| {
+			let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+			bucket.tokens + elapsed * limit.per_second < limit.burst as f64
+		});
+
+		//everybody is busy, the ones that have been quiet the longest go first
+		if self.buckets.len() >= MAX_TRACKED_SOURCES {
+			let mut idle: Vec<(Instant, IpAddr)> = self.buckets.iter().map(|(source, bucket)| (bucket.updated, *source)).collect();
+			idle.select_nth_unstable(EVICTED_SOURCES - 1);
+
+			for (_, source) in &idle[..EVICTED_SOURCES] {
+				self.buckets.remove(source);
+			}
+		}
+	}
+}
+
+struct Rules{
+	allow: Vec<Cidr>,
+	deny: Vec<Cidr>,
+	ip_limiter: Option<Limiter>,
+	subnet_limiter: Option<Limiter>,
+	subnet_prefix_v4: u8,
+	subnet_prefix_v6: u8
+}
+
+///Counters of a `Firewall`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FirewallStats{
+	pub passed: u64,
+	///Dropped by the deny list
+	pub denied: u64,
+	///Dropped because the allow list isn't empty and doesn't have them
+	pub not_allowed: u64,
+	pub ip_rate_limited: u64,
+	pub subnet_rate_limited: u64
+}
+
+///Drops connections by source address before anything is read from them.
+///
+///Deny list wins over allow list. If the allow list is empty, everybody not denied is allowed.
+///Then the source address and its subnet(/24 for ipv4 and /64 for ipv6 by default) each have to get a token from their rate limit.
+///Every method takes `&self`, so the rules can be changed while the server is running
+pub struct Firewall{
+	rules: Mutex<Rules>,
+	passed: AtomicU64,
+	denied: AtomicU64,
+	not_allowed: AtomicU64,
+	ip_rate_limited: AtomicU64,
+	subnet_rate_limited: AtomicU64
+}
+
+impl Default for Firewall{
+	#[inline]
+	fn default() -> Firewall{
+		Firewall::new()
+	}
+}
+
+impl Firewall{
+	///Creates a firewall that lets everybody in
+	pub fn new() -> Firewall{
+		Firewall{
+			rules: Mutex::new(Rules{
+				allow: Vec::new(),
+				deny: Vec::new(),
+				ip_limiter: None,
+				subnet_limiter: None,
+				subnet_prefix_v4: 24,
+				subnet_prefix_v6: 64
+			}),
+			passed: AtomicU64::new(0),
+			denied: AtomicU64::new(0),
+			not_allowed: AtomicU64::new(0),
+			ip_rate_limited: AtomicU64::new(0),
+			subnet_rate_limited: AtomicU64::new(0)
+		}
+	}
+
+	#[inline]
+	fn rules(&self) -> std::sync::MutexGuard<'_, Rules>{
+		self.rules.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	pub fn allow(&self, cidr: Cidr){
+		self.rules().allow.push(cidr);
+	}
+
+	pub fn deny(&self, cidr: Cidr){
+		self.rules().deny.push(cidr);
+	}
+
+	pub fn remove_allowed(&self, cidr: &Cidr){
+		self.rules().allow.retain(|c| c != cidr);
+	}
+
+	pub fn remove_denied(&self, cidr: &Cidr){
+		self.rules().deny.retain(|c| c != cidr);
+	}
+
+	#[inline]
+	pub fn get_allowed(&self) -> Vec<Cidr>{
+		self.rules().allow.clone()
+	}
+
+	#[inline]
+	pub fn get_denied(&self) -> Vec<Cidr>{
+		self.rules().deny.clone()
+	}
+
+	///Sets the rate limit for every source address, None turns it off. Buckets start over
+	pub fn set_ip_rate_limit(&self, limit: Option<RateLimit>){
+		self.rules().ip_limiter = limit.map(|limit| Limiter{ limit, buckets: HashMap::new() });
+	}
+
+	///Sets the rate limit for every subnet, None turns it off. Buckets start over
+	pub fn set_subnet_rate_limit(&self, limit: Option<RateLimit>){
+		self.rules().subnet_limiter = limit.map(|limit| Limiter{ limit, buckets: HashMap::new() });
+	}
+
+	///Sets the prefix lengths that make a subnet for `set_subnet_rate_limit`
+	pub fn set_subnet_prefixes(&self, v4: u8, v6: u8){
+		let mut rules = self.rules();
+		rules.subnet_prefix_v4 = v4.min(32);
+		rules.subnet_prefix_v6 = v6.min(128);
+
+		if let Some(limiter) = rules.subnet_limiter.as_mut(){
+			limiter.buckets.clear();
+		}
+	}
+
+	///Decides if a connection from `addr` may go on and updates the counters
+	pub fn check(&self, addr: IpAddr) -> bool{
+		//ipv4 peers of a dual stack listener come as ::ffff:a.b.c.d
+		let addr = match addr{
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+			addr => addr
+		};
+
+		let mut rules = self.rules();
+
+		let counter = if rules.deny.iter().any(|cidr| cidr.contains(&addr)) {
+			&self.denied
+		} else if !rules.allow.is_empty() && !rules.allow.iter().any(|cidr| cidr.contains(&addr)) {
+			&self.not_allowed
+		} else {
+			let now = Instant::now();
+			let subnet = match addr{
+				IpAddr::V4(_) => mask(addr, rules.subnet_prefix_v4),
+				IpAddr::V6(_) => mask(addr, rules.subnet_prefix_v6)
+			};
+
+			//tokens are taken only once every limit lets the connection in
+			if !rules.ip_limiter.as_mut().is_none_or(|limiter| limiter.has_token(addr, now)) {
+				&self.ip_rate_limited
+			} else if !rules.subnet_limiter.as_mut().is_none_or(|limiter| limiter.has_token(subnet, now)) {
+				&self.subnet_rate_limited
+			} else {
+				if let Some(limiter) = rules.ip_limiter.as_mut(){
+					limiter.take(&addr);
+				}
+				if let Some(limiter) = rules.subnet_limiter.as_mut(){
+					limiter.take(&subnet);
+				}
+				&self.passed
+			}
+		};
+
+		counter.fetch_add(1, Ordering::Relaxed);
+		std::ptr::eq(counter, &self.passed)
+	}
+
+	pub fn stats(&self) -> FirewallStats{
+		FirewallStats{
+			passed: self.passed.load(Ordering::Relaxed),
+			denied: self.denied.load(Ordering::Relaxed),
+			not_allowed: self.not_allowed.load(Ordering::Relaxed),
+			ip_rate_limited: self.ip_rate_limited.load(Ordering::Relaxed),
+			subnet_rate_limited: self.subnet_rate_limited.load(Ordering::Relaxed)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn cidr_test(){
+		let net: Cidr = "10.1.2.3/8".parse().unwrap();
+		assert_eq!(net.to_string(), "10.0.0.0/8");
+		assert!(net.contains(&"10.200.0.1".parse().unwrap()));
+		assert!(!net.contains(&"11.0.0.1".parse().unwrap()));
+		assert!(!net.contains(&"::a00:1".parse().unwrap()));
+
+		let net: Cidr = "2001:db8::/32".parse().unwrap();
+		assert!(net.contains(&"2001:db8:78::1".parse().unwrap()));
+		assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+
+		let single: Cidr = "127.0.0.1".parse().unwrap();
+		assert_eq!(single.get_prefix(), 32);
+		assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&"1.2.3.4".parse().unwrap()));
+
+		assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(CidrParseError::Prefix));
+		assert_eq!("10.0.0.0/x".parse::<Cidr>(), Err(CidrParseError::Prefix));
+		assert!(matches!("10.0.0/8".parse::<Cidr>(), Err(CidrParseError::Address(_))));
+	}
+
+	#[test]
+	fn eviction_test(){
+		let mut limiter = Limiter{ limit: RateLimit{ burst: 1, per_second: 0.001 }, buckets: HashMap::new() };
+		let start = Instant::now();
+
+		for i in 0..MAX_TRACKED_SOURCES as u32 {
+			let source = IpAddr::V4(i.into());
+			assert!(limiter.has_token(source, start + std::time::Duration::from_millis(i as u64)));
+			limiter.take(&source);
+		}
+
+		//nobody has refilled, so only the longest idle ones are forgotten
+		let now = start + std::time::Duration::from_secs(100);
+		assert!(limiter.has_token(IpAddr::V4(u32::MAX.into()), now));
+		assert_eq!(limiter.buckets.len(), MAX_TRACKED_SOURCES - EVICTED_SOURCES + 1);
+		assert!(limiter.buckets.contains_key(&IpAddr::V4((MAX_TRACKED_SOURCES as u32 - 1).into())));
+		assert!(!limiter.buckets.contains_key(&IpAddr::V4(0.into())));
+	}
+
+	#[test]
+	fn firewall_test(){
+		let firewall = Firewall::new();
+		firewall.allow("10.0.0.0/8".parse().unwrap());
+		firewall.deny("10.0.0.78".parse().unwrap());
+
+		assert!(firewall.check("10.0.0.1".parse().unwrap()));
+		assert!(!firewall.check("10.0.0.78".parse().unwrap()));
+		assert!(!firewall.check("192.168.0.1".parse().unwrap()));
+		assert!(firewall.check("::ffff:10.0.0.2".parse().unwrap()));
+
+		firewall.set_ip_rate_limit(Some(RateLimit{ burst: 2, per_second: 0.001 }));
+		firewall.set_subnet_rate_limit(Some(RateLimit{ burst: 3, per_second: 0.001 }));
+
+		assert!(firewall.check("10.0.0.5".parse().unwrap()));
+		assert!(firewall.check("10.0.0.5".parse().unwrap()));
+		assert!(!firewall.check("10.0.0.5".parse().unwrap()));
+		assert!(firewall.check("10.0.0.6".parse().unwrap()));
+		assert!(!firewall.check("10.0.0.7".parse().unwrap()));
+		assert!(firewall.check("10.0.1.7".parse().unwrap()));
+
+		assert_eq!(firewall.stats(), FirewallStats{ passed: 6, denied: 1, not_allowed: 1, ip_rate_limited: 1, subnet_rate_limited: 1 });
+
+		//the refusal by the subnet limit didn't cost 10.0.0.7 its own tokens
+		firewall.set_subnet_rate_limit(None);
+		assert!(firewall.check("10.0.0.7".parse().unwrap()));
+		assert!(firewall.check("10.0.0.7".parse().unwrap()));
+		assert!(!firewall.check("10.0.0.7".parse().unwrap()));
+
+		firewall.set_ip_rate_limit(None);
+		firewall.remove_allowed(&"10.0.0.0/8".parse().unwrap());
+		assert!(firewall.check("192.168.0.1".parse().unwrap()));
+	}
+
+	#[test]
+	fn server_firewall_test(){
+		use crate::client::Client;
+		use futures::StreamExt;
+
+		let (server, addr) = testing::bind();
+		let firewall = server.firewall();
+		firewall.set_ip_rate_limit(Some(RateLimit{ burst: 1, per_second: 0.001 }));
+
+		let incoming = server.incoming(None);
+		let _server_side = testing::spawn(incoming.collect::<Vec<_>>());
+
+		let handshake = || futures::executor::block_on(async move {
+			let mut client = Client::connect(addr, None).await.unwrap();
+			client.handshake(None).await
+		});
+
+		handshake().unwrap();
+		//the second one is dropped without a word
+		assert!(handshake().is_err());
+
+		//the stream picks the change up right away
+		firewall.set_ip_rate_limit(None);
+		handshake().unwrap();
+
+		firewall.deny("127.0.0.0/8".parse().unwrap());
+		assert!(handshake().is_err());
+
+		let stats = firewall.stats();
+		assert_eq!((stats.passed, stats.ip_rate_limited, stats.denied), (2, 1, 1));
+	}
+}
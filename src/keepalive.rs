@@ -0,0 +1,217 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use async_net::TcpStream;
+
+use crate::alert::Alert;
+use crate::client::Writer;
+
+//How long the last alert to an unresponsive peer may take
+const GIVE_UP_LINGER: Duration = Duration::from_secs(1);
+
+///Ping settings of a connection. A ping goes out after every `interval` in which nothing came from the peer,
+///after `missed_pongs` of them in a row the connection is closed with `Alert::PeerUnresponsive`.
+///
+///Nothing reads the connection in the background: pongs are only noticed and the peer's pings only answered inside `Client::get_message`.
+///So only the time spent waiting in `get_message` counts, each call starts the count over.
+///
+///That makes it a hard requirement on the peer: while one side has keepalive on, the other has to keep calling `get_message`,
+///whether it has keepalive on itself or not. A peer that stops reading can't be told from a dead one and is given up on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive{
+	pub interval: Duration,
+	pub missed_pongs: u32
+}
+
+impl Default for Keepalive{
+	#[inline]
+	fn default() -> Keepalive{
+		Keepalive{ interval: Duration::from_secs(15), missed_pongs: 3 }
+	}
+}
+
+///What the ping task and the reading side of a connection share. The task stops once it's dropped
+pub(crate) struct Heartbeat{
+	//something came from the peer since the last tick
+	heard: AtomicBool,
+	//`get_message` is waiting, silence counts only then
+	reading: AtomicBool,
+	unresponsive: AtomicBool
+}
+
+///Marks a `get_message` call, so the time between calls isn't taken for the peer's silence
+pub(crate) struct Reading<'a>{
+	heartbeat: &'a Heartbeat
+}
+
+impl Drop for Reading<'_>{
+	#[inline]
+	fn drop(&mut self){
+		self.heartbeat.reading.store(false, Ordering::Relaxed);
+	}
+}
+
+impl Heartbeat{
+	pub(crate) fn start(keepalive: Keepalive, writer: Arc<Writer>, stream: TcpStream) -> Arc<Heartbeat>{
+		let heartbeat = Arc::new(Heartbeat{ heard: AtomicBool::new(false), reading: AtomicBool::new(false), unresponsive: AtomicBool::new(false) });
+		async_std::task::spawn(beat(keepalive, Arc::downgrade(&heartbeat), writer, stream));
+
+		heartbeat
+	}
+
+	#[inline]
+	pub(crate) fn heard(&self){
+		self.heard.store(true, Ordering::Relaxed);
+	}
+
+	///Starts the count of missed pongs over, it goes on while the returned guard lives
+	#[inline]
+	pub(crate) fn reading(&self) -> Reading<'_>{
+		self.heard();
+		self.reading.store(true, Ordering::Relaxed);
+		Reading{ heartbeat: self }
+	}
+
+	#[inline]
+	pub(crate) fn is_unresponsive(&self) -> bool{
+		self.unresponsive.load(Ordering::SeqCst)
+	}
+}
+
+async fn beat(keepalive: Keepalive, heartbeat: Weak<Heartbeat>, writer: Arc<Writer>, stream: TcpStream){
+	let mut missed = 0;
+	//a ping stuck behind a big write isn't sent again
+	let pinging = Arc::new(AtomicBool::new(false));
+
+	loop {
+		async_std::task::sleep(keepalive.interval).await;
+
+		let Some(heartbeat) = heartbeat.upgrade() else { return };
+
+		//nobody was reading, so the peer couldn't be heard
+		if heartbeat.heard.swap(false, Ordering::Relaxed) || !heartbeat.reading.load(Ordering::Relaxed){
+			missed = 0;
+			continue;
+		}
+
+		if missed >= keepalive.missed_pongs{
+			heartbeat.unresponsive.store(true, Ordering::SeqCst);
+
+			let close = async { writer.close(&Alert::PeerUnresponsive).await };
+			let _ = async_std::future::timeout(GIVE_UP_LINGER, close).await;
+			let _ = stream.shutdown(std::net::Shutdown::Both);
+
+			return;
+		}
+
+		missed += 1;
+
+		if !pinging.swap(true, Ordering::AcqRel){
+			let (writer, pinging) = (writer.clone(), pinging.clone());
+
+			async_std::task::spawn(async move {
+				let _ = writer.ping().await;
+				pinging.store(false, Ordering::Release);
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn keepalive_test(){
+		use crate::alert::Alert;
+		use crate::rpc::{RpcClient, RpcServer};
+		use std::time::{Duration, Instant};
+
+		const KEEPALIVE: Keepalive = Keepalive{ interval: Duration::from_millis(50), missed_pongs: 2 };
+
+		let (mut server, addr) = testing::bind();
+		server.set_keepalive(Some(KEEPALIVE));
+
+		let server_side = testing::spawn(async move {
+			//keeps reading, so the pings are answered while the call is running
+			let rpc = RpcServer::new().method("slow", |_, _| async move {
+				async_std::task::sleep(Duration::from_millis(400)).await;
+				Ok("done".into())
+			});
+			let client = server.listen_handshaked(true, None).await.unwrap();
+			async_std::task::spawn(async move { rpc.run(client).await });
+
+			//stands for a hung peer, it keeps the connection but never reads. A healthy peer has to read, see `Keepalive`
+			let mut silent = server.listen_handshaked(true, None).await.unwrap();
+			let start = Instant::now();
+			let err = silent.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::PeerUnresponsive));
+			assert!(start.elapsed() < Duration::from_secs(1));
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			client.set_keepalive(Some(KEEPALIVE));
+			assert_eq!(client.get_keepalive(), Some(KEEPALIVE));
+
+			//nothing comes for longer than the keepalive allows, but the pongs do
+			let rpc = RpcClient::new(client);
+			assert_eq!(&rpc.call("slow", "").await.unwrap()[..], b"done");
+
+			let _hung = testing::connect(addr).await;
+			server_side.join().unwrap();
+		});
+	}
+
+	#[test]
+	fn slow_reader_test(){
+		use crate::alert::Alert;
+		use crate::Message;
+		use std::time::Duration;
+
+		const KEEPALIVE: Keepalive = Keepalive{ interval: Duration::from_millis(50), missed_pongs: 2 };
+
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+			client.send_message(Message::new("first", 1)).await.unwrap();
+			//answers the pings while it waits for the peer
+			let _ = client.get_message().await;
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			client.set_keepalive(Some(KEEPALIVE));
+			assert_eq!(client.get_message().await.unwrap().get_content(), b"first");
+
+			//busy with something else for much longer than the keepalive allows, the healthy peer isn't given up on
+			async_std::task::sleep(Duration::from_millis(500)).await;
+			let err = client.get_message_with_timeout(Duration::from_millis(300)).await.err().unwrap();
+			assert_eq!((err.kind(), Alert::from_error(&err)), (std::io::ErrorKind::TimedOut, None));
+
+			drop(client);
+			server_side.join().unwrap();
+		});
+	}
+}
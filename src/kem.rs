@@ -41,7 +41,7 @@ pub fn enc_key_to_bytes(key: &ml_kem::kem::EncapsulationKey<MlKem1024Params>) ->
 ///restores encapsulation key from bytes
 #[inline]
 pub fn enc_key_from_bytes(key: Vec<u8>) -> ml_kem::kem::EncapsulationKey<MlKem1024Params> {
-	let type_annotation: ml_kem::Encoded<ml_kem::kem::EncapsulationKey<MlKem1024Params>> = ml_kem::array::Array::try_from_iter(key.into_iter()).unwrap(); 
+	let type_annotation: ml_kem::Encoded<ml_kem::kem::EncapsulationKey<MlKem1024Params>> = ml_kem::array::Array::try_from_iter(key).unwrap(); 
 
 	ml_kem::kem::EncapsulationKey::from_bytes(&type_annotation)
 }
@@ -75,11 +75,9 @@ pub fn encapsulate <CryptoRngCore>(rng: &mut CryptoRngCore, ek: &ml_kem::kem::En
 ///Decapsulates encapsulated key
 pub fn decapsulate(data: &[u8; 1568], dk: &ml_kem::kem::DecapsulationKey<MlKem1024Params>) ->
 	Option<[u8; 32]> {
-	use ml_kem::array::typenum;
+	let ta: Result<ml_kem::Ciphertext<MlKem1024>, ml_kem::array::TryFromIteratorError> = ml_kem::array::Array::try_from_iter(*data);
 
-	let ta: Result<ml_kem::array::Array<u8, typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UInt<typenum::uint::UTerm, typenum::bit::B1>, typenum::bit::B1>, typenum::bit::B0>, typenum::bit::B0>, typenum::bit::B0>, typenum::bit::B1>, typenum::bit::B0>, typenum::bit::B0>, typenum::bit::B0>, typenum::bit::B0>, typenum::bit::B0>>, ml_kem::array::TryFromIteratorError> = ml_kem::array::Array::try_from_iter((*data).into_iter());
-
-	if let Err(_) = ta {
+	if ta.is_err() {
 		return None;
 	}
 
@@ -95,7 +93,7 @@ pub fn decapsulate(data: &[u8; 1568], dk: &ml_kem::kem::DecapsulationKey<MlKem10
 	assert_eq!(key.len(), 32);
 	let mut res = [0u8; 32];
 
-	for (ind, i) in key.into_iter().enumerate(){
+	for (ind, i) in key.iter().enumerate(){
 		res[ind] = *i;
 	}
 
@@ -112,7 +110,7 @@ mod tests{
 
 		let (dk, ek) = create_keypair(&mut rng);
 		let r = enc_key_to_bytes(&ek);
-		assert_eq!(r.len(), 228);
+		assert_eq!(r.len(), 1568);
 		let ek = enc_key_from_bytes(r);
 
 		let (enc, key) = encapsulate(&mut rng, &ek).unwrap();
@@ -0,0 +1,202 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Priority lanes of the sending side of a connection.
+//!
+//! Every record waits for its turn before it's written: a record goes out only once no record of a higher priority is waiting.
+//! Big messages below `Priority::High` are split into fragments, each fragment takes its own turn,
+//! so a control message waits for one fragment at most, never for a whole bulk transfer.
+//! A lane carries one split message at a time, the receiving side puts the fragments of every lane back together
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_std::sync::{Mutex, MutexGuard};
+use event_listener::Event;
+
+///Priority of an outgoing message. Messages of a higher priority jump the queue at record boundaries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority{
+	///Control messages, never split
+	High,
+	#[default]
+	Normal,
+	///Bulk data
+	Low
+}
+
+pub(crate) const LANES: usize = 3;
+
+impl Priority{
+	#[inline]
+	pub(crate) fn lane(self) -> usize{
+		self as usize
+	}
+}
+
+///Who's waiting for a turn, by lane
+pub(crate) struct Lanes{
+	waiting: [AtomicUsize; LANES],
+	turn: Event,
+	//one split message per lane at a time
+	splitting: [Mutex<()>; LANES]
+}
+
+//Stops waiting on drop, so a cancelled send doesn't hold lower lanes up
+struct Waiting<'a>{
+	lanes: &'a Lanes,
+	lane: usize
+}
+
+impl Drop for Waiting<'_>{
+	fn drop(&mut self){
+		self.lanes.waiting[self.lane].fetch_sub(1, Ordering::SeqCst);
+		self.lanes.turn.notify(usize::MAX);
+	}
+}
+
+impl Lanes{
+	#[inline]
+	pub(crate) fn new() -> Lanes{
+		Lanes{
+			waiting: Default::default(),
+			turn: Event::new(),
+			splitting: Default::default()
+		}
+	}
+
+	#[inline]
+	fn is_passed(&self, lane: usize) -> bool{
+		self.waiting[..lane].iter().any(|waiting| waiting.load(Ordering::SeqCst) > 0)
+	}
+
+	///Waits until nothing of a higher priority waits, then takes the connection with `lock`.
+	///If something of a higher priority came while it waited for the connection, it lets the connection go and waits again
+	pub(crate) async fn turn<T, F: Future<Output = T>>(&self, priority: Priority, lock: impl Fn() -> F) -> T{
+		let lane = priority.lane();
+		self.waiting[lane].fetch_add(1, Ordering::SeqCst);
+		let _waiting = Waiting{ lanes: self, lane };
+
+		loop {
+			let turn = self.turn.listen();
+
+			if self.is_passed(lane){
+				turn.await;
+				continue;
+			}
+
+			let guard = lock().await;
+
+			if !self.is_passed(lane){
+				return guard;
+			}
+		}
+	}
+
+	///Takes the lane for a split message
+	#[inline]
+	pub(crate) async fn split(&self, priority: Priority) -> MutexGuard<'_, ()>{
+		self.splitting[priority.lane()].lock().await
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	use std::time::Duration;
+
+	#[test]
+	fn turn_test(){
+		futures::executor::block_on(async {
+			let lanes = Lanes::new();
+			let connection = Mutex::new(Vec::new());
+
+			//the connection is busy, a low and then a high priority record queue up behind it
+			let busy = connection.lock().await;
+
+			let low = async {
+				let mut records = lanes.turn(Priority::Low, || connection.lock()).await;
+				records.push(Priority::Low);
+			};
+
+			let high = async {
+				async_std::task::sleep(Duration::from_millis(20)).await;
+				let mut records = lanes.turn(Priority::High, || connection.lock()).await;
+				records.push(Priority::High);
+			};
+
+			let release = async {
+				async_std::task::sleep(Duration::from_millis(50)).await;
+				drop(busy);
+			};
+
+			futures::join!(low, high, release);
+			assert_eq!(*connection.lock().await, vec![Priority::High, Priority::Low]);
+
+			//a cancelled turn doesn't hold lower lanes up
+			let busy = connection.lock().await;
+			assert!(futures::FutureExt::now_or_never(lanes.turn(Priority::High, || connection.lock())).is_none());
+			assert!(!lanes.is_passed(Priority::Low.lane()));
+			drop(busy);
+		});
+	}
+
+	#[test]
+	fn priority_test(){
+		use crate::Message;
+		use crate::router::Context;
+
+		//big enough to be still going out when the control message comes
+		const BULK: usize = 4 * 1024 * 1024;
+
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+			let context = Context::new(&client);
+
+			let bulk = async_std::task::spawn({
+				let context = context.clone();
+				async move { context.send_with_priority(&Message::new(vec![7u8; BULK], 1), Priority::Low).await }
+			});
+
+			async_std::task::sleep(Duration::from_millis(100)).await;
+			context.send_with_priority(&Message::new("stop", 2), Priority::High).await.unwrap();
+			bulk.await.unwrap();
+
+			//split on the way back too
+			assert_eq!(client.get_message().await.unwrap().get_content(), &[1u8; 100_000][..]);
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+
+			let control = client.get_message().await.unwrap();
+			assert_eq!((control.get_code(), control.get_content()), (2, &b"stop"[..]));
+
+			let bulk = client.get_message().await.unwrap();
+			assert_eq!(bulk.get_code(), 1);
+			assert!(bulk.get_content().len() == BULK && bulk.get_content().iter().all(|&byte| byte == 7));
+
+			client.send_message_with_priority(Message::new(vec![1u8; 100_000], 3), Priority::Low).await.unwrap();
+			server_side.join().unwrap();
+		});
+	}
+}
@@ -23,14 +23,34 @@ This library is for transfering encrypted data through TCP/IP, just for this. It
 * data's encrypted with ChaCha20
 * key and nonce exchange is proceeded with ml-kem in 1024-bit mode
 * it's completely asynchronous
-
-Some features(e.g. refusing/accepting connections based on channels) will be added in further versions.
+* connections can be accepted or refused by peer address and identity with `Server::set_admission`
+* under load clients can be sent for a stateless retry cookie before any KEM work with `Server::set_retry_cookies`
+* hashcash-style puzzles with difficulty that follows the handshake load with `Server::set_puzzles`, `Client::handshake` solves them by itself
+* a cap on live connections with `Server::set_max_connections`, past it the server stops accepting or answers with `Alert::ServerBusy`
+* graceful shutdown with `Server::shutdown_handle`: live connections get `Alert::CloseNotify` and are drained until a deadline
+* accepted connections get a `ConnectionId` and can be looked up, broadcast to, sent to and disconnected with `Server::connections`
+* a `Router` calls async handlers by message code or code range, with per-handler state, a fallback and middleware. `Server::serve` runs it over every connection
+* calls with `rpc::RpcClient::call` and `rpc::RpcServer`: many calls at once on one connection, per-call timeouts, cancellation, error answers and streamed answers
+* a pub/sub `pubsub::Broker` with `+`/`#` topic wildcards, retained messages and per-subscriber queue limits that drop messages or disconnect slow consumers. `Server::serve_broker` runs it over every connection
+* many logical streams over one connection with `mux::Mux`, each with its own flow-control window and fair turns, as messages or as `AsyncRead`/`AsyncWrite` bytes
+* ping/pong keepalive with `Client::set_keepalive` and `Server::set_keepalive`, a silent peer fails `get_message` with `Alert::PeerUnresponsive`
+* a `reconnect::ReconnectingClient` that reconnects with jittered exponential backoff, queues messages while the link is down and reports its state
+* opt-in reliable delivery with `reliable::ReliableClient` and `reliable::Receipts`: acknowledgements, retransmission after reconnect, deduplication and an in-memory or file outbox
+* priority lanes with `Client::send_message_with_priority`: higher priority messages jump the queue at record boundaries, big lower priority messages are split so they don't hold the connection
+* token bucket bandwidth limits on sending and receiving, per connection with `Client::throttle` and for a whole server with `Server::throttle`, changeable at runtime and applied as backpressure
+* zstd/lz4 compression negotiated in the handshake with `Client::set_compression` and `Server::set_compression`, behind `zstd` and `lz4` features. Messages marked `Message::secret_mixed` are never compressed, decompressed sizes are held to `get_max_message_size`
+* padding of records to powers of two or a fixed size with `Client::set_padding`/`Server::set_padding` and cover traffic of dummy records with `set_cover_traffic`, the peer drops both on its own
+* CIDR allow/deny lists and per address and per subnet connection rate limits with `Server::firewall`, changeable at runtime
+* serde values can be sent as they are with `send_typed`/`get_typed`, codecs are behind `bincode`, `postcard`, `cbor` and `json` features
+
+Some features will be added in further versions.
 
 # How to use this
 Here are basic usage examples below:
 
 server
-``` rust
+``` rust,no_run
+# async fn run() -> std::io::Result<()> {
 use korneplod::server::Server;
 use korneplod::tools::sockaddr_from;
 use korneplod::Message;
@@ -44,16 +64,19 @@ let mut client = server.listen_handshaked(true, Some([78u8; 32])).await.unwrap()
 let message = Message::new("new message".as_bytes().to_vec(), 0);
 
 //Sending the message
-client.send_message(&message).await?;
+client.send_message(message).await?;
+# Ok(())
+# }
 ```
 
 client
-``` rust
+``` rust,no_run
+# async fn run() -> std::io::Result<()> {
 use korneplod::client::Client;
 use korneplod::tools::sockaddr_from;
 
 //Connecting to listener
-let mut client = Client::connect( sockaddr_from("127.0.0.1", 1448, false).unwrap() ).await?;
+let mut client = Client::connect( sockaddr_from("127.0.0.1", 1448, false).unwrap(), None ).await?;
 
 //Performing handshaking
 client.handshake(Some([78u8; 32])).await?;
@@ -62,11 +85,35 @@ client.handshake(Some([78u8; 32])).await?;
 let message = client.get_message().await?;
 
 assert_eq!(String::from_utf8(message.get_content_vec()).unwrap(), "new message");
+# Ok(())
+# }
 ```*/
 pub mod message;
+pub mod alert;
+pub mod admission;
+pub mod firewall;
+pub mod cookie;
+pub mod puzzle;
+mod slots;
+pub mod registry;
+pub mod shutdown;
+pub mod router;
+pub mod rpc;
+pub mod pubsub;
+pub mod mux;
 pub mod kem;
 pub mod server;
 pub mod client;
+pub mod lanes;
+pub mod throttle;
+pub mod compression;
+pub mod padding;
+pub mod cover;
+pub mod codec;
+pub mod deadline;
+pub mod keepalive;
+pub mod reconnect;
+pub mod reliable;
 
 pub use message::*;
 
@@ -119,6 +166,42 @@ pub mod tools {
 	}
 }
 
+///Helpers for the tests that go over real connections
+#[cfg(test)]
+pub(crate) mod testing{
+	use std::future::Future;
+	use std::net::SocketAddr;
+
+	use crate::server::Server;
+	use crate::client::Client;
+
+	///Binds a server to a free port of localhost, returns it with its address
+	pub(crate) fn bind() -> (Server, SocketAddr){
+		let server = futures::executor::block_on(Server::new(SocketAddr::from(([127, 0, 0, 1], 0)))).unwrap();
+		let addr = server.local_addr().unwrap();
+
+		(server, addr)
+	}
+
+	///Returns a localhost address nothing listens on
+	pub(crate) fn unused_addr() -> SocketAddr{
+		std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap().local_addr().unwrap()
+	}
+
+	///Runs `future` on its own thread
+	pub(crate) fn spawn<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> std::thread::JoinHandle<T>{
+		std::thread::spawn(move || futures::executor::block_on(future))
+	}
+
+	///Connects and handshakes without a password
+	pub(crate) async fn connect(addr: SocketAddr) -> Client{
+		let mut client = Client::connect(addr, None).await.unwrap();
+		client.handshake(None).await.unwrap();
+
+		client
+	}
+}
+
 #[cfg(test)]
 mod tests{
 	#[test]
@@ -132,7 +215,7 @@ mod tests{
 			let mut server = Server::new(ADDR).await.unwrap();
 			let client = server.listen_handshaked(true, Some([78u8; 32])).await;
 
-			if let None = client{
+			if client.is_none(){
 				panic!("Connection failed");
 			}
 
@@ -145,7 +228,7 @@ mod tests{
 
 		let client_side = async ||{
 			let mut client = Client::connect(ADDR, None).await.unwrap();
-			let _ = client.handshake(Some([78u8; 32])).await.unwrap();
+			client.handshake(Some([78u8; 32])).await.unwrap();
 			let mes = client.get_message_with_timeout(Duration::from_secs(2)).await.unwrap();
 
 			assert_eq!(String::from_utf8(mes.get_content().to_vec()).unwrap(), "i am dungeon master");
@@ -13,17 +13,86 @@ GNU General Public License for more details.
 
 You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.*/
-#[derive(Clone)]
+use bytes::Bytes;
+
+use std::io::{Error, ErrorKind};
+
+///Version of the serialized message layout, the first byte of `as_bytes`
+pub const MESSAGE_FORMAT_VERSION: u8 = 1;
+
+//flags byte, says which optional header fields follow
+const FLAG_CORRELATION_ID: u8 = 1;
+const FLAG_TIMESTAMP: u8 = 1 << 1;
+const FLAG_HEADERS: u8 = 1 << 2;
+
+///Content is kept in `bytes::Bytes`, so cloning a `Message` or getting its content with `get_content_bytes` doesn't copy any data.
+///
+///Serialized layout(integers are LEB128 varints):
+///`version` `flags` `code` `id` [`correlation id`] [`timestamp`] [`header count` (`key len` `key` `value len` `value`)*] `content`
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Message{
-	content: Vec<u8>,
-	code: u8
+	content: Bytes,
+	code: u16,
+	id: u64,
+	correlation_id: Option<u64>,
+	timestamp: Option<u64>,
+	headers: Vec<(String, Bytes)>,
+	//kept on this side only, it isn't serialized
+	secret_mixed: bool
 }
 
 impl Message{
-	///Creates a new `Message` instance with auto-computed checksum
+	///Creates a new `Message` instance with no id, correlation id, timestamp or headers
+	#[inline]
+	pub fn new(content: impl Into<Bytes>, code: u16) -> Message {
+		Message{ content: content.into(), code, id: 0, correlation_id: None, timestamp: None, headers: Vec::new(), secret_mixed: false }
+	}
+
+	#[inline]
+	pub fn with_id(mut self, id: u64) -> Message {
+		self.id = id;
+		self
+	}
+
+	///Marks the message as related to(e.g. an answer to) the message with the given id
+	#[inline]
+	pub fn with_correlation_id(mut self, correlation_id: u64) -> Message {
+		self.correlation_id = Some(correlation_id);
+		self
+	}
+
+	///Sets the timestamp, milliseconds since the unix epoch
+	#[inline]
+	pub fn with_timestamp(mut self, timestamp: u64) -> Message {
+		self.timestamp = Some(timestamp);
+		self
+	}
+
+	///Sets the timestamp to the current time
+	#[inline]
+	pub fn with_timestamp_now(self) -> Message {
+		let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+		self.with_timestamp(now.as_millis() as u64)
+	}
+
+	///Adds a user header. Keys aren't required to be unique, `get_header` returns the first match
 	#[inline]
-	pub fn new(content: Vec<u8>, code: u8) -> Message {
-		Message{ content, code }
+	pub fn with_header(mut self, key: impl Into<String>, value: impl Into<Bytes>) -> Message {
+		self.headers.push((key.into(), value.into()));
+		self
+	}
+
+	///Marks the message as mixing secrets with data somebody else chooses, so it's never compressed, see `compression`.
+	///The mark isn't sent, the peer gets an ordinary message. `reliable::FileOutbox` doesn't keep it either
+	#[inline]
+	pub fn secret_mixed(mut self) -> Message {
+		self.secret_mixed = true;
+		self
+	}
+
+	#[inline]
+	pub fn is_secret_mixed(&self) -> bool{
+		self.secret_mixed
 	}
 
 	#[inline]
@@ -31,33 +100,240 @@ impl Message{
 		&self.content[..]
 	}
 
+	///Copies the content into a new vector. Use `get_content_bytes` or `into_content` to avoid copying
 	#[inline]
 	pub fn get_content_vec(&self) -> Vec<u8>{
+		self.content.to_vec()
+	}
+
+	///Returns the content without copying it
+	#[inline]
+	pub fn get_content_bytes(&self) -> Bytes{
 		self.content.clone()
 	}
 
 	#[inline]
-	pub fn get_code(&self) -> u8{
+	pub fn into_content(self) -> Bytes{
+		self.content
+	}
+
+	#[inline]
+	pub fn get_code(&self) -> u16{
 		self.code
 	}
 
+	#[inline]
+	pub fn get_id(&self) -> u64{
+		self.id
+	}
+
+	#[inline]
+	pub fn get_correlation_id(&self) -> Option<u64>{
+		self.correlation_id
+	}
+
+	#[inline]
+	pub fn get_timestamp(&self) -> Option<u64>{
+		self.timestamp
+	}
+
+	#[inline]
+	pub fn get_header(&self, key: &str) -> Option<&[u8]>{
+		self.headers.iter().find(|(k, _)| k == key).map(|(_, v)| &v[..])
+	}
+
+	#[inline]
+	pub fn get_headers(&self) -> &[(String, Bytes)]{
+		&self.headers[..]
+	}
+
+	#[inline]
+	fn flags(&self) -> u8{
+		let mut flags = 0;
+
+		if self.correlation_id.is_some(){
+			flags |= FLAG_CORRELATION_ID;
+		}
+		if self.timestamp.is_some(){
+			flags |= FLAG_TIMESTAMP;
+		}
+		if !self.headers.is_empty(){
+			flags |= FLAG_HEADERS;
+		}
+
+		flags
+	}
+
+	///Length of the message in bytes, the same as `as_bytes().len()`
+	pub fn encoded_len(&self) -> usize{
+		let mut len = 2 + varint_len(self.code as u64) + varint_len(self.id);
+
+		if let Some(correlation_id) = self.correlation_id{
+			len += varint_len(correlation_id);
+		}
+		if let Some(timestamp) = self.timestamp{
+			len += varint_len(timestamp);
+		}
+		if !self.headers.is_empty(){
+			len += varint_len(self.headers.len() as u64);
+
+			for (key, value) in self.headers.iter(){
+				len += varint_len(key.len() as u64) + key.len() + varint_len(value.len() as u64) + value.len();
+			}
+		}
+
+		len + self.content.len()
+	}
+
+	///Appends the serialized message to `buf`, so it can be built right in a send buffer
+	pub fn write_to(&self, buf: &mut Vec<u8>){
+		buf.reserve(self.encoded_len());
+		buf.push(MESSAGE_FORMAT_VERSION);
+		buf.push(self.flags());
+
+		write_varint(buf, self.code as u64);
+		write_varint(buf, self.id);
+
+		if let Some(correlation_id) = self.correlation_id{
+			write_varint(buf, correlation_id);
+		}
+		if let Some(timestamp) = self.timestamp{
+			write_varint(buf, timestamp);
+		}
+		if !self.headers.is_empty(){
+			write_varint(buf, self.headers.len() as u64);
+
+			for (key, value) in self.headers.iter(){
+				write_varint(buf, key.len() as u64);
+				buf.extend_from_slice(key.as_bytes());
+				write_varint(buf, value.len() as u64);
+				buf.extend_from_slice(value);
+			}
+		}
+
+		buf.extend_from_slice(&self.content);
+	}
+
 	#[inline]
 	pub fn as_bytes(&self) -> Vec<u8> {
-		vec![vec![self.code], self.content.clone()].concat()
+		let mut res = Vec::with_capacity(self.encoded_len());
+		self.write_to(&mut res);
+		res
 	}
 
 	#[inline]
 	pub fn as_bytes_once(self) -> Vec<u8> {
-		vec![vec![self.code], self.content].concat()
+		self.as_bytes()
+	}
+
+	///Restores a message serialized with `as_bytes`. Fails with `ErrorKind::InvalidData` if the version is unknown or the bytes are malformed
+	#[inline]
+	pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Message> {
+		Message::from_shared(Bytes::copy_from_slice(bytes))
+	}
+
+	///The same as `from_bytes`, but the content and header values keep pointing into `bytes` instead of being copied
+	pub fn from_shared(bytes: Bytes) -> std::io::Result<Message> {
+		let mut reader = Reader{ bytes, pos: 0 };
+
+		let version = reader.byte()?;
+		if version != MESSAGE_FORMAT_VERSION {
+			return Err(Error::new(ErrorKind::InvalidData, format!("unsupported message format version {}", version)));
+		}
+
+		let flags = reader.byte()?;
+		let code = u16::try_from(reader.varint()?).map_err(|_| malformed())?;
+		let id = reader.varint()?;
+
+		let correlation_id = match flags & FLAG_CORRELATION_ID != 0{
+			true => Some(reader.varint()?),
+			false => None
+		};
+
+		let timestamp = match flags & FLAG_TIMESTAMP != 0{
+			true => Some(reader.varint()?),
+			false => None
+		};
+
+		let mut headers = Vec::new();
+		if flags & FLAG_HEADERS != 0{
+			let count = reader.varint()?;
+
+			for _ in 0..count{
+				let key_len = reader.varint()?;
+				let key = String::from_utf8(reader.take(key_len)?.to_vec()).map_err(|_| malformed())?;
+				let value_len = reader.varint()?;
+
+				headers.push((key, reader.take(value_len)?));
+			}
+		}
+
+		Ok(Message{ content: reader.bytes.slice(reader.pos..), code, id, correlation_id, timestamp, headers, secret_mixed: false })
+	}
+}
+
+#[inline]
+fn malformed() -> Error{
+	Error::new(ErrorKind::InvalidData, "malformed message")
+}
+
+#[inline]
+fn varint_len(mut value: u64) -> usize{
+	let mut len = 1;
+	while value >= 0x80{
+		value >>= 7;
+		len += 1;
+	}
+	len
+}
+
+#[inline]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64){
+	while value >= 0x80{
+		buf.push(value as u8 | 0x80);
+		value >>= 7;
 	}
+	buf.push(value as u8);
+}
 
-	///The same as `load` method, but from bytes
+struct Reader{
+	bytes: Bytes,
+	pos: usize
+}
+
+impl Reader{
 	#[inline]
-	pub fn from_bytes(bytes: &[u8]) -> Message {
-		Message {
-			content: bytes[1..].to_vec(), 
-			code: bytes[0]
+	fn byte(&mut self) -> std::io::Result<u8>{
+		let res = *self.bytes.get(self.pos).ok_or_else(malformed)?;
+		self.pos += 1;
+		Ok(res)
+	}
+
+	fn varint(&mut self) -> std::io::Result<u64>{
+		let mut res = 0u64;
+
+		for shift in (0..64).step_by(7){
+			let byte = self.byte()?;
+			res |= ((byte & 0x7f) as u64).checked_shl(shift).ok_or_else(malformed)?;
+
+			if byte & 0x80 == 0{
+				return Ok(res);
+			}
 		}
+
+		Err(malformed())
+	}
+
+	fn take(&mut self, len: u64) -> std::io::Result<Bytes>{
+		let end = usize::try_from(len).ok().and_then(|len| self.pos.checked_add(len)).ok_or_else(malformed)?;
+
+		if end > self.bytes.len(){
+			return Err(malformed());
+		}
+
+		let res = self.bytes.slice(self.pos..end);
+		self.pos = end;
+		Ok(res)
 	}
 }
 
@@ -67,9 +343,44 @@ mod tests{
 	#[test]
 	fn ser_de_test(){
 		let message = Message::new("78".as_bytes().to_vec(), 78);
-		let msg = Message::from_bytes(&message.as_bytes()[..]);
+		let msg = Message::from_bytes(&message.as_bytes()[..]).unwrap();
 
 		assert_eq!(msg.get_code(), message.get_code());
 		assert_eq!(msg.get_content(), message.get_content());
 	}
+
+	#[test]
+	fn headers_test(){
+		let message = Message::new("78".as_bytes().to_vec(), 7878)
+			.with_id(u64::MAX)
+			.with_correlation_id(300)
+			.with_timestamp_now()
+			.with_header("encoding", "utf-8")
+			.with_header("empty", Vec::new());
+
+		let bytes = message.as_bytes();
+		assert_eq!(bytes.len(), message.encoded_len());
+		assert_eq!(bytes[0], MESSAGE_FORMAT_VERSION);
+
+		let msg = Message::from_bytes(&bytes).unwrap();
+		assert_eq!(msg, message);
+		assert_eq!(msg.get_header("encoding"), Some("utf-8".as_bytes()));
+		assert_eq!(msg.get_header("nothing"), None);
+
+		assert!(Message::from_bytes(&bytes[..5]).is_err());
+		assert!(Message::from_bytes(&[0, 0, 78]).is_err());
+		assert!(Message::from_bytes(&[]).is_err());
+	}
+
+	#[test]
+	fn shared_test(){
+		let bytes = bytes::Bytes::from(Message::new(vec![1u8, 2, 3], 78).as_bytes());
+		let msg = Message::from_shared(bytes.clone()).unwrap();
+
+		assert_eq!(msg.get_code(), 78);
+		assert_eq!(msg.get_content(), &[1, 2, 3]);
+		assert_eq!(msg.get_content().as_ptr(), bytes[bytes.len() - 3..].as_ptr());
+		assert_eq!(msg.get_content_bytes().as_ptr(), msg.clone().into_content().as_ptr());
+		assert_eq!(msg.encoded_len(), msg.as_bytes().len());
+	}
 }
\ No newline at end of file
@@ -0,0 +1,819 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Many logical streams over one connection.
+//!
+//! Every frame is a message with the stream id as its id. A stream is opened with `CODE_OPEN`, its messages are cut into
+//! `CODE_DATA_MORE` frames of up to `FRAME_SIZE` bytes followed by a last `CODE_DATA` one, and each side says it won't send anymore with `CODE_CLOSE`.
+//! The side that called `Client::handshake` opens odd ids, the other one even ids.
+//!
+//! A side may send only as many data bytes on a stream as the other side allowed. `INITIAL_WINDOW` bytes are allowed from the start,
+//! more are allowed with `CODE_WINDOW` frames as the receiver reads. A side that sends more than a frame past what it was allowed
+//! loses the connection. Streams that have something to send and are allowed to take turns one frame at a time,
+//! so a bulk transfer doesn't hold up the other streams for longer than a frame.
+//!
+//! A side keeps up to `get_max_streams` streams opened by the other side, the ones opened past that are closed right away.
+//!
+//! Codes from `0xFD00` up to `CODE_CLOSE` are taken by streams, other messages on the connection are dropped
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use async_net::TcpStream;
+use bytes::Bytes;
+use event_listener::{Event, EventListener};
+use futures::io::{AsyncRead, AsyncWrite};
+
+use crate::Message;
+use crate::client::Client;
+
+pub const CODE_OPEN: u16 = 0xFD00;
+///Last frame of a message
+pub const CODE_DATA: u16 = 0xFD01;
+///Frame of a message with more frames after it
+pub const CODE_DATA_MORE: u16 = 0xFD02;
+///Allows the other side to send more bytes, a big endian u64
+pub const CODE_WINDOW: u16 = 0xFD03;
+pub const CODE_CLOSE: u16 = 0xFD04;
+
+///Biggest data frame
+pub const FRAME_SIZE: usize = 16 * 1024;
+///Bytes a side may send on a new stream before it's allowed more
+pub const INITIAL_WINDOW: u64 = 64 * 1024;
+pub const DEFAULT_WINDOW: u64 = 256 * 1024;
+///Streams opened by the other side that are kept at the same time by default
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+
+//Data bytes a stream may have waiting to be sent before writes wait
+const QUEUED_LIMIT: usize = 4 * FRAME_SIZE;
+//Bytes written with the byte API go in messages of this size, so each one fits a frame with its encoding
+const CHUNK_SIZE: usize = FRAME_SIZE - 64;
+
+#[derive(Default)]
+struct StreamState{
+	//frames waiting for their turn, in order
+	outbound: VecDeque<Message>,
+	//data bytes in `outbound`
+	queued: usize,
+	//bytes the other side allows us to send, a message may overshoot it
+	credit: i64,
+	//bytes we allow the other side to send, it may overshoot by a frame
+	allowed: i64,
+	//received messages with the number of data bytes they took
+	inbound: VecDeque<(Message, usize)>,
+	//how much of the first inbound message was read with the byte API
+	read_pos: usize,
+	partial: Vec<u8>,
+	//bytes read, but not allowed again yet
+	unacked: u64,
+	local_closed: bool,
+	remote_closed: bool,
+	//nobody holds the stream anymore
+	dropped: bool,
+	error: Option<(ErrorKind, String)>
+}
+
+impl StreamState{
+	fn new() -> StreamState{
+		StreamState{ credit: INITIAL_WINDOW as i64, allowed: INITIAL_WINDOW as i64, ..StreamState::default() }
+	}
+
+	fn push_message(&mut self, id: u64, mes: &Message){
+		let bytes = Bytes::from(mes.as_bytes());
+		let mut pieces = bytes.chunks(FRAME_SIZE).peekable();
+
+		while let Some(piece) = pieces.next() {
+			let code = match pieces.peek(){
+				Some(_) => CODE_DATA_MORE,
+				None => CODE_DATA
+			};
+
+			let frame = Message::new(bytes.slice_ref(piece), code).with_id(id);
+			self.outbound.push_back(if mes.is_secret_mixed() { frame.secret_mixed() } else { frame });
+		}
+
+		self.queued += bytes.len();
+	}
+
+	fn close(&mut self, id: u64){
+		if !self.local_closed{
+			self.local_closed = true;
+			self.outbound.push_back(Message::new(Vec::new(), CODE_CLOSE).with_id(id));
+		}
+	}
+
+	fn is_sendable(&self) -> bool{
+		self.outbound.front().is_some_and(|frame| !is_data(frame) || self.credit > 0)
+	}
+
+	//counts received data bytes against what the other side was allowed
+	fn take_allowed(&mut self, bytes: usize) -> io::Result<()>{
+		self.allowed -= bytes as i64;
+
+		if self.allowed < -(FRAME_SIZE as i64) {
+			return Err(io::Error::new(ErrorKind::InvalidData, "the other side sent more than the stream window"));
+		}
+
+		Ok(())
+	}
+}
+
+#[inline]
+fn is_data(frame: &Message) -> bool{
+	matches!(frame.get_code(), CODE_DATA | CODE_DATA_MORE)
+}
+
+#[inline]
+fn window_frame(id: u64, bytes: u64) -> Message{
+	Message::new(bytes.to_be_bytes().to_vec(), CODE_WINDOW).with_id(id)
+}
+
+struct State{
+	streams: HashMap<u64, StreamState>,
+	//opened by the other side and not accepted yet
+	incoming: VecDeque<u64>,
+	//window frames, they go before any data
+	control: VecDeque<Message>,
+	next_id: u64,
+	last_sent: u64,
+	window: u64,
+	max_streams: usize,
+	max_message_size: u64,
+	//why the connection is gone, once it is
+	closed: Option<(ErrorKind, String)>
+}
+
+impl State{
+	fn new(initiator: bool, max_message_size: u64) -> State{
+		State{
+			streams: HashMap::new(),
+			incoming: VecDeque::new(),
+			control: VecDeque::new(),
+			next_id: if initiator { 1 } else { 2 },
+			last_sent: 0,
+			window: DEFAULT_WINDOW,
+			max_streams: DEFAULT_MAX_STREAMS,
+			max_message_size,
+			closed: None
+		}
+	}
+
+	///Takes the next frame to send: window frames first, then streams in turns by id
+	fn next_frame(&mut self) -> Option<Message>{
+		if let Some(frame) = self.control.pop_front(){
+			return Some(frame);
+		}
+
+		let last = self.last_sent;
+		let id = self.streams.iter()
+			.filter(|(_, stream)| stream.is_sendable())
+			.map(|(id, _)| *id)
+			.min_by_key(|id| (*id <= last, *id))?;
+
+		let stream = self.streams.get_mut(&id)?;
+		let frame = stream.outbound.pop_front()?;
+
+		if is_data(&frame){
+			stream.credit -= frame.get_content().len() as i64;
+			stream.queued -= frame.get_content().len();
+		}
+
+		self.last_sent = id;
+		self.reap(id);
+
+		Some(frame)
+	}
+
+	///Counts read bytes and allows the other side to send more once enough were read
+	fn consumed(&mut self, id: u64, bytes: usize){
+		let threshold = self.window / 2;
+		let Some(stream) = self.streams.get_mut(&id) else { return };
+
+		stream.unacked += bytes as u64;
+
+		if stream.unacked >= threshold && !stream.remote_closed{
+			self.control.push_back(window_frame(id, stream.unacked));
+			stream.allowed += stream.unacked as i64;
+			stream.unacked = 0;
+		}
+	}
+
+	///Fails if the other side broke the rules badly enough to drop the connection
+	fn receive(&mut self, mes: Message) -> io::Result<()>{
+		let id = mes.get_id();
+
+		match mes.get_code(){
+			CODE_OPEN => {
+				//ids of the other side only
+				if self.streams.contains_key(&id) || id % 2 == self.next_id % 2{
+					return Ok(());
+				}
+
+				let theirs = self.streams.keys().filter(|stream| *stream % 2 == id % 2).count();
+
+				if theirs >= self.max_streams{
+					self.control.push_back(Message::new(Vec::new(), CODE_CLOSE).with_id(id));
+					return Ok(());
+				}
+
+				let mut stream = StreamState::new();
+
+				if self.window > INITIAL_WINDOW{
+					self.control.push_back(window_frame(id, self.window - INITIAL_WINDOW));
+					stream.allowed = self.window as i64;
+				}
+
+				self.streams.insert(id, stream);
+				self.incoming.push_back(id);
+			}
+			code @ (CODE_DATA | CODE_DATA_MORE) => {
+				let len = mes.get_content().len();
+				let max_message_size = self.max_message_size;
+				let Some(stream) = self.streams.get_mut(&id) else { return Ok(()) };
+
+				stream.take_allowed(len)?;
+
+				if stream.dropped || stream.error.is_some(){
+					self.consumed(id, len);
+					return Ok(());
+				}
+
+				stream.partial.extend_from_slice(mes.get_content());
+
+				if stream.partial.len() as u64 > max_message_size{
+					stream.error = Some((ErrorKind::InvalidData, "stream message is too large".to_owned()));
+					let dropped = std::mem::take(&mut stream.partial).len();
+					self.consumed(id, dropped);
+					return Ok(());
+				}
+
+				if code == CODE_DATA{
+					let bytes = std::mem::take(&mut stream.partial);
+					let wire = bytes.len();
+
+					match Message::from_shared(bytes.into()){
+						Ok(mes) => stream.inbound.push_back((mes, wire)),
+						Err(e) => {
+							stream.error = Some((e.kind(), e.to_string()));
+							self.consumed(id, wire);
+						}
+					}
+				}
+			}
+			CODE_WINDOW => if let Some(stream) = self.streams.get_mut(&id) && let Ok(bytes) = <[u8; 8]>::try_from(mes.get_content()){
+				stream.credit = stream.credit.saturating_add(u64::from_be_bytes(bytes).min(i64::MAX as u64) as i64);
+			}
+			CODE_CLOSE => if let Some(stream) = self.streams.get_mut(&id){
+				stream.remote_closed = true;
+				self.reap(id);
+			}
+			_ => {}
+		}
+
+		Ok(())
+	}
+
+	//forgets a stream nobody holds once both sides are done with it
+	fn reap(&mut self, id: u64){
+		if let Some(stream) = self.streams.get(&id) && stream.dropped && stream.remote_closed && stream.outbound.is_empty(){
+			self.streams.remove(&id);
+		}
+	}
+
+	fn check_open(&self) -> io::Result<()>{
+		match &self.closed{
+			Some((kind, reason)) => Err(io::Error::new(*kind, reason.clone())),
+			None => Ok(())
+		}
+	}
+}
+
+///What the reader and writer tasks share with the handles
+struct Inner{
+	state: Mutex<State>,
+	//something a handle may wait for has changed
+	changed: Event,
+	//there may be frames to send
+	outbound: Event
+}
+
+impl Inner{
+	#[inline]
+	fn state(&self) -> MutexGuard<'_, State>{
+		self.state.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	#[inline]
+	fn notify(&self){
+		self.changed.notify(usize::MAX);
+		self.outbound.notify(usize::MAX);
+	}
+
+	fn fail(&self, e: &io::Error){
+		self.state().closed.get_or_insert_with(|| (e.kind(), e.to_string()));
+		self.notify();
+	}
+}
+
+//the connection is closed once the last handle is gone
+struct Shared{
+	inner: Arc<Inner>,
+	stream: TcpStream
+}
+
+impl Drop for Shared{
+	#[inline]
+	fn drop(&mut self){
+		let _ = self.stream.shutdown(std::net::Shutdown::Both);
+	}
+}
+
+///Opens and accepts streams over a connection. Clones work on the same connection, it's closed once every clone and stream is dropped
+#[derive(Clone)]
+pub struct Mux{
+	shared: Arc<Shared>
+}
+
+impl Mux{
+	///Takes over a handshaked client. `initiator` is true on the side that called `Client::handshake`
+	pub fn new(mut client: Client, initiator: bool) -> Mux{
+		let inner = Arc::new(Inner{
+			state: Mutex::new(State::new(initiator, client.get_max_message_size())),
+			changed: Event::new(),
+			outbound: Event::new()
+		});
+
+		let writer = client.get_writer();
+		let stream = client.get_stream().clone();
+
+		async_std::task::spawn({
+			let inner = inner.clone();
+			async move {
+				let e = loop {
+					let received = client.get_message().await.and_then(|mes| inner.state().receive(mes));
+
+					match received{
+						Ok(()) => inner.notify(),
+						Err(e) => break e
+					}
+				};
+
+				inner.fail(&e);
+			}
+		});
+
+		async_std::task::spawn({
+			let inner = inner.clone();
+			async move {
+				loop {
+					let outbound = inner.outbound.listen();
+
+					let frame = {
+						let mut state = inner.state();
+
+						if state.closed.is_some(){
+							return;
+						}
+
+						state.next_frame()
+					};
+
+					let Some(frame) = frame else {
+						outbound.await;
+						continue;
+					};
+
+					inner.changed.notify(usize::MAX);
+
+					if let Err(e) = writer.send_message(&frame).await{
+						inner.fail(&e);
+						return;
+					}
+				}
+			}
+		});
+
+		Mux{ shared: Arc::new(Shared{ inner, stream }) }
+	}
+
+	#[inline]
+	fn inner(&self) -> &Inner{
+		&self.shared.inner
+	}
+
+	///Bytes the other side may send on a stream before it waits for us to read, `DEFAULT_WINDOW` by default
+	#[inline]
+	pub fn get_window(&self) -> u64{
+		self.inner().state().window
+	}
+
+	///Applies to streams opened or accepted after the call, can't be lower than `INITIAL_WINDOW`
+	#[inline]
+	pub fn set_window(&self, window: u64){
+		self.inner().state().window = window.max(INITIAL_WINDOW);
+	}
+
+	///Streams opened by the other side that are kept at the same time, `DEFAULT_MAX_STREAMS` by default.
+	///They count until both sides closed them
+	#[inline]
+	pub fn get_max_streams(&self) -> usize{
+		self.inner().state().max_streams
+	}
+
+	///Applies to streams opened by the other side after the call
+	#[inline]
+	pub fn set_max_streams(&self, max_streams: usize){
+		self.inner().state().max_streams = max_streams;
+	}
+
+	///Opens a new stream. The other side gets it from `accept`
+	pub fn open(&self) -> io::Result<MuxStream>{
+		let mut state = self.inner().state();
+		state.check_open()?;
+
+		let id = state.next_id;
+		state.next_id += 2;
+
+		let mut stream = StreamState::new();
+		stream.outbound.push_back(Message::new(Vec::new(), CODE_OPEN).with_id(id));
+
+		if state.window > INITIAL_WINDOW{
+			stream.outbound.push_back(window_frame(id, state.window - INITIAL_WINDOW));
+			stream.allowed = state.window as i64;
+		}
+
+		state.streams.insert(id, stream);
+		drop(state);
+
+		self.inner().notify();
+
+		Ok(MuxStream::new(id, self.shared.clone()))
+	}
+
+	///Waits for a stream opened by the other side
+	pub async fn accept(&self) -> io::Result<MuxStream>{
+		loop {
+			let changed = self.inner().changed.listen();
+
+			{
+				let mut state = self.inner().state();
+
+				if let Some(id) = state.incoming.pop_front(){
+					return Ok(MuxStream::new(id, self.shared.clone()));
+				}
+
+				state.check_open()?;
+			}
+
+			changed.await;
+		}
+	}
+}
+
+///A logical stream of a `Mux`. Sends and receives whole messages with `send_message`/`get_message` or bytes with `AsyncRead`/`AsyncWrite`,
+///mixing the two on one stream isn't a good idea.
+///
+///Dropping the stream closes it, messages it already took are still sent. What comes after that is thrown away
+pub struct MuxStream{
+	id: u64,
+	shared: Arc<Shared>,
+	listener: Option<EventListener>
+}
+
+impl MuxStream{
+	#[inline]
+	fn new(id: u64, shared: Arc<Shared>) -> MuxStream{
+		MuxStream{ id, shared, listener: None }
+	}
+
+	#[inline]
+	pub fn get_id(&self) -> u64{
+		self.id
+	}
+
+	///Calls `f` on the stream's state until it gives something, waiting for changes in between
+	fn poll_state<R>(&mut self, cx: &mut Context<'_>, mut f: impl FnMut(&mut State, u64) -> Option<io::Result<R>>) -> Poll<io::Result<R>>{
+		loop {
+			let res = {
+				let mut state = self.shared.inner.state();
+
+				match state.streams.contains_key(&self.id){
+					true => f(&mut state, self.id),
+					false => Some(Err(io::Error::from(ErrorKind::NotConnected)))
+				}
+			};
+
+			if let Some(res) = res{
+				self.listener = None;
+				self.shared.inner.notify();
+				return Poll::Ready(res);
+			}
+
+			match self.listener.as_mut(){
+				Some(listener) => {
+					futures::ready!(Pin::new(listener).poll(cx));
+					self.listener = None;
+				}
+				None => self.listener = Some(self.shared.inner.changed.listen())
+			}
+		}
+	}
+
+	//waits until the stream may queue more
+	fn poll_writable<R>(&mut self, cx: &mut Context<'_>, mut write: impl FnMut(&mut StreamState, u64) -> R) -> Poll<io::Result<R>>{
+		self.poll_state(cx, |state, id| {
+			if let Err(e) = state.check_open(){
+				return Some(Err(e));
+			}
+
+			let stream = state.streams.get_mut(&id)?;
+
+			if stream.local_closed{
+				return Some(Err(io::Error::new(ErrorKind::BrokenPipe, "stream is closed")));
+			}
+
+			(stream.queued < QUEUED_LIMIT).then(|| Ok(write(stream, id)))
+		})
+	}
+
+	//waits for something to read. None at the end of the stream
+	fn poll_readable<R>(&mut self, cx: &mut Context<'_>, mut read: impl FnMut(&mut State, u64) -> R) -> Poll<io::Result<Option<R>>>{
+		self.poll_state(cx, |state, id| {
+			let stream = state.streams.get(&id)?;
+
+			if !stream.inbound.is_empty(){
+				return Some(Ok(Some(read(state, id))));
+			}
+
+			if let Some((kind, reason)) = &stream.error{
+				return Some(Err(io::Error::new(*kind, reason.clone())));
+			}
+
+			if stream.remote_closed{
+				return Some(Ok(None));
+			}
+
+			state.check_open().err().map(Err)
+		})
+	}
+
+	pub async fn send_message(&mut self, mes: &Message) -> io::Result<()>{
+		futures::future::poll_fn(|cx| self.poll_writable(cx, |stream, id| stream.push_message(id, mes))).await
+	}
+
+	///Fails with `ErrorKind::UnexpectedEof` once the other side closed the stream and everything it sent was read
+	pub async fn get_message(&mut self) -> io::Result<Message>{
+		let mes = futures::future::poll_fn(|cx| self.poll_readable(cx, |state, id| {
+			let stream = state.streams.get_mut(&id)?;
+			let (mes, wire) = stream.inbound.pop_front()?;
+			stream.read_pos = 0;
+			state.consumed(id, wire);
+
+			Some(mes)
+		})).await?;
+
+		mes.flatten().ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "stream is closed"))
+	}
+
+	///Says we won't send anymore, what's queued still goes out. The other side's messages can still be read
+	pub fn close(&mut self){
+		if let Some(stream) = self.shared.inner.state().streams.get_mut(&self.id){
+			stream.close(self.id);
+		}
+
+		self.shared.inner.notify();
+	}
+}
+
+impl AsyncWrite for MuxStream{
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>{
+		let this = self.get_mut();
+		let len = buf.len().min(CHUNK_SIZE);
+
+		this.poll_writable(cx, |stream, id| {
+			stream.push_message(id, &Message::new(buf[..len].to_vec(), 0));
+			len
+		})
+	}
+
+	///Waits until everything queued is sent
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+		self.get_mut().poll_state(cx, |state, id| {
+			if let Err(e) = state.check_open(){
+				return Some(Err(e));
+			}
+
+			state.streams.get(&id)?.outbound.is_empty().then_some(Ok(()))
+		})
+	}
+
+	fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+		self.get_mut().close();
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsyncRead for MuxStream{
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>{
+		let read = self.get_mut().poll_readable(cx, |state, id| {
+			let stream = state.streams.get_mut(&id)?;
+			let (mes, wire) = stream.inbound.front()?;
+			let (wire, rest) = (*wire, &mes.get_content()[stream.read_pos..]);
+
+			let len = rest.len().min(buf.len());
+			buf[..len].copy_from_slice(&rest[..len]);
+			stream.read_pos += len;
+
+			if stream.read_pos == mes.get_content().len(){
+				stream.inbound.pop_front();
+				stream.read_pos = 0;
+				state.consumed(id, wire);
+			}
+
+			Some(len)
+		});
+
+		let len = futures::ready!(read)?;
+		Poll::Ready(Ok(len.flatten().unwrap_or(0)))
+	}
+}
+
+impl Drop for MuxStream{
+	fn drop(&mut self){
+		let mut state = self.shared.inner.state();
+
+		if let Some(stream) = state.streams.get_mut(&self.id){
+			stream.close(self.id);
+			stream.dropped = true;
+
+			let unread = stream.inbound.drain(..).map(|(_, wire)| wire).sum::<usize>() + stream.partial.len();
+			stream.partial.clear();
+
+			state.consumed(self.id, unread);
+			state.reap(self.id);
+		}
+
+		drop(state);
+		self.shared.inner.notify();
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn turns_test(){
+		let mut state = State::new(true, u64::MAX);
+
+		for id in [1, 3] {
+			let mut stream = StreamState::new();
+			stream.push_message(id, &Message::new(vec![0u8; 3 * FRAME_SIZE], 0));
+			state.streams.insert(id, stream);
+		}
+
+		let ids = std::iter::from_fn(|| state.next_frame()).map(|frame| frame.get_id()).collect::<Vec<_>>();
+		assert_eq!(&ids[..6], &[1, 3, 1, 3, 1, 3]);
+
+		//out of credit after INITIAL_WINDOW bytes, reading allows more
+		state.streams.get_mut(&1).unwrap().push_message(1, &Message::new(vec![0u8; INITIAL_WINDOW as usize], 0));
+		let sent = std::iter::from_fn(|| state.next_frame()).count();
+		assert!(sent < 8);
+
+		state.receive(window_frame(1, INITIAL_WINDOW)).unwrap();
+		assert!(state.next_frame().is_some());
+	}
+
+	#[test]
+	fn window_test(){
+		let mut state = State::new(false, u64::MAX);
+		state.receive(Message::new(Vec::new(), CODE_OPEN).with_id(1)).unwrap();
+		assert_eq!(state.incoming.pop_front(), Some(1));
+
+		let window = state.control.pop_front().unwrap();
+		assert_eq!(window.get_content(), (DEFAULT_WINDOW - INITIAL_WINDOW).to_be_bytes());
+
+		state.consumed(1, (DEFAULT_WINDOW / 2) as usize - 1);
+		assert!(state.control.is_empty());
+		state.consumed(1, 1);
+		assert_eq!(state.control.pop_front().unwrap().get_content(), (DEFAULT_WINDOW / 2).to_be_bytes());
+
+		//even ids are ours
+		state.receive(Message::new(Vec::new(), CODE_OPEN).with_id(2)).unwrap();
+		assert!(state.incoming.is_empty());
+	}
+
+	#[test]
+	fn limits_test(){
+		let mut state = State::new(false, u64::MAX);
+		state.max_streams = 2;
+
+		for id in [1, 3, 5] {
+			state.receive(Message::new(Vec::new(), CODE_OPEN).with_id(id)).unwrap();
+		}
+		assert_eq!(state.incoming, [1, 3]);
+
+		//the one over the limit is closed right away
+		let closed = state.control.iter().find(|frame| frame.get_code() == CODE_CLOSE).unwrap();
+		assert_eq!(closed.get_id(), 5);
+
+		//the window and a frame more are fine even if nothing is read, past that the connection fails
+		let frame = || Message::new(vec![0u8; FRAME_SIZE], CODE_DATA_MORE).with_id(1);
+		for _ in 0..DEFAULT_WINDOW as usize / FRAME_SIZE + 1 {
+			state.receive(frame()).unwrap();
+		}
+		assert_eq!(state.receive(frame()).err().unwrap().kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn mux_test(){
+		use crate::Message;
+		use futures::{AsyncReadExt, AsyncWriteExt};
+		use std::sync::Arc;
+		use std::sync::atomic::{AtomicBool, Ordering};
+		use std::time::Duration;
+
+		const BULK: usize = 2 * 1024 * 1024;
+
+		let (mut server, addr) = testing::bind();
+
+		let _server_side = testing::spawn(async move {
+			let mux = Mux::new(server.listen_handshaked(true, None).await.unwrap(), false);
+
+			let mut bulk = mux.accept().await.unwrap();
+			let mut echo = mux.accept().await.unwrap();
+			//never read, so its sender runs out of window
+			let _stalled = mux.accept().await.unwrap();
+
+			async_std::task::spawn(async move {
+				while let Ok(mes) = echo.get_message().await {
+					echo.send_message(&mes).await.unwrap();
+				}
+			});
+
+			let mut received = Vec::new();
+			bulk.read_to_end(&mut received).await.unwrap();
+			bulk.write_all(&(received.len() as u64).to_be_bytes()).await.unwrap();
+			bulk.close();
+
+			futures::future::pending::<()>().await
+		});
+
+		futures::executor::block_on(async {
+			let client = testing::connect(addr).await;
+			let mux = Mux::new(client, true);
+
+			let mut bulk = mux.open().unwrap();
+			let mut echo = mux.open().unwrap();
+			let mut stalled = mux.open().unwrap();
+			assert_eq!((bulk.get_id(), echo.get_id(), stalled.get_id()), (1, 3, 5));
+
+			let bulk_sent = Arc::new(AtomicBool::new(false));
+			let bulk_task = async_std::task::spawn({
+				let bulk_sent = bulk_sent.clone();
+				async move {
+					bulk.write_all(&vec![7u8; BULK]).await.unwrap();
+					bulk.close();
+					bulk_sent.store(true, Ordering::SeqCst);
+
+					let mut total = Vec::new();
+					bulk.read_to_end(&mut total).await.unwrap();
+					total
+				}
+			});
+
+			//the stalled stream takes its window and then waits, without holding up the others
+			let stall = async_std::future::timeout(Duration::from_millis(300), stalled.write_all(&vec![0u8; 1024 * 1024])).await;
+			assert!(stall.is_err());
+
+			//the interactive stream isn't stuck behind the bulk one
+			for i in 0..3u8 {
+				echo.send_message(&Message::new(vec![i], 1)).await.unwrap();
+				assert_eq!(echo.get_message().await.unwrap().get_content(), [i]);
+			}
+			assert!(!bulk_sent.load(Ordering::SeqCst));
+
+			assert_eq!(bulk_task.await, (BULK as u64).to_be_bytes());
+		});
+	}
+}
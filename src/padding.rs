@@ -0,0 +1,173 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Padding of records, so their plaintext lengths don't tell how long the messages are.
+//!
+//! A padded record has zeros after its payload and the 4-byte big-endian number of them at its end, all of it encrypted.
+//! With padding on, messages are split so no record is longer than 64 KiB(or the fixed size), the peer puts them back together.
+//! Records are never padded past what the peer takes, see `Client::set_max_message_size`.
+//! The receiving side strips padding whatever its own policy is
+
+use crate::client::FRAGMENT_SIZE;
+
+//The trailing length of the zeros
+pub(crate) const PADDING_TRAILER: usize = 4;
+
+///Fixed records can't be shorter than this
+pub const MIN_FIXED_RECORD_SIZE: usize = 64;
+
+///How the records of a connection are padded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding{
+	#[default]
+	None,
+	///Every record is padded to the next power of two
+	PowersOfTwo,
+	///Every record is exactly this long, between `MIN_FIXED_RECORD_SIZE` and 64 KiB. Messages that don't fit are split into as many records as they need
+	Fixed(usize)
+}
+
+impl Padding{
+	///Longest record, None if records aren't padded. It's never over `peer_limit`, the longest record the peer takes
+	#[inline]
+	pub(crate) fn get_record_limit(self, peer_limit: usize) -> Option<usize>{
+		match self{
+			Padding::None => None,
+			Padding::PowersOfTwo => Some(FRAGMENT_SIZE.min(peer_limit)),
+			Padding::Fixed(size) => Some(size.clamp(MIN_FIXED_RECORD_SIZE, FRAGMENT_SIZE).min(peer_limit))
+		}
+	}
+
+	///Length a record of `len` bytes, the trailer included, is padded to
+	#[inline]
+	pub(crate) fn padded_len(self, len: usize, peer_limit: usize) -> usize{
+		match (self, self.get_record_limit(peer_limit)){
+			(Padding::PowersOfTwo, Some(limit)) => len.next_power_of_two().min(limit).max(len),
+			(Padding::Fixed(_), Some(size)) => len.div_ceil(size) * size,
+			_ => len
+		}
+	}
+}
+
+///Appends padding for `policy` to a record
+pub(crate) fn pad(record: &mut Vec<u8>, policy: Padding, peer_limit: usize){
+	let zeros = policy.padded_len(record.len() + PADDING_TRAILER, peer_limit) - record.len() - PADDING_TRAILER;
+
+	record.resize(record.len() + zeros, 0);
+	record.extend_from_slice(&(zeros as u32).to_be_bytes());
+}
+
+///Returns the length of a padded record without its padding, None if the padding is malformed
+pub(crate) fn unpadded_len(record: &[u8]) -> Option<usize>{
+	let (rest, zeros) = record.split_last_chunk::<PADDING_TRAILER>()?;
+
+	rest.len().checked_sub(u32::from_be_bytes(*zeros) as usize)
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn padding_test(){
+		for (policy, len, padded) in [
+			(Padding::None, 100, 100),
+			(Padding::PowersOfTwo, 100, 128),
+			(Padding::PowersOfTwo, 128, 128),
+			(Padding::Fixed(256), 100, 256),
+			(Padding::Fixed(256), 300, 512),
+			//too small to be useful
+			(Padding::Fixed(1), 10, MIN_FIXED_RECORD_SIZE)
+		]{
+			assert_eq!(policy.padded_len(len, usize::MAX), padded);
+		}
+
+		//not past what the peer takes
+		assert_eq!(Padding::PowersOfTwo.padded_len(100, 120), 120);
+		assert_eq!(Padding::Fixed(256).padded_len(100, 200), 200);
+		assert_eq!(Padding::Fixed(256).get_record_limit(200), Some(200));
+
+		for policy in [Padding::PowersOfTwo, Padding::Fixed(256)]{
+			for len in [1, 59, 60, 61, 1000]{
+				let mut record = vec![7u8; len];
+				pad(&mut record, policy, usize::MAX);
+
+				assert_eq!(record.len(), policy.padded_len(len + PADDING_TRAILER, usize::MAX));
+				assert_eq!(unpadded_len(&record), Some(len));
+			}
+		}
+
+		assert_eq!(unpadded_len(&[0, 0, 1]), None);
+		assert_eq!(unpadded_len(&[0, 0, 0, 0, 2]), None);
+	}
+
+	#[test]
+	fn padded_connection_test(){
+		use crate::{client::Client, Message};
+		use crate::cover::CoverTraffic;
+		use crate::lanes::Priority;
+
+		const COVER: CoverTraffic = CoverTraffic{ interval: std::time::Duration::from_millis(10), max_size: 300 };
+
+		let messages = [
+			(Message::new(b"small".to_vec(), 1), Priority::Normal),
+			(Message::new((0..200_000).map(|i| i as u8).collect::<Vec<u8>>(), 2), Priority::Normal),
+			//split too once records are padded
+			(Message::new(vec![3u8; 100_000], 3), Priority::High),
+			(Message::new(Vec::new(), 4), Priority::Low)
+		];
+
+		let (mut server, addr) = testing::bind();
+		server.set_padding(Padding::Fixed(256));
+		server.set_cover_traffic(Some(COVER));
+		assert_eq!(server.get_padding(), Padding::Fixed(256));
+
+		let server_side = testing::spawn({
+			let messages = messages.clone();
+			async move {
+				let mut client = server.listen_handshaked(true, None).await.unwrap();
+				assert_eq!(client.get_cover_traffic(), Some(COVER));
+
+				//echoes everything back, cover records in between never show up
+				for (mes, priority) in messages{
+					assert_eq!(client.get_message().await.unwrap(), mes);
+					async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+					client.send_message_with_priority(mes, priority).await.unwrap();
+				}
+
+				assert!(client.get_message().await.is_err());
+			}
+		});
+
+		futures::executor::block_on(async {
+			let mut client = Client::connect(addr, None).await.unwrap();
+			client.set_padding(Padding::PowersOfTwo);
+			client.set_cover_traffic(Some(COVER));
+			client.handshake(None).await.unwrap();
+
+			for (mes, priority) in messages{
+				client.send_message_with_priority(mes.clone(), priority).await.unwrap();
+				assert_eq!(client.get_message().await.unwrap(), mes);
+			}
+
+			drop(client);
+			server_side.join().unwrap();
+		});
+	}
+}
@@ -0,0 +1,561 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Publish/subscribe over a `Server`.
+//!
+//! Topics are levels split by `/`, e.g. `news/sport/football`. A subscription filter may have `+` for any single level
+//! and `#` as the last level for any number of levels, including none: `news/+/football`, `news/#`.
+//!
+//! Peers talk to the `Broker` with control messages made by `subscribe`, `unsubscribe` and `publish`. Subscriptions are answered
+//! with `CODE_ACK` or `CODE_REFUSED` carrying the request id as the correlation id. Published messages come to subscribers as `CODE_PUBLISH`
+//! messages with the topic in the `topic` header, see `get_topic`. Answers go through the subscriber's queue like the messages,
+//! so a subscription's ack comes before its retained messages and anything published after it.
+//!
+//! Codes from `0xFE00` up to `CODE_REFUSED` are taken by the broker, don't use them for other messages on its connections
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_net::TcpStream;
+use async_std::channel::{Sender, TrySendError};
+use bytes::Bytes;
+
+use crate::Message;
+use crate::alert::Alert;
+use crate::client::{Client, Writer};
+
+pub const CODE_SUBSCRIBE: u16 = 0xFE00;
+pub const CODE_UNSUBSCRIBE: u16 = 0xFE01;
+pub const CODE_PUBLISH: u16 = 0xFE02;
+pub const CODE_ACK: u16 = 0xFE03;
+pub const CODE_REFUSED: u16 = 0xFE04;
+
+///Header with the topic of a published message
+pub const TOPIC_HEADER: &str = "topic";
+///Header of a message that's kept as the last one of its topic, or that was kept and is delivered on subscription
+pub const RETAIN_HEADER: &str = "retain";
+
+pub const DEFAULT_QUEUE_LIMIT: usize = 1024;
+pub const DEFAULT_MAX_FILTERS: usize = 256;
+pub const DEFAULT_MAX_RETAINED: usize = 10_000;
+
+//How long a slow consumer is given to take its alert before the socket is closed
+const SLOW_LINGER: Duration = Duration::from_secs(1);
+
+///What the broker does with a subscriber whose queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhenSlow{
+	///Drops the messages that don't fit
+	#[default]
+	DropMessages,
+	///Disconnects the subscriber with `Alert::Disconnected`
+	Disconnect
+}
+
+///Asks the broker for messages of topics matching `filter`
+#[inline]
+pub fn subscribe(filter: &str) -> Message{
+	Message::new(filter.to_owned(), CODE_SUBSCRIBE)
+}
+
+#[inline]
+pub fn unsubscribe(filter: &str) -> Message{
+	Message::new(filter.to_owned(), CODE_UNSUBSCRIBE)
+}
+
+///Publishes `payload` to `topic`. A retained message is kept as the last one of its topic and sent to everyone who subscribes later,
+///a retained empty payload removes the kept one
+pub fn publish(topic: &str, payload: impl Into<Bytes>, retain: bool) -> Message{
+	let mes = Message::new(payload, CODE_PUBLISH).with_header(TOPIC_HEADER, topic.to_owned());
+
+	match retain{
+		true => mes.with_header(RETAIN_HEADER, vec![1]),
+		false => mes
+	}
+}
+
+///Topic of a published message
+#[inline]
+pub fn get_topic(mes: &Message) -> Option<&str>{
+	mes.get_header(TOPIC_HEADER).and_then(|topic| std::str::from_utf8(topic).ok())
+}
+
+#[inline]
+pub fn is_retained(mes: &Message) -> bool{
+	mes.get_header(RETAIN_HEADER) == Some(&[1])
+}
+
+///A topic has at least one level and no wildcards
+pub fn is_valid_topic(topic: &str) -> bool{
+	!topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+///Wildcards take a whole level, `#` only the last one
+pub fn is_valid_filter(filter: &str) -> bool{
+	let levels = filter.split('/').collect::<Vec<_>>();
+
+	!filter.is_empty() && levels.iter().enumerate().all(|(i, level)| match *level{
+		"+" => true,
+		"#" => i == levels.len() - 1,
+		level => !level.contains(['+', '#'])
+	})
+}
+
+pub fn matches(filter: &str, topic: &str) -> bool{
+	let mut topic = topic.split('/');
+
+	for level in filter.split('/') {
+		match (level, topic.next()){
+			("#", _) => return true,
+			("+", Some(_)) => {}
+			(level, Some(topic)) if level == topic => {}
+			_ => return false
+		}
+	}
+
+	topic.next().is_none()
+}
+
+struct Subscriber{
+	filters: Vec<String>,
+	queue: Sender<Message>,
+	writer: Arc<Writer>,
+	stream: TcpStream
+}
+
+#[derive(Default)]
+struct Topics{
+	subscribers: Mutex<HashMap<u64, Subscriber>>,
+	retained: Mutex<HashMap<String, Message>>,
+	next_id: AtomicU64,
+	dropped: AtomicU64,
+	disconnected: AtomicU64
+}
+
+#[inline]
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T>{
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+///Passes published messages to subscribers, see the module docs. Clones share topics, subscribers and retained messages.
+///
+///Every subscriber has a queue of messages waiting to be sent, so a slow one doesn't hold up publishers or other subscribers.
+///Settings are taken by a connection when `run` starts
+#[derive(Clone, Default)]
+pub struct Broker{
+	topics: Arc<Topics>,
+	queue_limit: Option<usize>,
+	max_filters: Option<usize>,
+	max_retained: Option<usize>,
+	when_slow: WhenSlow,
+	secret_mixed: bool
+}
+
+impl Broker{
+	#[inline]
+	pub fn new() -> Broker{
+		Broker::default()
+	}
+
+	///How many messages may wait for a subscriber, `DEFAULT_QUEUE_LIMIT` by default
+	#[inline]
+	pub fn get_queue_limit(&self) -> usize{
+		self.queue_limit.unwrap_or(DEFAULT_QUEUE_LIMIT)
+	}
+
+	#[inline]
+	pub fn set_queue_limit(&mut self, limit: usize){
+		self.queue_limit = Some(limit.max(1));
+	}
+
+	///How many filters a subscriber may have, `DEFAULT_MAX_FILTERS` by default. Subscriptions past that are refused
+	#[inline]
+	pub fn get_max_filters(&self) -> usize{
+		self.max_filters.unwrap_or(DEFAULT_MAX_FILTERS)
+	}
+
+	#[inline]
+	pub fn set_max_filters(&mut self, max_filters: usize){
+		self.max_filters = Some(max_filters);
+	}
+
+	///How many topics may have a retained message, `DEFAULT_MAX_RETAINED` by default.
+	///Retained messages of other topics are refused then, the kept ones can still be replaced or removed
+	#[inline]
+	pub fn get_max_retained(&self) -> usize{
+		self.max_retained.unwrap_or(DEFAULT_MAX_RETAINED)
+	}
+
+	#[inline]
+	pub fn set_max_retained(&mut self, max_retained: usize){
+		self.max_retained = Some(max_retained);
+	}
+
+	#[inline]
+	pub fn get_when_slow(&self) -> WhenSlow{
+		self.when_slow
+	}
+
+	#[inline]
+	pub fn set_when_slow(&mut self, when_slow: WhenSlow){
+		self.when_slow = when_slow;
+	}
+
+	#[inline]
+	pub fn is_secret_mixed(&self) -> bool{
+		self.secret_mixed
+	}
+
+	///Sends the messages to subscribers as `Message::secret_mixed`, so they're never compressed. Off by default.
+	///Peers mark what they publish themselves
+	#[inline]
+	pub fn set_secret_mixed(&mut self, secret_mixed: bool){
+		self.secret_mixed = secret_mixed;
+	}
+
+	///Number of messages dropped because a subscriber's queue was full
+	#[inline]
+	pub fn get_dropped(&self) -> u64{
+		self.topics.dropped.load(Ordering::Relaxed)
+	}
+
+	///Number of subscribers disconnected for being slow
+	#[inline]
+	pub fn get_disconnected(&self) -> u64{
+		self.topics.disconnected.load(Ordering::Relaxed)
+	}
+
+	///Last retained message of a topic
+	#[inline]
+	pub fn get_retained(&self, topic: &str) -> Option<Bytes>{
+		lock(&self.topics.retained).get(topic).map(|mes| mes.get_content_bytes())
+	}
+
+	///Publishes from the server side, same as a peer's `publish`. Returns to how many subscribers the message was queued.
+	///Fails with `ErrorKind::QuotaExceeded` if it should be retained and `get_max_retained` topics already are, then it isn't published at all
+	pub fn publish(&self, topic: &str, payload: impl Into<Bytes>, retain: bool) -> io::Result<usize>{
+		if !is_valid_topic(topic){
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid topic"));
+		}
+
+		let payload = payload.into();
+
+		if retain{
+			let mut retained = lock(&self.topics.retained);
+
+			if !payload.is_empty() && !retained.contains_key(topic) && retained.len() >= self.get_max_retained(){
+				return Err(io::Error::new(io::ErrorKind::QuotaExceeded, "too many retained topics"));
+			}
+
+			match payload.is_empty(){
+				true => retained.remove(topic),
+				false => retained.insert(topic.to_owned(), publish(topic, payload.clone(), true))
+			};
+		}
+
+		let mes = publish(topic, payload, false);
+		let mut subscribers = lock(&self.topics.subscribers);
+		let mut queued = 0;
+		let mut slow = Vec::new();
+
+		for (id, subscriber) in subscribers.iter() {
+			if !subscriber.filters.iter().any(|filter| matches(filter, topic)){
+				continue;
+			}
+
+			match subscriber.queue.try_send(mes.clone()){
+				Ok(()) => queued += 1,
+				Err(TrySendError::Full(_)) => {
+					self.topics.dropped.fetch_add(1, Ordering::Relaxed);
+
+					if self.when_slow == WhenSlow::Disconnect{
+						slow.push(*id);
+					}
+				}
+				Err(TrySendError::Closed(_)) => {}
+			}
+		}
+
+		for subscriber in slow.into_iter().filter_map(|id| subscribers.remove(&id)) {
+			self.disconnect_slow(subscriber);
+		}
+
+		Ok(queued)
+	}
+
+	//the subscriber is already out of the map
+	fn disconnect_slow(&self, subscriber: Subscriber){
+		self.topics.disconnected.fetch_add(1, Ordering::Relaxed);
+		subscriber.queue.close();
+
+		async_std::task::spawn(async move {
+			let close = async { subscriber.writer.close(&Alert::Disconnected("too slow".to_owned())).await };
+			let _ = async_std::future::timeout(SLOW_LINGER, close).await;
+			let _ = subscriber.stream.shutdown(std::net::Shutdown::Both);
+		});
+	}
+
+	///Handles control messages from the connection until it fails, returns that error. Other messages are dropped
+	pub async fn run(&self, mut client: Client) -> io::Result<()>{
+		let id = self.topics.next_id.fetch_add(1, Ordering::Relaxed);
+		let writer = client.get_writer();
+		let (queue, deliveries) = async_std::channel::bounded::<Message>(self.get_queue_limit());
+
+		lock(&self.topics.subscribers).insert(id, Subscriber{
+			filters: Vec::new(),
+			queue: queue.clone(),
+			writer: writer.clone(),
+			stream: client.get_stream().clone()
+		});
+
+		async_std::task::spawn({
+			let writer = writer.clone();
+			let secret_mixed = self.secret_mixed;
+			async move {
+				while let Ok(mes) = deliveries.recv().await {
+					let mes = match secret_mixed{
+						true => mes.secret_mixed(),
+						false => mes
+					};
+
+					if writer.send_message(&mes).await.is_err(){
+						break;
+					}
+				}
+			}
+		});
+
+		let res = self.serve_subscriber(id, &mut client, &queue).await;
+
+		lock(&self.topics.subscribers).remove(&id);
+		queue.close();
+
+		res
+	}
+
+	async fn serve_subscriber(&self, id: u64, client: &mut Client, queue: &Sender<Message>) -> io::Result<()>{
+		loop {
+			let mes = client.get_message().await?;
+			let reply = |code, reason: &str| Message::new(reason.to_owned(), code).with_correlation_id(mes.get_id());
+
+			let answer = match mes.get_code(){
+				CODE_SUBSCRIBE | CODE_UNSUBSCRIBE => {
+					let filter = String::from_utf8_lossy(mes.get_content()).into_owned();
+
+					match is_valid_filter(&filter){
+						true => match self.set_subscribed(id, &filter, mes.get_code() == CODE_SUBSCRIBE, reply(CODE_ACK, "")){
+							Ok(()) => continue,
+							Err(reason) => reply(CODE_REFUSED, reason)
+						}
+						false => reply(CODE_REFUSED, "invalid filter")
+					}
+				}
+				CODE_PUBLISH => match get_topic(&mes).map(|topic| self.publish(topic, mes.get_content_bytes(), is_retained(&mes))){
+					Some(Ok(_)) => continue,
+					Some(Err(e)) => reply(CODE_REFUSED, &e.to_string()),
+					None => reply(CODE_REFUSED, "invalid topic")
+				}
+				_ => continue
+			};
+
+			//the queue is only closed when the subscriber is disconnected for being slow
+			if queue.send(answer).await.is_err(){
+				return Err(io::Error::new(io::ErrorKind::BrokenPipe, "disconnected for being slow"));
+			}
+		}
+	}
+
+	//The ack is queued under the lock, so it comes before the retained messages of a new filter, which are queued right away,
+	//and before anything published after it. Answers that don't fit in the queue count as dropped messages
+	fn set_subscribed(&self, id: u64, filter: &str, subscribed: bool, ack: Message) -> Result<(), &'static str>{
+		let mut subscribers = lock(&self.topics.subscribers);
+		//gone for being slow, it won't read the ack anyway
+		let Some(subscriber) = subscribers.get_mut(&id) else { return Ok(()) };
+
+		let known = subscriber.filters.iter().any(|known| known == filter);
+		if subscribed && !known && subscriber.filters.len() >= self.get_max_filters(){
+			return Err("too many filters");
+		}
+
+		subscriber.filters.retain(|known| known != filter);
+
+		if subscribed{
+			subscriber.filters.push(filter.to_owned());
+		}
+
+		let full = {
+			let retained = lock(&self.topics.retained);
+			let retained = retained.iter().filter(|(topic, _)| subscribed && matches(filter, topic)).map(|(_, mes)| mes.clone());
+
+			std::iter::once(ack).chain(retained).map(|mes| subscriber.queue.try_send(mes)).filter(|res| matches!(res, Err(TrySendError::Full(_)))).count()
+		};
+
+		if full > 0 {
+			self.topics.dropped.fetch_add(full as u64, Ordering::Relaxed);
+
+			if self.when_slow == WhenSlow::Disconnect && let Some(subscriber) = subscribers.remove(&id){
+				self.disconnect_slow(subscriber);
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn matches_test(){
+		assert!(matches("news/sport", "news/sport"));
+		assert!(!matches("news/sport", "news/sport/football"));
+		assert!(!matches("news/sport/football", "news/sport"));
+
+		assert!(matches("news/+/football", "news/sport/football"));
+		assert!(!matches("news/+", "news/sport/football"));
+		assert!(matches("+/+", "news/sport"));
+
+		assert!(matches("news/#", "news/sport/football"));
+		assert!(matches("news/#", "news"));
+		assert!(matches("#", "news"));
+		assert!(!matches("news/#", "weather"));
+	}
+
+	#[test]
+	fn valid_test(){
+		assert!(is_valid_filter("news/+/football"));
+		assert!(is_valid_filter("#"));
+		assert!(!is_valid_filter("news/#/football"));
+		assert!(!is_valid_filter("news/sp+rt"));
+		assert!(!is_valid_filter(""));
+
+		assert!(is_valid_topic("news/sport"));
+		assert!(!is_valid_topic("news/+"));
+		assert!(!is_valid_topic(""));
+	}
+
+	#[test]
+	fn pubsub_test(){
+		use std::time::Duration;
+
+		let mut broker = Broker::new();
+		broker.set_queue_limit(4);
+		broker.set_when_slow(WhenSlow::Disconnect);
+		//nothing changes for the subscribers, the messages only skip compression
+		broker.set_secret_mixed(true);
+		assert!(broker.is_secret_mixed());
+
+		let (server, addr) = testing::bind();
+		let _server_side = testing::spawn({
+			let broker = broker.clone();
+			async move { server.serve_broker(None, broker).await }
+		});
+
+		futures::executor::block_on(async {
+			let mut reader = testing::connect(addr).await;
+			let mut publisher = testing::connect(addr).await;
+
+			reader.send_message(subscribe("news/+").with_id(1)).await.unwrap();
+			let ack = reader.get_message().await.unwrap();
+			assert_eq!((ack.get_code(), ack.get_correlation_id()), (CODE_ACK, Some(1)));
+
+			reader.send_message(subscribe("news/#/old").with_id(2)).await.unwrap();
+			assert_eq!(reader.get_message().await.unwrap().get_code(), CODE_REFUSED);
+
+			publisher.send_message(publish("weather", "rain", false)).await.unwrap();
+			publisher.send_message(publish("news/today", "hello", true)).await.unwrap();
+
+			let mes = reader.get_message().await.unwrap();
+			assert_eq!((get_topic(&mes), mes.get_content(), is_retained(&mes)), (Some("news/today"), &b"hello"[..], false));
+
+			//subscribing later gets the retained message
+			let mut late = testing::connect(addr).await;
+			late.send_message(subscribe("#")).await.unwrap();
+			assert_eq!(late.get_message().await.unwrap().get_code(), CODE_ACK);
+			let mes = late.get_message().await.unwrap();
+			assert_eq!((mes.get_content(), is_retained(&mes)), (&b"hello"[..], true));
+			assert_eq!(broker.get_retained("news/today").as_deref(), Some(&b"hello"[..]));
+
+			reader.send_message(unsubscribe("news/+")).await.unwrap();
+			assert_eq!(reader.get_message().await.unwrap().get_code(), CODE_ACK);
+			assert_eq!(broker.publish("news/today", "", true).unwrap(), 1);
+			assert!(reader.get_message_with_timeout(Duration::from_millis(100)).await.is_err());
+			assert_eq!(broker.get_retained("news/today"), None);
+
+			//`late` doesn't read anymore, so its queue fills up and it's disconnected
+			let big = vec![0u8; 256 * 1024];
+			let mut published = 0;
+			while broker.get_disconnected() == 0 && published < 1000 {
+				let _ = broker.publish("flood", big.clone(), false);
+				published += 1;
+				async_std::task::sleep(Duration::from_millis(1)).await;
+			}
+
+			assert_eq!(broker.get_disconnected(), 1);
+			assert!(broker.get_dropped() > 0);
+		});
+	}
+
+	#[test]
+	fn limits_test(){
+		let mut broker = Broker::new();
+		broker.set_max_filters(2);
+		broker.set_max_retained(1);
+
+		let (server, addr) = testing::bind();
+		let _server_side = testing::spawn({
+			let broker = broker.clone();
+			async move { server.serve_broker(None, broker).await }
+		});
+
+		futures::executor::block_on(async {
+			let mut peer = testing::connect(addr).await;
+
+			for filter in ["a", "b", "a"] {
+				peer.send_message(subscribe(filter)).await.unwrap();
+				assert_eq!(peer.get_message().await.unwrap().get_code(), CODE_ACK);
+			}
+
+			peer.send_message(subscribe("c")).await.unwrap();
+			let refused = peer.get_message().await.unwrap();
+			assert_eq!((refused.get_code(), refused.get_content()), (CODE_REFUSED, &b"too many filters"[..]));
+
+			//room again after an unsubscribe
+			peer.send_message(unsubscribe("b")).await.unwrap();
+			assert_eq!(peer.get_message().await.unwrap().get_code(), CODE_ACK);
+			peer.send_message(subscribe("c")).await.unwrap();
+			assert_eq!(peer.get_message().await.unwrap().get_code(), CODE_ACK);
+
+			broker.publish("x", "1", true).unwrap();
+			assert_eq!(broker.publish("y", "1", true).unwrap_err().kind(), io::ErrorKind::QuotaExceeded);
+			broker.publish("x", "2", true).unwrap();
+
+			peer.send_message(publish("y", "1", true)).await.unwrap();
+			let refused = peer.get_message().await.unwrap();
+			assert_eq!((refused.get_code(), refused.get_content()), (CODE_REFUSED, &b"too many retained topics"[..]));
+
+			broker.publish("x", "", true).unwrap();
+			broker.publish("y", "1", true).unwrap();
+			assert_eq!(broker.get_retained("y").as_deref(), Some(&b"1"[..]));
+		});
+	}
+}
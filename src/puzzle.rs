@@ -0,0 +1,236 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use hmac::Mac;
+use sha2::{Digest, Sha256};
+
+use crate::cookie::{self, CookieJar};
+
+///8-byte big-endian unix time, difficulty byte, 8 random bytes and HMAC-SHA256 of them and the client address
+pub const CHALLENGE_LEN: usize = 8 + 1 + 8 + 32;
+
+const MAC_OFFSET: usize = 8 + 1 + 8;
+
+///The hardest puzzle `Client::handshake` solves by default, in leading zero bits. About 16 million hashes
+pub const DEFAULT_MAX_PUZZLE_DIFFICULTY: u8 = 24;
+
+//Nobody is asked for more than this, hashes have nothing to do with it, solving would just never end
+const MAX_DIFFICULTY: u8 = 48;
+
+//Used challenges are remembered until they expire, at most this many. Past that, solutions are refused and clients get new puzzles
+const MAX_SOLVED: usize = 65536;
+
+///When and how hard the server asks clients to solve a hashcash-style puzzle before it does any KEM work.
+///
+///Difficulty is a number of leading zero bits of `SHA-256(challenge || solution)`, every bit doubles the client's work
+///while the server checks a solution with a single hash. It grows with the number of handshakes running at the same time:
+///`min_difficulty` once there are more than `above` of them, one more bit for every `step` handshakes on top, up to `max_difficulty`.
+///
+///Like retry cookies, puzzles aren't kept by the server. The client reconnects with the solution, which admits one handshake from its address
+///before the puzzle expires, and only while the difficulty asked for isn't above the puzzle's
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PuzzlePolicy{
+	pub above: usize,
+	pub min_difficulty: u8,
+	pub step: usize,
+	pub max_difficulty: u8
+}
+
+impl Default for PuzzlePolicy{
+	#[inline]
+	fn default() -> PuzzlePolicy{
+		PuzzlePolicy{
+			above: 32,
+			min_difficulty: 12,
+			step: 16,
+			max_difficulty: 22
+		}
+	}
+}
+
+impl PuzzlePolicy{
+	///Difficulty for `running` handshakes, 0 means no puzzle
+	pub fn difficulty(&self, running: usize) -> u8{
+		if running <= self.above {
+			return 0;
+		}
+
+		let extra = (running - self.above - 1) / self.step.max(1);
+		let max = self.max_difficulty.clamp(1, MAX_DIFFICULTY);
+
+		(self.min_difficulty as usize + extra).clamp(1, max as usize) as u8
+	}
+}
+
+pub(crate) fn issue(jar: &CookieJar, addr: &IpAddr, difficulty: u8) -> [u8; CHALLENGE_LEN]{
+	let time = cookie::now();
+	let mut challenge = [0u8; CHALLENGE_LEN];
+
+	challenge[..8].copy_from_slice(&time.to_be_bytes());
+	challenge[8] = difficulty;
+	//two clients behind one address in the same second get different puzzles
+	challenge[9..MAC_OFFSET].copy_from_slice(&rand::random::<[u8; 8]>());
+
+	let mac = jar.mac(cookie::MAC_PUZZLE, time, addr, &challenge[8..MAC_OFFSET]).finalize().into_bytes();
+	challenge[MAC_OFFSET..].copy_from_slice(&mac);
+
+	challenge
+}
+
+#[inline]
+pub(crate) fn get_difficulty(challenge: &[u8; CHALLENGE_LEN]) -> u8{
+	challenge[8]
+}
+
+///True if the challenge was issued by this jar to `addr`, hasn't expired or been used yet and `solution` solves it
+pub(crate) fn check(jar: &CookieJar, solved: &SolvedPuzzles, addr: &IpAddr, challenge: &[u8; CHALLENGE_LEN], solution: u64) -> bool{
+	let time = u64::from_be_bytes(challenge[..8].try_into().unwrap());
+
+	cookie::fresh(time)
+		&& jar.mac(cookie::MAC_PUZZLE, time, addr, &challenge[8..MAC_OFFSET]).verify_slice(&challenge[MAC_OFFSET..]).is_ok()
+		&& solves(challenge, solution)
+		&& solved.insert(time, challenge[MAC_OFFSET..].try_into().unwrap())
+}
+
+///Challenges that have already let somebody in, so a solution can't be replayed
+#[derive(Default)]
+pub(crate) struct SolvedPuzzles{
+	solved: Mutex<Solved>
+}
+
+#[derive(Default)]
+struct Solved{
+	macs: HashSet<[u8; 32]>,
+	//oldest first, to forget them once they expire
+	order: VecDeque<(u64, [u8; 32])>
+}
+
+impl SolvedPuzzles{
+	///False if the challenge has been used already or there's no room to remember it
+	fn insert(&self, time: u64, mac: [u8; 32]) -> bool{
+		let mut solved = self.solved.lock().unwrap_or_else(|e| e.into_inner());
+
+		while let Some((time, mac)) = solved.order.front().copied() && !cookie::fresh(time) {
+			solved.macs.remove(&mac);
+			solved.order.pop_front();
+		}
+
+		if solved.macs.len() >= MAX_SOLVED || !solved.macs.insert(mac) {
+			return false;
+		}
+
+		solved.order.push_back((time, mac));
+		true
+	}
+}
+
+///Finds a solution. Takes about `2^difficulty` hashes, so it's better run off the async threads
+pub(crate) fn solve(challenge: &[u8; CHALLENGE_LEN]) -> u64{
+	(0..=u64::MAX).find(|solution| solves(challenge, *solution)).unwrap_or(0)
+}
+
+fn solves(challenge: &[u8; CHALLENGE_LEN], solution: u64) -> bool{
+	let hash = Sha256::new()
+		.chain_update(challenge)
+		.chain_update(solution.to_be_bytes())
+		.finalize();
+
+	let mut zeros = 0;
+
+	for byte in hash{
+		zeros += byte.leading_zeros();
+
+		if byte != 0 {
+			break;
+		}
+	}
+
+	zeros >= get_difficulty(challenge).min(MAX_DIFFICULTY) as u32
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn puzzle_test(){
+		let jar = CookieJar::new();
+		let addr: IpAddr = "10.0.0.78".parse().unwrap();
+
+		let challenge = issue(&jar, &addr, 10);
+		let solution = solve(&challenge);
+
+		let solved = SolvedPuzzles::default();
+
+		assert!(!check(&jar, &solved, &"10.0.0.77".parse().unwrap(), &challenge, solution));
+
+		let wrong = (0..).find(|n| !solves(&challenge, *n)).unwrap();
+		assert!(!check(&jar, &solved, &addr, &challenge, wrong));
+
+		//the difficulty can't be lowered by the client
+		let mut easier = challenge;
+		easier[8] = 0;
+		assert!(!check(&jar, &solved, &addr, &easier, solution));
+
+		assert!(check(&jar, &solved, &addr, &challenge, solution));
+		//one solution, one handshake
+		assert!(!check(&jar, &solved, &addr, &challenge, solution));
+
+		//the same address gets a different puzzle every time
+		assert_ne!(issue(&jar, &addr, 10), challenge);
+	}
+
+	#[test]
+	fn difficulty_test(){
+		let policy = PuzzlePolicy{ above: 10, min_difficulty: 8, step: 5, max_difficulty: 10 };
+
+		assert_eq!(policy.difficulty(10), 0);
+		assert_eq!(policy.difficulty(11), 8);
+		assert_eq!(policy.difficulty(15), 8);
+		assert_eq!(policy.difficulty(16), 9);
+		assert_eq!(policy.difficulty(1000), 10);
+	}
+
+	#[test]
+	fn handshake_puzzle_test(){
+		use crate::{client::Client, Message};
+		use futures::StreamExt;
+
+		let (mut server, addr) = testing::bind();
+		//every handshake counts itself, so `above: 0` asks everybody
+		server.set_puzzles(Some(PuzzlePolicy{ above: 0, min_difficulty: 8, step: 100, max_difficulty: 8 }));
+
+		let incoming = server.incoming(None);
+		let _server_side = testing::spawn(incoming.for_each(|mut client| async move {
+			client.send_message(Message::new("solved", 78)).await.unwrap();
+		}));
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			assert_eq!(client.get_message().await.unwrap().get_content(), b"solved");
+
+			let mut lazy = Client::connect(addr, None).await.unwrap();
+			lazy.set_max_puzzle_difficulty(4);
+			assert_eq!(lazy.handshake(None).await.err().unwrap().kind(), std::io::ErrorKind::ConnectionRefused);
+		});
+	}
+}
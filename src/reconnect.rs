@@ -0,0 +1,541 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use async_net::TcpStream;
+use async_std::channel::{Receiver, Sender};
+use event_listener::Event;
+use futures_lite::future;
+
+use crate::Message;
+use crate::alert::Alert;
+use crate::client::{Client, Writer};
+use crate::deadline::HandshakeDeadlines;
+use crate::keepalive::Keepalive;
+use crate::shutdown::Signal;
+
+pub const DEFAULT_QUEUE_LIMIT: usize = 1024;
+
+//Received messages waiting for `get_message`, the connection isn't read while it's full
+const INCOMING_LIMIT: usize = 64;
+
+///Delays between connection attempts: `initial`, multiplied by `multiplier` after every failed attempt up to `max`.
+///Every delay is shortened by a random part of up to `jitter`, so clients that lost the server together don't come back together
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff{
+	pub initial: Duration,
+	pub max: Duration,
+	pub multiplier: f64,
+	///From 0 to 1
+	pub jitter: f64,
+	///Attempts in a row after which the client gives up. None means it never does
+	pub max_attempts: Option<u32>
+}
+
+impl Default for Backoff{
+	#[inline]
+	fn default() -> Backoff{
+		Backoff{
+			initial: Duration::from_millis(100),
+			max: Duration::from_secs(30),
+			multiplier: 2.0,
+			jitter: 0.5,
+			max_attempts: None
+		}
+	}
+}
+
+impl Backoff{
+	///Delay after `failed` failed attempts in a row
+	pub fn delay(&self, failed: u32) -> Duration{
+		let full = self.initial.as_secs_f64() * self.multiplier.max(1.0).powi(failed.min(i32::MAX as u32) as i32);
+		let full = full.min(self.max.as_secs_f64());
+		let cut = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+
+		Duration::from_secs_f64(full * (1.0 - cut))
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState{
+	///Not started yet, see `ReconnectingClient::connect`
+	Idle,
+	///Connecting and handshaking, `attempt` counts from 1 since the last time it was connected
+	Connecting{ attempt: u32 },
+	Connected,
+	///The connection broke or an attempt failed, another one follows
+	Disconnected{ kind: ErrorKind, reason: String },
+	///Out of attempts, or the server rejected the client. Nothing follows
+	GaveUp{ kind: ErrorKind, reason: String },
+	///Closed by `close` or by dropping the client. Nothing follows
+	Closed
+}
+
+#[derive(Clone)]
+struct Settings{
+	backoff: Backoff,
+	queue_limit: usize,
+	max_message_size: u64,
+	keepalive: Option<Keepalive>,
+	handshake_deadlines: HandshakeDeadlines
+}
+
+struct Link{
+	//the connection, while there is one
+	writer: Option<Arc<Writer>>,
+	stream: Option<TcpStream>,
+	//messages waiting for a connection
+	queue: VecDeque<Message>,
+	state: ConnectionState,
+	events: Vec<Sender<ConnectionState>>,
+	settings: Settings
+}
+
+impl Link{
+	fn enqueue(&mut self, mes: Message) -> io::Result<()>{
+		if self.queue.len() >= self.settings.queue_limit{
+			return Err(Error::new(ErrorKind::WouldBlock, "outbound queue is full"));
+		}
+
+		self.queue.push_back(mes);
+		Ok(())
+	}
+}
+
+struct Shared{
+	link: Mutex<Link>,
+	stop: Arc<Signal>,
+	changed: Event
+}
+
+impl Shared{
+	#[inline]
+	fn link(&self) -> MutexGuard<'_, Link>{
+		self.link.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn set_state(&self, state: ConnectionState){
+		let mut link = self.link();
+		link.events.retain(|events| events.try_send(state.clone()).is_ok());
+		link.state = state;
+		drop(link);
+
+		self.changed.notify(usize::MAX);
+	}
+
+	///Takes a new connection: what's queued goes out first, then new messages.
+	///Senders keep queueing until the queue is empty
+	async fn up(&self, client: &Client) -> io::Result<()>{
+		let writer = client.get_writer();
+		self.link().stream = Some(client.get_stream().clone());
+
+		loop {
+			let mes = {
+				let mut link = self.link();
+
+				match link.queue.pop_front(){
+					Some(mes) => mes,
+					None => {
+						link.writer = Some(writer);
+						return Ok(());
+					}
+				}
+			};
+
+			if let Err(e) = writer.send_message(&mes).await{
+				self.link().queue.push_front(mes);
+				return Err(e);
+			}
+		}
+	}
+
+	fn down(&self){
+		let mut link = self.link();
+		link.writer = None;
+		link.stream = None;
+	}
+}
+
+///A client that reconnects by itself. It keeps the address and the password, and after the link drops it connects again
+///after `Backoff` delays and does a new handshake. Sessions aren't resumed, every connection is a new one to the server.
+///
+///Messages sent while there's no connection are queued up to `get_queue_limit` and go out first once it's back.
+///A message sent right before the link drops may be lost, the connection can't tell whether the peer got it
+pub struct ReconnectingClient{
+	addr: SocketAddr,
+	password: Option<[u8; 32]>,
+	shared: Arc<Shared>,
+	incoming: Receiver<Message>,
+	//taken by the connecting task once it starts
+	incoming_sender: Option<Sender<Message>>
+}
+
+impl ReconnectingClient{
+	///Doesn't connect yet, see `connect`
+	pub fn new(addr: SocketAddr, password: Option<[u8; 32]>) -> ReconnectingClient{
+		let (incoming_sender, incoming) = async_std::channel::bounded(INCOMING_LIMIT);
+
+		let link = Link{
+			writer: None,
+			stream: None,
+			queue: VecDeque::new(),
+			state: ConnectionState::Idle,
+			events: Vec::new(),
+			settings: Settings{
+				backoff: Backoff::default(),
+				queue_limit: DEFAULT_QUEUE_LIMIT,
+				max_message_size: crate::client::DEFAULT_MAX_MESSAGE_SIZE,
+				keepalive: None,
+				handshake_deadlines: HandshakeDeadlines::default()
+			}
+		};
+
+		ReconnectingClient{
+			addr,
+			password,
+			shared: Arc::new(Shared{ link: Mutex::new(link), stop: Signal::new(), changed: Event::new() }),
+			incoming,
+			incoming_sender: Some(incoming_sender)
+		}
+	}
+
+	#[inline]
+	pub fn get_backoff(&self) -> Backoff{
+		self.shared.link().settings.backoff
+	}
+
+	///Applies from the next attempt
+	#[inline]
+	pub fn set_backoff(&mut self, backoff: Backoff){
+		self.shared.link().settings.backoff = backoff;
+	}
+
+	///How many messages may wait for a connection, `DEFAULT_QUEUE_LIMIT` by default
+	#[inline]
+	pub fn get_queue_limit(&self) -> usize{
+		self.shared.link().settings.queue_limit
+	}
+
+	#[inline]
+	pub fn set_queue_limit(&mut self, limit: usize){
+		self.shared.link().settings.queue_limit = limit;
+	}
+
+	#[inline]
+	pub fn get_max_message_size(&self) -> u64{
+		self.shared.link().settings.max_message_size
+	}
+
+	///Applies from the next connection. See `Client::set_max_message_size`
+	#[inline]
+	pub fn set_max_message_size(&mut self, size: u64){
+		self.shared.link().settings.max_message_size = size;
+	}
+
+	#[inline]
+	pub fn get_keepalive(&self) -> Option<Keepalive>{
+		self.shared.link().settings.keepalive
+	}
+
+	///Applies from the next connection. Pings notice a dead link long before a write would, see `Client::set_keepalive`
+	#[inline]
+	pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>){
+		self.shared.link().settings.keepalive = keepalive;
+	}
+
+	#[inline]
+	pub fn get_handshake_deadlines(&self) -> HandshakeDeadlines{
+		self.shared.link().settings.handshake_deadlines
+	}
+
+	#[inline]
+	pub fn set_handshake_deadlines(&mut self, deadlines: HandshakeDeadlines){
+		self.shared.link().settings.handshake_deadlines = deadlines;
+	}
+
+	#[inline]
+	pub fn get_state(&self) -> ConnectionState{
+		self.shared.link().state.clone()
+	}
+
+	///Every state the client goes into from now on, in order
+	pub fn state_events(&self) -> Receiver<ConnectionState>{
+		let (sender, events) = async_std::channel::unbounded();
+		self.shared.link().events.push(sender);
+
+		events
+	}
+
+	///Starts connecting, if it hasn't yet, and waits until the client is connected. Fails if it gives up or is closed.
+	///After that the client keeps reconnecting by itself
+	pub async fn connect(&mut self) -> io::Result<()>{
+		if let Some(incoming) = self.incoming_sender.take(){
+			async_std::task::spawn(supervise(self.addr, self.password, self.shared.clone(), incoming));
+		}
+
+		loop {
+			let changed = self.shared.changed.listen();
+
+			match self.get_state(){
+				ConnectionState::Connected => return Ok(()),
+				ConnectionState::GaveUp{ kind, reason } => return Err(Error::new(kind, reason)),
+				ConnectionState::Closed => return Err(closed()),
+				_ => {}
+			}
+
+			changed.await;
+		}
+	}
+
+	///Sends the message, or queues it while there's no connection.
+	///Fails with `ErrorKind::WouldBlock` if the queue is full and with `ErrorKind::NotConnected` once the client is closed or gave up
+	pub async fn send_message(&self, mes: Message) -> io::Result<()>{
+		let mut tried: Option<Arc<Writer>> = None;
+
+		loop {
+			let writer = {
+				let mut link = self.shared.link();
+
+				if self.shared.stop.is_set() || matches!(link.state, ConnectionState::GaveUp{ .. }){
+					return Err(closed());
+				}
+
+				match &link.writer{
+					//a new connection came up since the failed try
+					Some(writer) if !tried.as_ref().is_some_and(|tried| Arc::ptr_eq(tried, writer)) => writer.clone(),
+					_ => return link.enqueue(mes)
+				}
+			};
+
+			if writer.send_message(&mes).await.is_ok(){
+				return Ok(());
+			}
+
+			tried = Some(writer);
+		}
+	}
+
+	///Receives a message, waiting through reconnects. Fails once the client is closed or gave up and everything received was taken
+	pub async fn get_message(&self) -> io::Result<Message>{
+		self.incoming.recv().await.map_err(|_| match self.get_state(){
+			ConnectionState::GaveUp{ kind, reason } => Error::new(kind, reason),
+			_ => closed()
+		})
+	}
+
+	///Closes the connection with `Alert::CloseNotify` and stops reconnecting. Queued messages are dropped
+	pub async fn close(&self){
+		self.shared.stop.set();
+
+		let writer = self.shared.link().writer.clone();
+		if let Some(writer) = writer{
+			let _ = writer.close(&Alert::CloseNotify).await;
+		}
+
+		//otherwise the connecting task says it once it's done
+		if self.incoming_sender.is_some(){
+			self.shared.set_state(ConnectionState::Closed);
+		}
+	}
+
+	///Stops reconnecting and drops the connection without a word
+	pub(crate) fn stop(&self){
+		self.shared.stop.set();
+
+		if let Some(stream) = &self.shared.link().stream{
+			let _ = stream.shutdown(std::net::Shutdown::Both);
+		}
+	}
+}
+
+impl Drop for ReconnectingClient{
+	#[inline]
+	fn drop(&mut self){
+		self.stop();
+	}
+}
+
+#[inline]
+fn closed() -> Error{
+	Error::new(ErrorKind::NotConnected, "the client is closed")
+}
+
+async fn dial(addr: SocketAddr, password: Option<[u8; 32]>, settings: &Settings) -> io::Result<Client>{
+	let mut client = Client::connect(addr, None).await?;
+	client.set_max_message_size(settings.max_message_size);
+	client.set_handshake_deadlines(settings.handshake_deadlines);
+	client.handshake(password).await?;
+	client.set_keepalive(settings.keepalive);
+
+	Ok(client)
+}
+
+//Never ends before the client is stopped, then fails
+async fn stopped<T>(stop: &Signal) -> io::Result<T>{
+	stop.wait().await;
+	Err(closed())
+}
+
+//Connects, reads until the connection breaks and connects again until it gives up or is stopped
+async fn supervise(addr: SocketAddr, password: Option<[u8; 32]>, shared: Arc<Shared>, incoming: Sender<Message>){
+	let mut failed = 0;
+
+	loop {
+		if shared.stop.is_set(){
+			break;
+		}
+
+		shared.set_state(ConnectionState::Connecting{ attempt: failed + 1 });
+		let settings = shared.link().settings.clone();
+
+		let e = match future::or(dial(addr, password, &settings), stopped(&shared.stop)).await{
+			Ok(mut client) => {
+				failed = 0;
+
+				let read = async {
+					shared.up(&client).await?;
+					shared.set_state(ConnectionState::Connected);
+
+					loop {
+						let mes = client.get_message().await?;
+
+						if incoming.send(mes).await.is_err(){
+							return Err(closed());
+						}
+					}
+				};
+
+				let e = future::or(read, stopped::<()>(&shared.stop)).await.err().unwrap_or_else(closed);
+				shared.down();
+
+				e
+			}
+			Err(e) => {
+				failed += 1;
+				e
+			}
+		};
+
+		if shared.stop.is_set(){
+			break;
+		}
+
+		let rejected = matches!(Alert::from_error(&e), Some(Alert::Rejected(_)));
+		if rejected || settings.backoff.max_attempts.is_some_and(|max| failed >= max){
+			shared.set_state(ConnectionState::GaveUp{ kind: e.kind(), reason: e.to_string() });
+			return;
+		}
+
+		shared.set_state(ConnectionState::Disconnected{ kind: e.kind(), reason: e.to_string() });
+
+		let _ = future::or(async { async_std::task::sleep(settings.backoff.delay(failed)).await; Ok(()) }, stopped(&shared.stop)).await;
+	}
+
+	shared.set_state(ConnectionState::Closed);
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn backoff_test(){
+		let backoff = Backoff{ initial: Duration::from_millis(100), max: Duration::from_secs(1), multiplier: 2.0, jitter: 0.0, max_attempts: None };
+
+		assert_eq!(backoff.delay(0), Duration::from_millis(100));
+		assert_eq!(backoff.delay(2), Duration::from_millis(400));
+		assert_eq!(backoff.delay(10), Duration::from_secs(1));
+		assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+
+		let backoff = Backoff{ jitter: 0.5, ..backoff };
+		for _ in 0..100 {
+			let delay = backoff.delay(1);
+			assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+		}
+	}
+
+	#[test]
+	fn reconnect_test(){
+		use crate::Message;
+		use std::io::ErrorKind;
+		use std::time::Duration;
+
+		const BACKOFF: Backoff = Backoff{ initial: Duration::from_millis(20), max: Duration::from_millis(200), multiplier: 2.0, jitter: 0.5, max_attempts: Some(2) };
+
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let mut first = server.listen_handshaked(true, Some([78u8; 32])).await.unwrap();
+			assert_eq!(first.get_message().await.unwrap().get_content(), b"a");
+			drop(first);
+
+			let mut second = server.listen_handshaked(true, Some([78u8; 32])).await.unwrap();
+			assert_eq!(second.get_message().await.unwrap().get_content(), b"b");
+			assert_eq!(second.get_message().await.unwrap().get_content(), b"c");
+			second.send_message(Message::new("welcome back", 1)).await.unwrap();
+			let _ = second.get_message().await;
+		});
+
+		futures::executor::block_on(async {
+			let mut client = ReconnectingClient::new(addr, Some([78u8; 32]));
+			client.set_backoff(Backoff{ max_attempts: None, ..BACKOFF });
+			let events = client.state_events();
+
+			client.connect().await.unwrap();
+			client.send_message(Message::new("a", 1)).await.unwrap();
+
+			let mut seen = Vec::new();
+			while !matches!(seen.last(), Some(ConnectionState::Disconnected{ .. })) {
+				seen.push(events.recv().await.unwrap());
+			}
+
+			//queued until the client is back
+			client.send_message(Message::new("b", 1)).await.unwrap();
+			client.send_message(Message::new("c", 1)).await.unwrap();
+			assert_eq!(client.get_message().await.unwrap().get_content(), b"welcome back");
+
+			while seen.len() < 5 {
+				seen.push(events.recv().await.unwrap());
+			}
+
+			assert_eq!(seen[..2], [ConnectionState::Connecting{ attempt: 1 }, ConnectionState::Connected]);
+			assert_eq!(seen[3..], [ConnectionState::Connecting{ attempt: 1 }, ConnectionState::Connected]);
+
+			client.close().await;
+			assert_eq!(client.send_message(Message::new("d", 1)).await.err().unwrap().kind(), ErrorKind::NotConnected);
+			server_side.join().unwrap();
+
+			//nothing listens here
+			let mut dead = ReconnectingClient::new(testing::unused_addr(), None);
+			dead.set_backoff(BACKOFF);
+			dead.set_queue_limit(1);
+
+			dead.send_message(Message::new("queued", 1)).await.unwrap();
+			assert_eq!(dead.send_message(Message::new("too many", 1)).await.err().unwrap().kind(), ErrorKind::WouldBlock);
+
+			assert_eq!(dead.connect().await.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+			assert!(matches!(dead.get_state(), ConnectionState::GaveUp{ kind: ErrorKind::ConnectionRefused, .. }));
+		});
+	}
+}
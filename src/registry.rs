@@ -0,0 +1,291 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_net::TcpStream;
+use event_listener::Event;
+
+use crate::Message;
+use crate::alert::Alert;
+use crate::client::{Client, Writer};
+use crate::lanes::Priority;
+use crate::throttle::Throttle;
+use crate::slots::Slot;
+
+///Number the server gives an accepted connection. Ids aren't reused while the server lives
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId{
+	#[inline]
+	pub fn get(&self) -> u64{
+		self.0
+	}
+}
+
+impl std::fmt::Display for ConnectionId{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+		write!(f, "#{}", self.0)
+	}
+}
+
+///What the server knows about a live connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo{
+	pub id: ConnectionId,
+	pub addr: SocketAddr
+}
+
+///What the server keeps of a live connection
+#[derive(Clone)]
+pub(crate) struct Entry{
+	pub(crate) addr: SocketAddr,
+	pub(crate) writer: Arc<Writer>,
+	pub(crate) stream: TcpStream,
+	pub(crate) throttle: Arc<Throttle>
+}
+
+///Live connections of a server
+pub(crate) struct Registry{
+	next_id: AtomicU64,
+	live: Mutex<HashMap<ConnectionId, Entry>>,
+	emptied: Event
+}
+
+///Keeps a connection in the registry and its slot taken, both are given back on drop
+pub(crate) struct Registration{
+	id: ConnectionId,
+	registry: Arc<Registry>,
+	_slot: Slot
+}
+
+impl Registration{
+	#[inline]
+	pub(crate) fn get_id(&self) -> ConnectionId{
+		self.id
+	}
+}
+
+impl Registry{
+	#[inline]
+	pub(crate) fn new() -> Arc<Registry>{
+		Arc::new(Registry{
+			next_id: AtomicU64::new(0),
+			live: Mutex::new(HashMap::new()),
+			emptied: Event::new()
+		})
+	}
+
+	#[inline]
+	fn live(&self) -> MutexGuard<'_, HashMap<ConnectionId, Entry>>{
+		self.live.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	pub(crate) fn register(self: &Arc<Self>, client: &Client, addr: SocketAddr, slot: Slot) -> Registration{
+		let id = ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+		let entry = Entry{ addr, writer: client.get_writer(), stream: client.get_stream().clone(), throttle: client.throttle() };
+
+		self.live().insert(id, entry);
+
+		Registration{ id, registry: self.clone(), _slot: slot }
+	}
+
+	#[inline]
+	pub(crate) fn get(&self, id: ConnectionId) -> Option<Entry>{
+		self.live().get(&id).cloned()
+	}
+
+	#[inline]
+	pub(crate) fn snapshot(&self) -> Vec<(ConnectionId, Entry)>{
+		let mut entries = self.live().iter().map(|(id, entry)| (*id, entry.clone())).collect::<Vec<_>>();
+		entries.sort_by_key(|(id, _)| *id);
+
+		entries
+	}
+
+	fn remove(&self, id: ConnectionId){
+		let mut live = self.live();
+		live.remove(&id);
+
+		if live.is_empty(){
+			self.emptied.notify(usize::MAX);
+		}
+	}
+
+	///Waits until every registration is dropped
+	pub(crate) async fn emptied(&self){
+		loop {
+			let emptied = self.emptied.listen();
+
+			if self.live().is_empty(){
+				return;
+			}
+
+			emptied.await;
+		}
+	}
+}
+
+impl Drop for Registration{
+	#[inline]
+	fn drop(&mut self){
+		self.registry.remove(self.id);
+	}
+}
+
+///Handle to the live connections of a `Server`, see `Server::connections`. Clones look at the same connections.
+///
+///A connection is there from the end of its handshake until its `Client` is dropped or it's disconnected with `disconnect`.
+///Messages sent from here go out between the ones the connection's owner sends, never inside them
+#[derive(Clone)]
+pub struct Connections(Arc<Registry>);
+
+impl Connections{
+	#[inline]
+	pub(crate) fn new(registry: Arc<Registry>) -> Connections{
+		Connections(registry)
+	}
+
+	#[inline]
+	pub fn len(&self) -> usize{
+		self.0.live().len()
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool{
+		self.0.live().is_empty()
+	}
+
+	#[inline]
+	pub fn get(&self, id: ConnectionId) -> Option<ConnectionInfo>{
+		self.0.get(id).map(|entry| ConnectionInfo{ id, addr: entry.addr })
+	}
+
+	///Returns every live connection, oldest first
+	pub fn get_all(&self) -> Vec<ConnectionInfo>{
+		self.0.snapshot().into_iter().map(|(id, entry)| ConnectionInfo{ id, addr: entry.addr }).collect()
+	}
+
+	///Fails with `ErrorKind::NotFound` if there's no such connection
+	pub async fn send_to(&self, id: ConnectionId, mes: &Message) -> io::Result<()>{
+		let entry = self.0.get(id).ok_or_else(not_found)?;
+		entry.writer.send_message(mes).await
+	}
+
+	///Like `send_to`, see `Client::send_message_with_priority`
+	pub async fn send_to_with_priority(&self, id: ConnectionId, mes: &Message, priority: Priority) -> io::Result<()>{
+		let entry = self.0.get(id).ok_or_else(not_found)?;
+		entry.writer.send_message_with_priority(mes, priority).await
+	}
+
+	///Bandwidth limits of the connection, see `Client::throttle`. Fails with `ErrorKind::NotFound` if there's no such connection
+	pub fn throttle(&self, id: ConnectionId) -> io::Result<Arc<Throttle>>{
+		Ok(self.0.get(id).ok_or_else(not_found)?.throttle)
+	}
+
+	///Sends the message to every live connection at the same time and returns to how many it was sent
+	pub async fn broadcast(&self, mes: &Message) -> usize{
+		let sends = self.0.snapshot().into_iter().map(|(_, entry)| async move {
+			entry.writer.send_message(mes).await.is_ok()
+		});
+
+		futures::future::join_all(sends).await.into_iter().filter(|sent| *sent).count()
+	}
+
+	///Sends `Alert::Disconnected` with the reason and closes the connection. The owner's `get_message` fails right away,
+	///the connection is gone from the registry at once. Fails with `ErrorKind::NotFound` if there's no such connection
+	pub async fn disconnect(&self, id: ConnectionId, reason: impl Into<String>) -> io::Result<()>{
+		let entry = self.0.get(id).ok_or_else(not_found)?;
+		self.0.remove(id);
+
+		let res = entry.writer.close(&Alert::Disconnected(reason.into())).await;
+		let _ = entry.stream.shutdown(std::net::Shutdown::Read);
+
+		res
+	}
+}
+
+#[inline]
+fn not_found() -> Error{
+	Error::new(ErrorKind::NotFound, "no such connection")
+}
+
+#[cfg(test)]
+mod tests{
+	use crate::testing;
+
+	#[test]
+	fn connections_test(){
+		use crate::{alert::Alert, Message};
+		use futures::StreamExt;
+		use std::time::Duration;
+
+		let (server, addr) = testing::bind();
+		let connections = server.connections();
+		let incoming = server.incoming(None);
+
+		//the owners only read, so they drop their connections once the peers are gone
+		let _server_side = testing::spawn(incoming.for_each(|mut client| async move {
+			assert!(client.get_connection_id().is_some());
+			async_std::task::spawn(async move { while client.get_message().await.is_ok() {} });
+		}));
+
+		let wait_for = |count| {
+			while connections.len() != count {
+				std::thread::sleep(Duration::from_millis(10));
+			}
+		};
+
+		futures::executor::block_on(async {
+			let mut first = testing::connect(addr).await;
+			assert!(first.get_connection_id().is_none());
+			wait_for(1);
+
+			let mut second = testing::connect(addr).await;
+			wait_for(2);
+
+			let all = connections.get_all();
+			let (first_id, second_id) = (all[0].id, all[1].id);
+			assert!(connections.get(first_id).unwrap().addr.ip().is_loopback());
+
+			assert_eq!(connections.broadcast(&Message::new("everybody", 0)).await, 2);
+			assert_eq!(first.get_message().await.unwrap().get_content(), b"everybody");
+			assert_eq!(second.get_message().await.unwrap().get_content(), b"everybody");
+
+			connections.send_to(first_id, &Message::new("just you", 1)).await.unwrap();
+			assert_eq!(first.get_message().await.unwrap().get_content(), b"just you");
+
+			connections.disconnect(second_id, "bye").await.unwrap();
+			let err = second.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::Disconnected("bye".to_string())));
+			assert!(connections.get(second_id).is_none());
+
+			drop(first);
+			wait_for(0);
+
+			let err = connections.send_to(first_id, &Message::new("gone", 2)).await.err().unwrap();
+			assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+		});
+	}
+}
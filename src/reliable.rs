@@ -0,0 +1,666 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Delivery that survives dropped connections.
+//!
+//! A `ReliableClient` numbers every message it sends and keeps it in an `Outbox` until the receiver acknowledges it.
+//! After every reconnect everything unacknowledged is sent again, in order. The receiver delivers each sequence number of a sender once
+//! and in order, with `Receipts`, and acknowledges it right away, so a message is delivered at least once and never twice while the receiver keeps its `Receipts`.
+//! An acknowledgement that couldn't be sent is sent again when the sender retransmits the message, which is then a duplicate.
+//!
+//! A message goes as `CODE_RELIABLE` with the sequence number as its id, the sender id in the `reliable-sender` header
+//! and the whole original message as content. `CODE_RELIABLE_ACK` has the same header and the last delivered sequence number as its correlation id.
+//! Codes from `0xFC00` up to `CODE_RELIABLE_ACK` are taken
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_std::channel::Receiver;
+
+use crate::Message;
+use crate::client::Client;
+use crate::reconnect::{ConnectionState, ReconnectingClient};
+use crate::router::Context;
+
+pub const CODE_RELIABLE: u16 = 0xFC00;
+pub const CODE_RELIABLE_ACK: u16 = 0xFC01;
+
+///Header with the id of the sender, a big endian u64
+pub const SENDER_HEADER: &str = "reliable-sender";
+
+//Received messages waiting for `get_message`
+const INCOMING_LIMIT: usize = 64;
+
+///Keeps sent messages until they're acknowledged. Sequence numbers of one outbox start from 1 and never repeat,
+///its sender id stays the same as long as it keeps its messages, e.g. across restarts for `FileOutbox`
+pub trait Outbox: Send + Sync{
+	fn get_sender_id(&self) -> u64;
+	///Keeps a message and returns its sequence number
+	fn push(&self, mes: &Message) -> io::Result<u64>;
+	///Forgets every message up to and including `seq`
+	fn ack(&self, seq: u64) -> io::Result<()>;
+	///Messages waiting for an acknowledgement, oldest first
+	fn pending(&self) -> io::Result<Vec<(u64, Message)>>;
+}
+
+struct Pending{
+	next_seq: u64,
+	messages: BTreeMap<u64, Message>
+}
+
+impl Pending{
+	#[inline]
+	fn new(next_seq: u64) -> Pending{
+		Pending{ next_seq, messages: BTreeMap::new() }
+	}
+
+	fn push(&mut self, mes: &Message) -> u64{
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		self.messages.insert(seq, mes.clone());
+
+		seq
+	}
+
+	fn ack(&mut self, seq: u64){
+		self.messages = self.messages.split_off(&seq.saturating_add(1));
+	}
+
+	fn list(&self) -> Vec<(u64, Message)>{
+		self.messages.iter().map(|(seq, mes)| (*seq, mes.clone())).collect()
+	}
+}
+
+#[inline]
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T>{
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+///Outbox in memory, it's lost with the process
+pub struct MemoryOutbox{
+	sender_id: u64,
+	pending: Mutex<Pending>
+}
+
+impl MemoryOutbox{
+	#[inline]
+	pub fn new() -> MemoryOutbox{
+		MemoryOutbox{ sender_id: rand::random(), pending: Mutex::new(Pending::new(1)) }
+	}
+}
+
+impl Default for MemoryOutbox{
+	#[inline]
+	fn default() -> MemoryOutbox{
+		MemoryOutbox::new()
+	}
+}
+
+impl Outbox for MemoryOutbox{
+	#[inline]
+	fn get_sender_id(&self) -> u64{
+		self.sender_id
+	}
+
+	#[inline]
+	fn push(&self, mes: &Message) -> io::Result<u64>{
+		Ok(lock(&self.pending).push(mes))
+	}
+
+	#[inline]
+	fn ack(&self, seq: u64) -> io::Result<()>{
+		lock(&self.pending).ack(seq);
+		Ok(())
+	}
+
+	#[inline]
+	fn pending(&self) -> io::Result<Vec<(u64, Message)>>{
+		Ok(lock(&self.pending).list())
+	}
+}
+
+const FILE_MAGIC: &[u8; 4] = b"KOB1";
+const ENTRY_PUSH: u8 = 0;
+const ENTRY_ACK: u8 = 1;
+
+///Outbox in a file, so pending messages outlive the process.
+///
+///The file is a header(`KOB1`, sender id, first sequence number, big endian u64s) followed by a log of pushes(0, seq, length, message)
+///and acks(1, seq). It's written anew with just the header once every message is acknowledged, next to the old one and renamed over it,
+///so a crash leaves one of the two whole. A torn entry at the end is ignored.
+///
+///A write that fails halfway is cut off again. If even that fails, the outbox refuses to write until every message
+///is acknowledged or it's opened again, since entries after a torn one would be lost
+pub struct FileOutbox{
+	path: PathBuf,
+	sender_id: u64,
+	state: Mutex<FileState>
+}
+
+struct FileState{
+	file: File,
+	pending: Pending,
+	//a torn entry is left at the end of the file
+	poisoned: bool
+}
+
+impl FileState{
+	fn append(&mut self, entry: &[u8]) -> io::Result<()>{
+		if self.poisoned{
+			return Err(Error::other("the outbox file has a torn entry, open it again"));
+		}
+
+		let len = self.file.stream_position()?;
+
+		if let Err(e) = self.file.write_all(entry){
+			self.poisoned = self.file.set_len(len).and_then(|_| self.file.seek(SeekFrom::Start(len))).is_err();
+			return Err(e);
+		}
+
+		Ok(())
+	}
+}
+
+impl FileOutbox{
+	///Opens the outbox at `path` or creates it with a new sender id
+	pub fn open(path: impl AsRef<Path>) -> io::Result<FileOutbox>{
+		let path = path.as_ref().to_owned();
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+
+		let mut bytes = Vec::new();
+		file.read_to_end(&mut bytes)?;
+
+		let (sender_id, pending) = match bytes.is_empty(){
+			true => {
+				let sender_id = rand::random();
+				file = write_header(&path, sender_id, 1)?;
+
+				(sender_id, Pending::new(1))
+			}
+			false => {
+				let (sender_id, pending, len) = replay(&bytes)?;
+				//a torn entry goes, so new ones don't follow it
+				file.set_len(len as u64)?;
+
+				(sender_id, pending)
+			}
+		};
+
+		file.seek(SeekFrom::End(0))?;
+
+		Ok(FileOutbox{ path, sender_id, state: Mutex::new(FileState{ file, pending, poisoned: false }) })
+	}
+}
+
+fn write_header(path: &Path, sender_id: u64, next_seq: u64) -> io::Result<File>{
+	let mut temp = path.as_os_str().to_owned();
+	temp.push(".tmp");
+	let temp = PathBuf::from(temp);
+
+	let mut file = File::create(&temp)?;
+	file.write_all(&[&FILE_MAGIC[..], &sender_id.to_be_bytes(), &next_seq.to_be_bytes()].concat())?;
+	file.sync_all()?;
+	drop(file);
+
+	std::fs::rename(&temp, path)?;
+
+	let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+	file.seek(SeekFrom::End(0))?;
+
+	Ok(file)
+}
+
+//Returns the sender id, the pending messages and the length of the whole entries
+fn replay(bytes: &[u8]) -> io::Result<(u64, Pending, usize)>{
+	let malformed = || Error::new(ErrorKind::InvalidData, "not an outbox file");
+
+	if bytes.len() < 20 || &bytes[..4] != FILE_MAGIC{
+		return Err(malformed());
+	}
+
+	let u64_at = |pos: usize| bytes.get(pos..pos + 8).map(|b| u64::from_be_bytes(b.try_into().unwrap()));
+
+	let sender_id = u64_at(4).ok_or_else(malformed)?;
+	let mut pending = Pending::new(u64_at(12).ok_or_else(malformed)?);
+	let mut pos = 20;
+
+	while let (Some(&kind), Some(seq)) = (bytes.get(pos), u64_at(pos + 1)) {
+		match kind{
+			ENTRY_PUSH => {
+				let Some(len) = u64_at(pos + 9) else { break };
+				let Some(mes) = bytes.get(pos + 17..).and_then(|rest| rest.get(..usize::try_from(len).ok()?)) else { break };
+
+				pending.messages.insert(seq, Message::from_bytes(mes)?);
+				pending.next_seq = pending.next_seq.max(seq + 1);
+				pos += 17 + mes.len();
+			}
+			ENTRY_ACK => {
+				pending.ack(seq);
+				pos += 9;
+			}
+			_ => return Err(malformed())
+		}
+	}
+
+	Ok((sender_id, pending, pos))
+}
+
+impl Outbox for FileOutbox{
+	#[inline]
+	fn get_sender_id(&self) -> u64{
+		self.sender_id
+	}
+
+	fn push(&self, mes: &Message) -> io::Result<u64>{
+		let mut state = lock(&self.state);
+
+		let bytes = mes.as_bytes();
+		let seq = state.pending.next_seq;
+		state.append(&[&[ENTRY_PUSH][..], &seq.to_be_bytes(), &(bytes.len() as u64).to_be_bytes(), &bytes].concat())?;
+
+		Ok(state.pending.push(mes))
+	}
+
+	fn ack(&self, seq: u64) -> io::Result<()>{
+		let mut state = lock(&self.state);
+
+		if state.pending.messages.first_key_value().is_none_or(|(first, _)| *first > seq){
+			return Ok(());
+		}
+
+		state.pending.ack(seq);
+
+		match state.pending.messages.is_empty(){
+			//the new file has no torn entry
+			true => {
+				state.file = write_header(&self.path, self.sender_id, state.pending.next_seq)?;
+				state.poisoned = false;
+			}
+			false => state.append(&[&[ENTRY_ACK][..], &seq.to_be_bytes()].concat())?
+		}
+
+		Ok(())
+	}
+
+	#[inline]
+	fn pending(&self) -> io::Result<Vec<(u64, Message)>>{
+		Ok(lock(&self.state).pending.list())
+	}
+}
+
+#[inline]
+fn get_sender(mes: &Message) -> Option<u64>{
+	Some(u64::from_be_bytes(mes.get_header(SENDER_HEADER)?.try_into().ok()?))
+}
+
+#[inline]
+fn wrap(sender_id: u64, seq: u64, mes: &Message) -> Message{
+	let wrapped = Message::new(mes.as_bytes(), CODE_RELIABLE).with_id(seq).with_header(SENDER_HEADER, sender_id.to_be_bytes().to_vec());
+
+	match mes.is_secret_mixed(){
+		true => wrapped.secret_mixed(),
+		false => wrapped
+	}
+}
+
+#[inline]
+fn ack_for(sender_id: u64, seq: u64) -> Message{
+	Message::new(Vec::new(), CODE_RELIABLE_ACK).with_correlation_id(seq).with_header(SENDER_HEADER, sender_id.to_be_bytes().to_vec())
+}
+
+struct Shared{
+	client: ReconnectingClient,
+	outbox: Arc<dyn Outbox>,
+	//retransmissions don't interleave with new messages
+	sending: async_std::sync::Mutex<()>
+}
+
+impl Shared{
+	async fn retransmit(&self) -> io::Result<()>{
+		let _sending = self.sending.lock().await;
+
+		for (seq, mes) in self.outbox.pending()? {
+			//a full queue is fine, the next reconnect sends it again
+			let _ = self.client.send_message(wrap(self.outbox.get_sender_id(), seq, &mes)).await;
+		}
+
+		Ok(())
+	}
+}
+
+///Sends messages reliably over a `ReconnectingClient`, see the module docs.
+///Messages that aren't acknowledgements are passed on to `get_message` as they are
+pub struct ReliableClient{
+	shared: Arc<Shared>,
+	incoming: Receiver<Message>
+}
+
+impl ReliableClient{
+	///Takes over `client`, connected or not, and sends again what's pending in `outbox`
+	pub fn new(client: ReconnectingClient, outbox: impl Outbox + 'static) -> ReliableClient{
+		let events = client.state_events();
+		let shared = Arc::new(Shared{ client, outbox: Arc::new(outbox), sending: async_std::sync::Mutex::new(()) });
+		let (incoming_sender, incoming) = async_std::channel::bounded(INCOMING_LIMIT);
+
+		async_std::task::spawn({
+			let shared = shared.clone();
+			async move {
+				let _ = shared.retransmit().await;
+
+				while let Ok(state) = events.recv().await {
+					match state{
+						ConnectionState::Connected => {
+							let _ = shared.retransmit().await;
+						}
+						ConnectionState::GaveUp{ .. } | ConnectionState::Closed => return,
+						_ => {}
+					}
+				}
+			}
+		});
+
+		async_std::task::spawn({
+			let shared = shared.clone();
+			async move {
+				while let Ok(mes) = shared.client.get_message().await {
+					match mes.get_code(){
+						CODE_RELIABLE_ACK if get_sender(&mes) == Some(shared.outbox.get_sender_id()) => if let Some(seq) = mes.get_correlation_id(){
+							let _ = shared.outbox.ack(seq);
+						}
+						_ => if incoming_sender.send(mes).await.is_err(){
+							return;
+						}
+					}
+				}
+			}
+		});
+
+		ReliableClient{ shared, incoming }
+	}
+
+	#[inline]
+	pub fn get_client(&self) -> &ReconnectingClient{
+		&self.shared.client
+	}
+
+	#[inline]
+	pub fn get_outbox(&self) -> &dyn Outbox{
+		&*self.shared.outbox
+	}
+
+	///Keeps the message in the outbox and sends it. Once it's in the outbox it gets to the receiver sooner or later,
+	///so the only errors are the outbox's ones
+	pub async fn send_message(&self, mes: Message) -> io::Result<()>{
+		let _sending = self.shared.sending.lock().await;
+		let seq = self.shared.outbox.push(&mes)?;
+
+		let _ = self.shared.client.send_message(wrap(self.shared.outbox.get_sender_id(), seq, &mes)).await;
+		Ok(())
+	}
+
+	pub async fn get_message(&self) -> io::Result<Message>{
+		self.incoming.recv().await.map_err(|_| Error::new(ErrorKind::NotConnected, "the client is closed"))
+	}
+
+	///Closes the connection, pending messages stay in the outbox
+	pub async fn close(&self){
+		self.shared.client.close().await;
+	}
+}
+
+impl Drop for ReliableClient{
+	#[inline]
+	fn drop(&mut self){
+		self.shared.client.stop();
+	}
+}
+
+///What a receiver does with a message
+enum Receipt{
+	Deliver(Message, Message),
+	Duplicate(Message),
+	Plain(Message)
+}
+
+///The receiving side: delivers every sender's messages once and in order and acknowledges them.
+///Clones share what was delivered, so one `Receipts` for every connection of a server lets senders reconnect anywhere.
+///
+///What was delivered is kept in memory for every sender ever seen, until `forget`
+#[derive(Clone, Default)]
+pub struct Receipts{
+	delivered: Arc<Mutex<HashMap<u64, u64>>>
+}
+
+impl Receipts{
+	#[inline]
+	pub fn new() -> Receipts{
+		Receipts::default()
+	}
+
+	///Last sequence number delivered from a sender
+	#[inline]
+	pub fn get_delivered(&self, sender_id: u64) -> Option<u64>{
+		lock(&self.delivered).get(&sender_id).copied()
+	}
+
+	#[inline]
+	pub fn forget(&self, sender_id: u64){
+		lock(&self.delivered).remove(&sender_id);
+	}
+
+	fn check(&self, mes: Message) -> io::Result<Receipt>{
+		let (Some(sender_id), CODE_RELIABLE) = (get_sender(&mes), mes.get_code()) else {
+			return Ok(Receipt::Plain(mes));
+		};
+
+		let mut delivered = lock(&self.delivered);
+		let last = delivered.get(&sender_id).copied().unwrap_or(0);
+		let seq = mes.get_id();
+
+		//a later message got here before a retransmitted earlier one, it comes again after it
+		if seq != last + 1{
+			return Ok(Receipt::Duplicate(ack_for(sender_id, last)));
+		}
+
+		let inner = Message::from_shared(mes.into_content())?;
+		delivered.insert(sender_id, seq);
+
+		Ok(Receipt::Deliver(inner, ack_for(sender_id, seq)))
+	}
+
+	///Receives the next message to deliver from the client. Reliable messages are acknowledged and unwrapped, others are returned as they are.
+	///A message is returned even if its acknowledgement couldn't be sent, it's already counted as delivered
+	pub async fn get_message(&self, client: &mut Client) -> io::Result<Message>{
+		loop {
+			match self.check(client.get_message().await?)?{
+				Receipt::Deliver(mes, ack) => {
+					let _ = client.send_message(ack).await;
+					return Ok(mes);
+				}
+				Receipt::Duplicate(ack) => client.send_message(ack).await?,
+				Receipt::Plain(mes) => return Ok(mes)
+			}
+		}
+	}
+
+	///The same for a message that came to a `Router` handler or middleware. Returns None if there's nothing to deliver
+	pub async fn receive(&self, ctx: &Context, mes: Message) -> io::Result<Option<Message>>{
+		match self.check(mes)?{
+			Receipt::Deliver(mes, ack) => {
+				let _ = ctx.send(&ack).await;
+				Ok(Some(mes))
+			}
+			Receipt::Duplicate(ack) => {
+				ctx.send(&ack).await?;
+				Ok(None)
+			}
+			Receipt::Plain(mes) => Ok(Some(mes))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn file_outbox_test(){
+		let path = std::env::temp_dir().join(format!("korneplod-outbox-{}", rand::random::<u64>()));
+
+		let outbox = FileOutbox::open(&path).unwrap();
+		let sender_id = outbox.get_sender_id();
+
+		for content in ["a", "b", "c"] {
+			outbox.push(&Message::new(content, 1)).unwrap();
+		}
+		outbox.ack(1).unwrap();
+		drop(outbox);
+
+		let outbox = FileOutbox::open(&path).unwrap();
+		assert_eq!(outbox.get_sender_id(), sender_id);
+		let pending = outbox.pending().unwrap();
+		assert_eq!(pending.iter().map(|(seq, mes)| (*seq, mes.get_content())).collect::<Vec<_>>(), [(2, &b"b"[..]), (3, &b"c"[..])]);
+
+		//everything acknowledged, the file is written anew but the numbers go on
+		outbox.ack(3).unwrap();
+		drop(outbox);
+
+		let outbox = FileOutbox::open(&path).unwrap();
+		assert!(outbox.pending().unwrap().is_empty());
+		assert_eq!(outbox.get_sender_id(), sender_id);
+		assert!(!path.with_extension("tmp").exists());
+		assert_eq!(outbox.push(&Message::new("d", 1)).unwrap(), 4);
+
+		//a torn entry at the end is ignored
+		drop(outbox);
+		let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+		file.write_all(&[ENTRY_PUSH, 0, 0]).unwrap();
+
+		let outbox = FileOutbox::open(&path).unwrap();
+		outbox.push(&Message::new("e", 1)).unwrap();
+		drop(outbox);
+
+		let outbox = FileOutbox::open(&path).unwrap();
+		assert_eq!(outbox.pending().unwrap().len(), 2);
+
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn failed_push_test(){
+		//every write fails and it can't be cut off either
+		let file = OpenOptions::new().write(true).open("/dev/full").unwrap();
+		let outbox = FileOutbox{ path: PathBuf::new(), sender_id: 1, state: Mutex::new(FileState{ file, pending: Pending::new(1), poisoned: false }) };
+
+		assert_eq!(outbox.push(&Message::new("a", 1)).unwrap_err().kind(), ErrorKind::StorageFull);
+		assert!(lock(&outbox.state).poisoned);
+		assert_eq!(outbox.push(&Message::new("b", 1)).unwrap_err().kind(), ErrorKind::Other);
+		assert!(outbox.pending().unwrap().is_empty());
+	}
+
+	#[test]
+	fn receipts_test(){
+		let receipts = Receipts::new();
+		let mes = Message::new("hi", 5);
+
+		let Receipt::Deliver(inner, ack) = receipts.check(wrap(78, 1, &mes)).unwrap() else { panic!() };
+		assert_eq!((inner, ack.get_correlation_id()), (mes.clone(), Some(1)));
+
+		//again, and one too early
+		assert!(matches!(receipts.check(wrap(78, 1, &mes)).unwrap(), Receipt::Duplicate(ack) if ack.get_correlation_id() == Some(1)));
+		assert!(matches!(receipts.check(wrap(78, 3, &mes)).unwrap(), Receipt::Duplicate(_)));
+		assert!(matches!(receipts.check(wrap(79, 1, &mes)).unwrap(), Receipt::Deliver(..)));
+		assert!(matches!(receipts.check(mes).unwrap(), Receipt::Plain(_)));
+
+		assert_eq!(receipts.get_delivered(78), Some(1));
+	}
+
+	#[test]
+	fn lost_ack_test(){
+		use crate::alert::Alert;
+
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let client = server.listen_handshaked(true, None).await.unwrap();
+			let ctx = Context::new(&client);
+			client.get_writer().close(&Alert::CloseNotify).await.unwrap();
+
+			//the ack can't go out, the message is delivered anyway and its retransmission only acknowledged
+			let receipts = Receipts::new();
+			let mes = Message::new("hi", 5);
+			assert_eq!(receipts.receive(&ctx, wrap(78, 1, &mes)).await.unwrap(), Some(mes.clone()));
+			assert_eq!(receipts.get_delivered(78), Some(1));
+			assert!(matches!(receipts.check(wrap(78, 1, &mes)).unwrap(), Receipt::Duplicate(ack) if ack.get_correlation_id() == Some(1)));
+		});
+
+		futures::executor::block_on(async {
+			let _client = testing::connect(addr).await;
+			server_side.join().unwrap();
+		});
+	}
+
+	#[test]
+	fn reliable_test(){
+		use crate::Message;
+		use crate::reconnect::{ReconnectingClient, Backoff};
+		use std::time::Duration;
+
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let receipts = Receipts::new();
+
+			let mut first = server.listen_handshaked(true, None).await.unwrap();
+			assert_eq!(receipts.get_message(&mut first).await.unwrap().get_content(), b"1");
+			//read, but the connection drops before it's acknowledged
+			assert_eq!(first.get_message().await.unwrap().get_code(), CODE_RELIABLE);
+			drop(first);
+
+			let mut second = server.listen_handshaked(true, None).await.unwrap();
+			assert_eq!(receipts.get_message(&mut second).await.unwrap().get_content(), b"2");
+			assert_eq!(receipts.get_message(&mut second).await.unwrap().get_content(), b"3");
+			assert!(receipts.get_message(&mut second).await.is_err());
+		});
+
+		futures::executor::block_on(async {
+			let mut client = ReconnectingClient::new(addr, None);
+			client.set_backoff(Backoff{ initial: Duration::from_millis(20), ..Backoff::default() });
+			client.connect().await.unwrap();
+
+			let client = ReliableClient::new(client, MemoryOutbox::new());
+			for content in ["1", "2", "3"] {
+				client.send_message(Message::new(content, 1)).await.unwrap();
+			}
+
+			let start = std::time::Instant::now();
+			while !client.get_outbox().pending().unwrap().is_empty() {
+				assert!(start.elapsed() < Duration::from_secs(5));
+				async_std::task::sleep(Duration::from_millis(10)).await;
+			}
+
+			client.close().await;
+			server_side.join().unwrap();
+		});
+	}
+}
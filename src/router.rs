@@ -0,0 +1,272 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+
+use crate::Message;
+use crate::client::{Client, Writer};
+use crate::lanes::Priority;
+use crate::registry::ConnectionId;
+
+type Handler = Arc<dyn Fn(Context, Message) -> BoxFuture<'static, io::Result<()>> + Send + Sync>;
+type Middleware = Arc<dyn Fn(Context, Message, Next) -> BoxFuture<'static, io::Result<()>> + Send + Sync>;
+
+///Codes a handler is registered for: a single code or a range of them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Codes(RangeInclusive<u16>);
+
+impl From<u16> for Codes{
+	#[inline]
+	fn from(code: u16) -> Codes{
+		Codes(code..=code)
+	}
+}
+
+impl From<RangeInclusive<u16>> for Codes{
+	#[inline]
+	fn from(codes: RangeInclusive<u16>) -> Codes{
+		Codes(codes)
+	}
+}
+
+impl From<Range<u16>> for Codes{
+	#[inline]
+	fn from(codes: Range<u16>) -> Codes{
+		match codes.end{
+			//matches nothing, like the range itself
+			0 => Codes(RangeInclusive::new(1, 0)),
+			end => Codes(codes.start..=end - 1)
+		}
+	}
+}
+
+///The connection a message came from, handed to handlers and middleware
+#[derive(Clone)]
+pub struct Context{
+	id: Option<ConnectionId>,
+	peer_addr: Option<SocketAddr>,
+	writer: Arc<Writer>
+}
+
+impl Context{
+	pub(crate) fn new(client: &Client) -> Context{
+		Context{
+			id: client.get_connection_id(),
+			peer_addr: client.peer_addr().ok(),
+			writer: client.get_writer()
+		}
+	}
+
+	///Id the server gave the connection, see `Client::get_connection_id`
+	#[inline]
+	pub fn get_connection_id(&self) -> Option<ConnectionId>{
+		self.id
+	}
+
+	#[inline]
+	pub fn get_peer_addr(&self) -> Option<SocketAddr>{
+		self.peer_addr
+	}
+
+	///Sends a message on the connection
+	pub async fn send(&self, mes: &Message) -> io::Result<()>{
+		self.writer.send_message(mes).await
+	}
+
+	///Sends a message ahead of everything of a lower priority going out on the connection, see `Client::send_message_with_priority`
+	pub async fn send_with_priority(&self, mes: &Message, priority: Priority) -> io::Result<()>{
+		self.writer.send_message_with_priority(mes, priority).await
+	}
+
+	///Sends `answer` with its correlation id set to the id of `request`
+	pub async fn reply(&self, request: &Message, answer: Message) -> io::Result<()>{
+		self.send(&answer.with_correlation_id(request.get_id())).await
+	}
+}
+
+///The rest of the middleware chain and the handler behind it
+pub struct Next{
+	routes: Arc<Routes>,
+	index: usize
+}
+
+impl Next{
+	///Passes the message on
+	pub fn run(self, ctx: Context, mes: Message) -> BoxFuture<'static, io::Result<()>>{
+		match self.routes.middleware.get(self.index).cloned(){
+			Some(middleware) => middleware(ctx, mes, Next{ routes: self.routes, index: self.index + 1 }),
+			None => self.routes.dispatch(ctx, mes)
+		}
+	}
+}
+
+#[derive(Clone, Default)]
+struct Routes{
+	codes: HashMap<u16, Handler>,
+	ranges: Vec<(RangeInclusive<u16>, Handler)>,
+	fallback: Option<Handler>,
+	middleware: Vec<Middleware>
+}
+
+impl Routes{
+	fn dispatch(&self, ctx: Context, mes: Message) -> BoxFuture<'static, io::Result<()>>{
+		let code = mes.get_code();
+
+		let handler = self.codes.get(&code)
+			.or_else(|| self.ranges.iter().find(|(codes, _)| codes.contains(&code)).map(|(_, handler)| handler))
+			.or(self.fallback.as_ref());
+
+		match handler{
+			Some(handler) => handler(ctx, mes),
+			None => Box::pin(async { Ok(()) })
+		}
+	}
+}
+
+///Calls async handlers by message code.
+///
+///A single code wins over ranges that have it, ranges are tried in the order they were added. Messages nobody takes go to the fallback,
+///without one they're dropped. Middleware runs before the handler in the order it was added and decides whether to call `Next::run`.
+///A handler that fails ends the connection
+#[derive(Clone, Default)]
+pub struct Router{
+	routes: Arc<Routes>
+}
+
+impl Router{
+	#[inline]
+	pub fn new() -> Router{
+		Router::default()
+	}
+
+	///Adds a handler for a code or a range of codes. A later handler for the same single code replaces the earlier one
+	pub fn route<F, Fut>(mut self, codes: impl Into<Codes>, handler: F) -> Router
+	where F: Fn(Context, Message) -> Fut + Send + Sync + 'static, Fut: Future<Output = io::Result<()>> + Send + 'static{
+		let handler: Handler = Arc::new(move |ctx, mes| Box::pin(handler(ctx, mes)));
+		let codes = codes.into().0;
+		let routes = Arc::make_mut(&mut self.routes);
+
+		match codes.start() == codes.end(){
+			true => {
+				routes.codes.insert(*codes.start(), handler);
+			}
+			false => routes.ranges.push((codes, handler))
+		}
+
+		self
+	}
+
+	///Like `route`, the handler also gets `state`, shared by every call on every connection
+	pub fn route_with_state<S, F, Fut>(self, codes: impl Into<Codes>, state: S, handler: F) -> Router
+	where S: Send + Sync + 'static, F: Fn(Context, Message, Arc<S>) -> Fut + Send + Sync + 'static, Fut: Future<Output = io::Result<()>> + Send + 'static{
+		let state = Arc::new(state);
+		self.route(codes, move |ctx, mes| handler(ctx, mes, state.clone()))
+	}
+
+	///Sets the handler for messages no other handler takes
+	pub fn fallback<F, Fut>(mut self, handler: F) -> Router
+	where F: Fn(Context, Message) -> Fut + Send + Sync + 'static, Fut: Future<Output = io::Result<()>> + Send + 'static{
+		Arc::make_mut(&mut self.routes).fallback = Some(Arc::new(move |ctx, mes| Box::pin(handler(ctx, mes))));
+		self
+	}
+
+	///Adds middleware. It sees every message before the handler and may change it, answer it itself or drop it
+	pub fn middleware<F, Fut>(mut self, middleware: F) -> Router
+	where F: Fn(Context, Message, Next) -> Fut + Send + Sync + 'static, Fut: Future<Output = io::Result<()>> + Send + 'static{
+		Arc::make_mut(&mut self.routes).middleware.push(Arc::new(move |ctx, mes, next| Box::pin(middleware(ctx, mes, next))));
+		self
+	}
+
+	///Passes a message through the middleware to its handler
+	async fn handle(&self, ctx: Context, mes: Message) -> io::Result<()>{
+		Next{ routes: self.routes.clone(), index: 0 }.run(ctx, mes).await
+	}
+
+	///Reads messages from the connection and handles them one after another until the connection or a handler fails.
+	///Returns that error, the connection is dropped
+	pub async fn run(&self, mut client: Client) -> io::Result<()>{
+		let ctx = Context::new(&client);
+
+		loop {
+			let mes = client.get_message().await?;
+			self.handle(ctx.clone(), mes).await?;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn router_test(){
+		use crate::Message;
+		use std::sync::atomic::{AtomicU64, Ordering};
+
+		let router = Router::new()
+			.route(1, |ctx: Context, mes: Message| async move {
+				ctx.reply(&mes, Message::new("one", 1)).await
+			})
+			.route_with_state(10..=19, AtomicU64::new(0), |ctx: Context, mes: Message, seen: std::sync::Arc<AtomicU64>| async move {
+				let seen = seen.fetch_add(1, Ordering::SeqCst) + 1;
+				ctx.send(&Message::new(seen.to_be_bytes().to_vec(), mes.get_code())).await
+			})
+			//a single code wins over the range
+			.route(15, |ctx: Context, _| async move {
+				ctx.send(&Message::new("fifteen", 15)).await
+			})
+			.fallback(|ctx: Context, mes: Message| async move {
+				ctx.send(&Message::new("unknown", mes.get_code())).await
+			})
+			.middleware(|ctx: Context, mes: Message, next: Next| async move {
+				match mes.get_code(){
+					666 => ctx.send(&Message::new("denied", 666)).await,
+					_ => next.run(ctx, mes).await
+				}
+			});
+
+		let (server, addr) = testing::bind();
+		let _server_side = testing::spawn(async move { server.serve(None, router).await });
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+
+			let mut ask = async |mes: Message| {
+				client.send_message(mes).await.unwrap();
+				client.get_message().await.unwrap()
+			};
+
+			let answer = ask(Message::new("", 1).with_id(78)).await;
+			assert_eq!((answer.get_content(), answer.get_correlation_id()), (&b"one"[..], Some(78)));
+
+			assert_eq!(ask(Message::new("", 12)).await.get_content(), 1u64.to_be_bytes());
+			assert_eq!(ask(Message::new("", 19)).await.get_content(), 2u64.to_be_bytes());
+			assert_eq!(ask(Message::new("", 15)).await.get_content(), b"fifteen");
+			assert_eq!(ask(Message::new("", 20)).await.get_content(), b"unknown");
+			assert_eq!(ask(Message::new("", 666)).await.get_content(), b"denied");
+		});
+	}
+}
@@ -0,0 +1,736 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Request/response calls over a connection.
+//!
+//! A call is a `CODE_REQUEST` message with the method in the `rpc-method` header and a fresh id. Every answer to it
+//! carries that id as its correlation id, so any number of calls can be waiting on one connection and the answers may come in any order.
+//! An answer is either a single `CODE_RESPONSE`, a `CODE_ERROR`, or any number of `CODE_STREAM_ITEM`s closed by `CODE_STREAM_END` or `CODE_ERROR`.
+//! A caller that gives up sends `CODE_CANCEL` and the server stops the handler.
+//! Up to 64 answers of a call wait for the caller to take them. A call with more is cancelled and ends with `RpcError::Overflowed`,
+//! so a caller that doesn't read its stream doesn't hold up the other calls on the connection.
+//!
+//! Codes from `0xFF00` up to `CODE_CANCEL` are taken by calls, don't use them for other messages on a connection that does calls
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::Duration;
+
+use async_net::TcpStream;
+use bytes::Bytes;
+use futures::StreamExt;
+use async_std::channel::{Receiver, Sender, TrySendError};
+use futures::future::{AbortHandle, BoxFuture};
+
+use crate::Message;
+use crate::client::{Client, Writer};
+use crate::router::Context;
+
+pub const CODE_REQUEST: u16 = 0xFF00;
+pub const CODE_RESPONSE: u16 = 0xFF01;
+pub const CODE_STREAM_ITEM: u16 = 0xFF02;
+pub const CODE_STREAM_END: u16 = 0xFF03;
+pub const CODE_ERROR: u16 = 0xFF04;
+pub const CODE_CANCEL: u16 = 0xFF05;
+
+///Header with the name of the called method
+pub const METHOD_HEADER: &str = "rpc-method";
+///Header of an error answer with its status, a big endian u16
+pub const STATUS_HEADER: &str = "rpc-status";
+
+///The server has no such method
+pub const STATUS_UNKNOWN_METHOD: u16 = 1;
+///The handler failed with something other than `RpcError::Remote`
+pub const STATUS_FAILED: u16 = 2;
+///The server is running as many calls from the connection as it takes
+pub const STATUS_BUSY: u16 = 3;
+
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+///Calls from one connection an `RpcServer` runs at the same time by default
+pub const DEFAULT_MAX_CALLS: usize = 256;
+
+//Answers of a call that came but weren't taken yet. A call that gets more is cancelled
+const ANSWERS_LIMIT: usize = 64;
+
+#[derive(Debug)]
+pub enum RpcError{
+	///The call couldn't be sent or the connection broke before the answer
+	Io(io::Error),
+	///No answer came in time, the call was cancelled
+	TimedOut,
+	///The other side answered with an error
+	Remote{ status: u16, message: String },
+	///The caller didn't take the answers of a stream as fast as they came, the call was cancelled after the ones it kept
+	Overflowed,
+}
+
+impl RpcError{
+	///An error for a handler to answer with
+	#[inline]
+	pub fn remote(status: u16, message: impl Into<String>) -> RpcError{
+		RpcError::Remote{ status, message: message.into() }
+	}
+
+	fn into_answer(self, id: u64) -> Message{
+		let (status, message) = match self{
+			RpcError::Remote{ status, message } => (status, message),
+			e => (STATUS_FAILED, e.to_string())
+		};
+
+		Message::new(message.into_bytes(), CODE_ERROR)
+			.with_correlation_id(id)
+			.with_header(STATUS_HEADER, status.to_be_bytes().to_vec())
+	}
+
+	fn from_answer(mes: &Message) -> RpcError{
+		let status = match mes.get_header(STATUS_HEADER){
+			Some(&[high, low]) => u16::from_be_bytes([high, low]),
+			_ => STATUS_FAILED
+		};
+
+		RpcError::Remote{ status, message: String::from_utf8_lossy(mes.get_content()).into_owned() }
+	}
+}
+
+impl std::fmt::Display for RpcError{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+		match self{
+			RpcError::Io(e) => write!(f, "{}", e),
+			RpcError::TimedOut => write!(f, "call timed out"),
+			RpcError::Remote{ status, message } => write!(f, "call failed with status {}: {}", status, message),
+			RpcError::Overflowed => write!(f, "call cancelled, too many answers weren't taken"),
+		}
+	}
+}
+
+impl std::error::Error for RpcError{
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+		match self{
+			RpcError::Io(e) => Some(e),
+			_ => None
+		}
+	}
+}
+
+impl From<io::Error> for RpcError{
+	#[inline]
+	fn from(e: io::Error) -> RpcError{
+		RpcError::Io(e)
+	}
+}
+
+impl From<RpcError> for io::Error{
+	#[inline]
+	fn from(e: RpcError) -> io::Error{
+		match e{
+			RpcError::Io(e) => e,
+			RpcError::TimedOut => io::Error::new(ErrorKind::TimedOut, e),
+			e => io::Error::other(e)
+		}
+	}
+}
+
+type Answers = Sender<Result<Bytes, RpcError>>;
+
+///Calls waiting for answers, by id
+#[derive(Default)]
+struct Pending{
+	calls: HashMap<u64, Answers>,
+	//why the connection is gone, once it is
+	closed: Option<(ErrorKind, String)>
+}
+
+struct Shared{
+	writer: Arc<Writer>,
+	stream: TcpStream,
+	pending: Arc<Mutex<Pending>>,
+	next_id: AtomicU64
+}
+
+impl Drop for Shared{
+	#[inline]
+	fn drop(&mut self){
+		let _ = self.stream.shutdown(std::net::Shutdown::Both);
+	}
+}
+
+#[inline]
+fn lock(pending: &Mutex<Pending>) -> MutexGuard<'_, Pending>{
+	pending.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+///Makes calls over a connection. Clones make calls over the same connection, it's closed once every clone and `ResponseStream` is dropped.
+///
+///Messages that aren't answers to a call are dropped
+#[derive(Clone)]
+pub struct RpcClient{
+	shared: Arc<Shared>,
+	timeout: Duration,
+	secret_mixed: bool
+}
+
+impl RpcClient{
+	///Takes over a handshaked client, its messages are read by a task from now on
+	pub fn new(mut client: Client) -> RpcClient{
+		let pending = Arc::new(Mutex::new(Pending::default()));
+
+		let writer = client.get_writer();
+		let shared = Arc::new(Shared{
+			writer: writer.clone(),
+			stream: client.get_stream().clone(),
+			pending: pending.clone(),
+			next_id: AtomicU64::new(1)
+		});
+
+		async_std::task::spawn(async move {
+			let e = loop {
+				match client.get_message().await{
+					Ok(mes) => answer(&pending, &writer, mes),
+					Err(e) => break e
+				}
+			};
+
+			let calls = {
+				let mut pending = lock(&pending);
+				pending.closed = Some((e.kind(), e.to_string()));
+				std::mem::take(&mut pending.calls)
+			};
+
+			//a stream with answers nobody has taken yet gets the error after them
+			for (_, answers) in calls {
+				let e = io::Error::new(e.kind(), e.to_string());
+				async_std::task::spawn(async move {
+					let _ = answers.send(Err(e.into())).await;
+				});
+			}
+		});
+
+		RpcClient{ shared, timeout: DEFAULT_CALL_TIMEOUT, secret_mixed: false }
+	}
+
+	///Timeout of `call`, `DEFAULT_CALL_TIMEOUT` by default
+	#[inline]
+	pub fn get_timeout(&self) -> Duration{
+		self.timeout
+	}
+
+	#[inline]
+	pub fn set_timeout(&mut self, timeout: Duration){
+		self.timeout = timeout;
+	}
+
+	#[inline]
+	pub fn is_secret_mixed(&self) -> bool{
+		self.secret_mixed
+	}
+
+	///Sends the requests of this client as `Message::secret_mixed`, so they're never compressed. Off by default
+	#[inline]
+	pub fn set_secret_mixed(&mut self, secret_mixed: bool){
+		self.secret_mixed = secret_mixed;
+	}
+
+	///Calls a method and waits for its answer up to the timeout. Dropping the future cancels the call
+	#[inline]
+	pub async fn call(&self, method: &str, payload: impl Into<Bytes>) -> Result<Bytes, RpcError>{
+		self.call_with_timeout(method, payload, self.timeout).await
+	}
+
+	pub async fn call_with_timeout(&self, method: &str, payload: impl Into<Bytes>, timeout: Duration) -> Result<Bytes, RpcError>{
+		let mut call = self.start(method, payload.into()).await?;
+
+		match async_std::future::timeout(timeout, call.answers.next()).await{
+			Ok(Some(answer)) => answer,
+			Ok(None) => Err(call.closed().into()),
+			//the call is dropped, which cancels it
+			Err(_) => Err(RpcError::TimedOut)
+		}
+	}
+
+	///Calls a method that answers with a stream of items. There's no timeout, dropping the stream cancels the call
+	#[inline]
+	pub async fn call_stream(&self, method: &str, payload: impl Into<Bytes>) -> Result<ResponseStream, RpcError>{
+		Ok(ResponseStream{ call: self.start(method, payload.into()).await?, done: false })
+	}
+
+	async fn start(&self, method: &str, payload: Bytes) -> Result<Call, RpcError>{
+		let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+		let (sender, answers) = async_std::channel::bounded(ANSWERS_LIMIT);
+
+		{
+			let mut pending = lock(&self.shared.pending);
+
+			if let Some((kind, reason)) = &pending.closed{
+				return Err(io::Error::new(*kind, reason.clone()).into());
+			}
+
+			pending.calls.insert(id, sender);
+		}
+
+		//registered before it's sent, so the answer can't come first
+		let call = Call{ id, shared: self.shared.clone(), answers };
+		let request = Message::new(payload, CODE_REQUEST).with_id(id).with_header(METHOD_HEADER, method.to_owned());
+
+		self.shared.writer.send_message(&mark_secret(request, self.secret_mixed)).await?;
+
+		Ok(call)
+	}
+}
+
+#[inline]
+fn mark_secret(mes: Message, secret_mixed: bool) -> Message{
+	match secret_mixed{
+		true => mes.secret_mixed(),
+		false => mes
+	}
+}
+
+//hands an answer to the call it's for. A call that has too many of them waiting is cancelled and gets `RpcError::Overflowed` after them,
+//the connection is never held up by one call
+fn answer(pending: &Mutex<Pending>, writer: &Arc<Writer>, mes: Message){
+	let Some(id) = mes.get_correlation_id() else { return };
+
+	let answer = {
+		let mut pending = lock(pending);
+
+		match mes.get_code(){
+			CODE_RESPONSE => pending.calls.remove(&id).map(|answers| (answers, Ok(mes.into_content()))),
+			CODE_STREAM_ITEM => pending.calls.get(&id).cloned().map(|answers| (answers, Ok(mes.into_content()))),
+			CODE_ERROR => pending.calls.remove(&id).map(|answers| (answers, Err(RpcError::from_answer(&mes)))),
+			//dropping the sender ends the stream
+			CODE_STREAM_END => {
+				pending.calls.remove(&id);
+				None
+			}
+			_ => None
+		}
+	};
+
+	//fails right away if the call was dropped
+	if let Some((answers, answer)) = answer && let Err(TrySendError::Full(_)) = answers.try_send(answer) {
+		lock(pending).calls.remove(&id);
+		cancel(writer, id);
+
+		async_std::task::spawn(async move {
+			let _ = answers.send(Err(RpcError::Overflowed)).await;
+		});
+	}
+}
+
+//tells the server to stop a call
+fn cancel(writer: &Arc<Writer>, id: u64){
+	let writer = writer.clone();
+	let cancel = Message::new(Vec::new(), CODE_CANCEL).with_correlation_id(id);
+
+	async_std::task::spawn(async move {
+		let _ = writer.send_message(&cancel).await;
+	});
+}
+
+///A call that's been sent. Cancelled on drop if it's still waiting
+struct Call{
+	id: u64,
+	shared: Arc<Shared>,
+	answers: Receiver<Result<Bytes, RpcError>>
+}
+
+impl Call{
+	fn closed(&self) -> io::Error{
+		match &lock(&self.shared.pending).closed{
+			Some((kind, reason)) => io::Error::new(*kind, reason.clone()),
+			None => io::Error::from(ErrorKind::ConnectionAborted)
+		}
+	}
+}
+
+impl Drop for Call{
+	fn drop(&mut self){
+		if lock(&self.shared.pending).calls.remove(&self.id).is_some(){
+			cancel(&self.shared.writer, self.id);
+		}
+	}
+}
+
+///Items of a streaming call. Ends after the last item, a failed call or a broken connection gives one `Err` and ends
+pub struct ResponseStream{
+	call: Call,
+	done: bool
+}
+
+impl ResponseStream{
+	///Cancels the call, same as dropping the stream
+	#[inline]
+	pub fn cancel(self){}
+}
+
+impl futures::Stream for ResponseStream{
+	type Item = Result<Bytes, RpcError>;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>>{
+		if self.done{
+			return Poll::Ready(None);
+		}
+
+		let item = futures::ready!(self.call.answers.poll_next_unpin(cx));
+		self.done = !matches!(item, Some(Ok(_)));
+
+		Poll::Ready(item)
+	}
+}
+
+type Unary = Arc<dyn Fn(Context, Message) -> BoxFuture<'static, Result<Bytes, RpcError>> + Send + Sync>;
+type Streaming = Arc<dyn Fn(Context, Message, ResponseSink) -> BoxFuture<'static, Result<(), RpcError>> + Send + Sync>;
+
+#[derive(Clone)]
+enum Method{
+	Unary(Unary),
+	Streaming(Streaming)
+}
+
+///Sends the items of a streaming answer
+#[derive(Clone)]
+pub struct ResponseSink{
+	ctx: Context,
+	id: u64,
+	secret_mixed: bool
+}
+
+impl ResponseSink{
+	pub async fn send(&self, item: impl Into<Bytes>) -> io::Result<()>{
+		let item = Message::new(item, CODE_STREAM_ITEM).with_correlation_id(self.id);
+		self.ctx.send(&mark_secret(item, self.secret_mixed)).await
+	}
+}
+
+///Answers calls by method name. Handlers get the request message, its content is the payload.
+///
+///Every call runs in its own task, so a slow one doesn't hold up the rest, and is stopped when the caller cancels it or the connection ends.
+///Calls past `get_max_calls` are answered with `STATUS_BUSY`, a call with the id of one that's still running is dropped
+#[derive(Clone)]
+pub struct RpcServer{
+	methods: Arc<HashMap<String, Method>>,
+	max_calls: usize,
+	secret_mixed: bool
+}
+
+impl Default for RpcServer{
+	#[inline]
+	fn default() -> RpcServer{
+		RpcServer{ methods: Arc::default(), max_calls: DEFAULT_MAX_CALLS, secret_mixed: false }
+	}
+}
+
+impl RpcServer{
+	#[inline]
+	pub fn new() -> RpcServer{
+		RpcServer::default()
+	}
+
+	///Calls from one connection running at the same time, `DEFAULT_MAX_CALLS` by default
+	#[inline]
+	pub fn get_max_calls(&self) -> usize{
+		self.max_calls
+	}
+
+	#[inline]
+	pub fn with_max_calls(mut self, max_calls: usize) -> RpcServer{
+		self.max_calls = max_calls;
+		self
+	}
+
+	#[inline]
+	pub fn is_secret_mixed(&self) -> bool{
+		self.secret_mixed
+	}
+
+	///Sends every answer and stream item as `Message::secret_mixed`, so they're never compressed. Off by default
+	#[inline]
+	pub fn with_secret_mixed(mut self, secret_mixed: bool) -> RpcServer{
+		self.secret_mixed = secret_mixed;
+		self
+	}
+
+	///Adds a method with a single answer. A later method with the same name replaces the earlier one
+	pub fn method<F, Fut>(mut self, name: impl Into<String>, handler: F) -> RpcServer
+	where F: Fn(Context, Message) -> Fut + Send + Sync + 'static, Fut: Future<Output = Result<Bytes, RpcError>> + Send + 'static{
+		let handler: Unary = Arc::new(move |ctx, mes| Box::pin(handler(ctx, mes)));
+		Arc::make_mut(&mut self.methods).insert(name.into(), Method::Unary(handler));
+		self
+	}
+
+	///Adds a method that answers with items sent to the sink. The stream ends when the handler returns
+	pub fn streaming_method<F, Fut>(mut self, name: impl Into<String>, handler: F) -> RpcServer
+	where F: Fn(Context, Message, ResponseSink) -> Fut + Send + Sync + 'static, Fut: Future<Output = Result<(), RpcError>> + Send + 'static{
+		let handler: Streaming = Arc::new(move |ctx, mes, sink| Box::pin(handler(ctx, mes, sink)));
+		Arc::make_mut(&mut self.methods).insert(name.into(), Method::Streaming(handler));
+		self
+	}
+
+	///Answers calls from the connection until it fails, returns that error. Other messages are dropped
+	pub async fn run(&self, mut client: Client) -> io::Result<()>{
+		let ctx = Context::new(&client);
+		let running = Arc::new(Mutex::new(HashMap::<u64, AbortHandle>::new()));
+
+		let e = loop {
+			let mes = match client.get_message().await{
+				Ok(mes) => mes,
+				Err(e) => break e
+			};
+
+			match mes.get_code(){
+				CODE_REQUEST => self.start(ctx.clone(), mes, &running),
+				CODE_CANCEL => if let Some(id) = mes.get_correlation_id() && let Some(call) = running.lock().unwrap_or_else(|e| e.into_inner()).remove(&id) {
+					call.abort();
+				}
+				_ => {}
+			}
+		};
+
+		for (_, call) in running.lock().unwrap_or_else(|e| e.into_inner()).drain() {
+			call.abort();
+		}
+
+		Err(e)
+	}
+
+	fn start(&self, ctx: Context, mes: Message, running: &Arc<Mutex<HashMap<u64, AbortHandle>>>){
+		let id = mes.get_id();
+
+		{
+			let running = running.lock().unwrap_or_else(|e| e.into_inner());
+
+			//answering it would end the running call on the caller's side
+			if running.contains_key(&id) {
+				return;
+			}
+
+			if running.len() >= self.max_calls {
+				let busy = RpcError::remote(STATUS_BUSY, "too many calls").into_answer(id);
+				async_std::task::spawn(async move {
+					let _ = ctx.send(&busy).await;
+				});
+				return;
+			}
+		}
+
+		let secret_mixed = self.secret_mixed;
+		let method = mes.get_header(METHOD_HEADER).and_then(|name| std::str::from_utf8(name).ok()).and_then(|name| self.methods.get(name)).cloned();
+
+		let call = async move {
+			let answer = match method{
+				Some(Method::Unary(handler)) => match handler(ctx.clone(), mes).await{
+					Ok(answer) => Message::new(answer, CODE_RESPONSE).with_correlation_id(id),
+					Err(e) => e.into_answer(id)
+				}
+				Some(Method::Streaming(handler)) => match handler(ctx.clone(), mes, ResponseSink{ ctx: ctx.clone(), id, secret_mixed }).await{
+					Ok(()) => Message::new(Vec::new(), CODE_STREAM_END).with_correlation_id(id),
+					Err(e) => e.into_answer(id)
+				}
+				None => RpcError::remote(STATUS_UNKNOWN_METHOD, "no such method").into_answer(id)
+			};
+
+			let _ = ctx.send(&mark_secret(answer, secret_mixed)).await;
+		};
+
+		let (call, handle) = futures::future::abortable(call);
+		running.lock().unwrap_or_else(|e| e.into_inner()).insert(id, handle);
+
+		let running = running.clone();
+		async_std::task::spawn(async move {
+			//a cancelled call is already gone from the map
+			if call.await.is_ok(){
+				running.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+	#[test]
+	fn error_answer_test(){
+		let answer = RpcError::remote(404, "nothing here").into_answer(7);
+		assert_eq!(answer.get_code(), CODE_ERROR);
+		assert_eq!(answer.get_correlation_id(), Some(7));
+
+		match RpcError::from_answer(&answer){
+			RpcError::Remote{ status, message } => {
+				assert_eq!(status, 404);
+				assert_eq!(message, "nothing here");
+			}
+			e => panic!("unexpected {:?}", e)
+		}
+
+		let answer = RpcError::TimedOut.into_answer(1);
+		assert!(matches!(RpcError::from_answer(&answer), RpcError::Remote{ status: STATUS_FAILED, .. }));
+
+		let e: io::Error = RpcError::TimedOut.into();
+		assert_eq!(e.kind(), ErrorKind::TimedOut);
+	}
+
+	#[test]
+	fn limits_test(){
+		use std::time::Duration;
+
+		let rpc = RpcServer::new()
+			.with_max_calls(1)
+			.method("slow", |_, _| async move {
+				async_std::task::sleep(Duration::from_millis(300)).await;
+				Ok("slow".into())
+			});
+
+		let (mut server, addr) = testing::bind();
+		let _server_side = testing::spawn(async move {
+			let client = server.listen_handshaked(true, None).await.unwrap();
+			let _ = rpc.run(client).await;
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			let request = || Message::new("", CODE_REQUEST).with_id(7).with_header(METHOD_HEADER, "slow");
+
+			//the copy of a running call is dropped, the next one is over the limit
+			client.send_message(request()).await.unwrap();
+			client.send_message(request()).await.unwrap();
+			client.send_message(request().with_id(8)).await.unwrap();
+
+			let busy = client.get_message().await.unwrap();
+			assert_eq!((busy.get_code(), busy.get_correlation_id()), (CODE_ERROR, Some(8)));
+			assert!(matches!(RpcError::from_answer(&busy), RpcError::Remote{ status: STATUS_BUSY, .. }));
+
+			let answer = client.get_message().await.unwrap();
+			assert_eq!((answer.get_code(), answer.get_correlation_id()), (CODE_RESPONSE, Some(7)));
+			assert!(async_std::future::timeout(Duration::from_millis(500), client.get_message()).await.is_err());
+		});
+	}
+
+	#[test]
+	fn rpc_test(){
+		use crate::Message;
+		use crate::router::Context;
+		use futures::StreamExt;
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		use std::time::Duration;
+
+		static FINISHED_SLOW: AtomicUsize = AtomicUsize::new(0);
+
+		let rpc = RpcServer::new()
+			.with_secret_mixed(true)
+			.method("echo", |_, mes: Message| async move { Ok(mes.into_content()) })
+			.method("slow", |_, _| async move {
+				async_std::task::sleep(Duration::from_millis(300)).await;
+				FINISHED_SLOW.fetch_add(1, Ordering::SeqCst);
+				Ok("slow".into())
+			})
+			.method("fail", |_, _| async move { Err(RpcError::remote(7, "bad")) })
+			.streaming_method("count", |_: Context, mes: Message, sink: ResponseSink| async move {
+				for i in 0..mes.get_content()[0] {
+					sink.send(vec![i]).await?;
+				}
+				Ok(())
+			});
+
+		let (server, addr) = testing::bind();
+		let incoming = server.incoming(None);
+
+		let _server_side = testing::spawn(incoming.for_each(move |client| {
+			let rpc = rpc.clone();
+			async_std::task::spawn(async move { let _ = rpc.run(client).await; });
+			async {}
+		}));
+
+		futures::executor::block_on(async {
+			let client = testing::connect(addr).await;
+			let rpc = RpcClient::new(client);
+
+			//the fast call is answered while the slow one is still running
+			let (slow, echo) = futures::join!(rpc.call("slow", ""), async {
+				let echo = rpc.call("echo", "hi").await;
+				(echo, FINISHED_SLOW.load(Ordering::SeqCst))
+			});
+			assert_eq!(&slow.unwrap()[..], b"slow");
+			assert_eq!((&echo.0.unwrap()[..], echo.1), (&b"hi"[..], 0));
+
+			//a timed out call is cancelled on the server too
+			let err = rpc.call_with_timeout("slow", "", Duration::from_millis(50)).await.err().unwrap();
+			assert!(matches!(err, RpcError::TimedOut));
+			async_std::task::sleep(Duration::from_millis(500)).await;
+			assert_eq!(FINISHED_SLOW.load(Ordering::SeqCst), 1);
+
+			assert!(matches!(rpc.call("fail", "").await, Err(RpcError::Remote{ status: 7, .. })));
+			assert!(matches!(rpc.call("nothing", "").await, Err(RpcError::Remote{ status: STATUS_UNKNOWN_METHOD, .. })));
+
+			let items = rpc.call_stream("count", vec![3]).await.unwrap().map(|item| item.unwrap().to_vec()).collect::<Vec<_>>().await;
+			assert_eq!(items, vec![vec![0], vec![1], vec![2]]);
+
+			//calls that skip compression work the same
+			let mut secret = rpc.clone();
+			secret.set_secret_mixed(true);
+			assert!(secret.is_secret_mixed() && !rpc.is_secret_mixed());
+			assert_eq!(&secret.call("echo", "secret").await.unwrap()[..], b"secret");
+		});
+	}
+
+	#[test]
+	fn unread_stream_test(){
+		use futures::StreamExt;
+		use std::time::Duration;
+
+		let rpc = RpcServer::new()
+			.method("echo", |_, mes: Message| async move { Ok(mes.into_content()) })
+			.streaming_method("count", |_, mes: Message, sink: ResponseSink| async move {
+				for i in 0..mes.get_content()[0] {
+					sink.send(vec![i]).await?;
+				}
+				Ok(())
+			});
+
+		let (mut server, addr) = testing::bind();
+		let _server_side = testing::spawn(async move {
+			let client = server.listen_handshaked(true, None).await.unwrap();
+			let _ = rpc.run(client).await;
+		});
+
+		futures::executor::block_on(async {
+			let rpc = RpcClient::new(testing::connect(addr).await);
+
+			//more items than a call keeps and nobody takes them
+			let mut items = rpc.call_stream("count", vec![200]).await.unwrap();
+			async_std::task::sleep(Duration::from_millis(300)).await;
+
+			let echo = rpc.call_with_timeout("echo", "hi", Duration::from_secs(5)).await.unwrap();
+			assert_eq!(&echo[..], b"hi");
+
+			//the stream ends with an error after the items it kept
+			for i in 0..ANSWERS_LIMIT {
+				assert_eq!(&items.next().await.unwrap().unwrap()[..], &[i as u8]);
+			}
+			assert!(matches!(items.next().await, Some(Err(RpcError::Overflowed))));
+			assert!(items.next().await.is_none());
+		});
+	}
+}
@@ -17,146 +17,818 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
 use crate::client;
-use async_net::TcpListener;
+use crate::kem;
+use crate::deadline::{Deadline, HandshakeDeadlines};
+use crate::keepalive::Keepalive;
+use crate::padding::Padding;
+use crate::cover::CoverTraffic;
+use crate::admission::{Admission, Identity, Verdict};
+use crate::firewall::Firewall;
+use crate::throttle::{BandwidthLimits, Throttle};
+use crate::compression::Compression;
+use crate::cookie::{CookieJar, RetryCookies, COOKIE_LEN};
+use crate::puzzle::{self, PuzzlePolicy, SolvedPuzzles, CHALLENGE_LEN};
+use crate::alert::Alert;
+use crate::slots::{Slot, Slots};
+use crate::registry::{Connections, Registry};
+use crate::shutdown::{ShutdownHandle, Signal};
+use crate::router::Router;
+use crate::pubsub::Broker;
+use async_net::{TcpListener, TcpStream};
 use std::io;
-use chacha20::cipher::{KeyIvInit, StreamCipher};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use chacha20::cipher::StreamCipher;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
 
-use futures_lite::prelude::*;
+use futures::stream::{Stream, StreamExt};
 
-///This macro is for returning None if a given Result instance has Err variant
-macro_rules! res_to_none{
-	($expres:expr) => {
-		if $expres.is_err(){
-			return None;
-		}
-	};
-}
+///Default number of handshakes `Server::incoming` runs at the same time
+pub const DEFAULT_HANDSHAKE_CONCURRENCY: usize = 64;
 
-///if statement is true, then if break_on_fail is true it returns None otherwise continues loop
-macro_rules! continue_or_break {
-	($statement:expr, $break_on_fail:expr) => {
-		if $statement{
-			if $break_on_fail {
-				return None;
-			}
-			continue;
-		}
-	}
+//How long a peer refused with `Alert::ServerBusy` is drained before the socket is closed
+const BUSY_LINGER: Duration = Duration::from_secs(1);
+
+///What the server does with new connections once `get_max_connections` of them are live
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhenFull{
+	///Doesn't accept until a connection is dropped, new peers wait in the OS backlog
+	#[default]
+	StopAccepting,
+	///Accepts and answers with `Alert::ServerBusy` right away
+	RefuseBusy
 }
 
 ///Server aсcepts or refuses incoming connections
 pub struct Server{
-	listener: TcpListener
+	listener: TcpListener,
+	slots: Arc<Slots>,
+	when_full: WhenFull,
+	registry: Arc<Registry>,
+	shutdown: Arc<Signal>,
+	max_message_size: u64,
+	keepalive: Option<Keepalive>,
+	padding: Padding,
+	cover_traffic: Option<CoverTraffic>,
+	handshake_concurrency: usize,
+	handshake_deadlines: HandshakeDeadlines,
+	admission: Option<Arc<dyn Admission>>,
+	firewall: Arc<Firewall>,
+	throttle: Arc<Throttle>,
+	connection_limits: BandwidthLimits,
+	compression: Vec<Compression>,
+	retry_cookies: RetryCookies,
+	puzzles: Option<PuzzlePolicy>,
+	cookie_jar: Arc<CookieJar>,
+	solved_puzzles: Arc<SolvedPuzzles>,
+	running_handshakes: Arc<AtomicUsize>
+}
+
+///Everything a handshake task needs to know about the server
+#[derive(Clone)]
+struct HandshakeSettings{
+	password: Option<[u8; 32]>,
+	max_message_size: u64,
+	keepalive: Option<Keepalive>,
+	padding: Padding,
+	cover_traffic: Option<CoverTraffic>,
+	throttle: Arc<Throttle>,
+	connection_limits: BandwidthLimits,
+	compression: Vec<Compression>,
+	deadlines: HandshakeDeadlines,
+	admission: Option<Arc<dyn Admission>>,
+	retry_cookies: RetryCookies,
+	puzzles: Option<PuzzlePolicy>,
+	cookie_jar: Arc<CookieJar>,
+	solved_puzzles: Arc<SolvedPuzzles>,
+	running_handshakes: Arc<AtomicUsize>,
+	registry: Arc<Registry>,
+	shutdown: Arc<Signal>
+}
+
+///Accepts connections that get through the firewall and have a slot
+#[derive(Clone)]
+struct Acceptor{
+	listener: TcpListener,
+	firewall: Arc<Firewall>,
+	slots: Arc<Slots>,
+	when_full: WhenFull,
+	shutdown: Arc<Signal>
+}
+
+impl Acceptor{
+	///Returns None once the server is shutting down
+	async fn accept(&self) -> Option<(TcpStream, SocketAddr, Slot)>{
+		let accepted = async {
+			loop {
+				let slot = match self.when_full{
+					WhenFull::StopAccepting => Some(self.slots.take().await),
+					WhenFull::RefuseBusy => None
+				};
+
+				let Ok((sock, addr)) = self.listener.accept().await else {
+					continue;
+				};
+
+				if !self.firewall.check(addr.ip()){
+					continue;
+				}
+
+				match slot.or_else(|| self.slots.try_take()){
+					Some(slot) => return Some((sock, addr, slot)),
+					None => {
+						async_std::task::spawn(refuse_busy(sock));
+					}
+				}
+			}
+		};
+
+		futures_lite::future::or(accepted, async {
+			self.shutdown.wait().await;
+			None
+		}).await
+	}
 }
 
 impl Server{
 	pub async fn new(address: std::net::SocketAddr) -> io::Result<Server>{
 		let listener = TcpListener::bind(address).await?;
 		Ok(
-			Server { listener }
+			Server {
+				listener,
+				slots: Slots::new(None),
+				when_full: WhenFull::StopAccepting,
+				registry: Registry::new(),
+				shutdown: Signal::new(),
+				max_message_size: client::DEFAULT_MAX_MESSAGE_SIZE,
+				keepalive: None,
+				padding: Padding::None,
+				cover_traffic: None,
+				handshake_concurrency: DEFAULT_HANDSHAKE_CONCURRENCY,
+				handshake_deadlines: HandshakeDeadlines::default(),
+				admission: None,
+				firewall: Arc::new(Firewall::new()),
+				throttle: Arc::new(Throttle::default()),
+				connection_limits: BandwidthLimits::default(),
+				compression: Vec::new(),
+				retry_cookies: RetryCookies::Never,
+				puzzles: None,
+				cookie_jar: Arc::new(CookieJar::new()),
+				solved_puzzles: Arc::new(SolvedPuzzles::default()),
+				running_handshakes: Arc::new(AtomicUsize::new(0))
+			}
 		)
 	}
 
-	///just listens for incoming connections wihout any checkings and returns Client instance
+	#[inline]
+	pub fn local_addr(&self) -> io::Result<std::net::SocketAddr>{
+		self.listener.local_addr()
+	}
+
+	#[inline]
+	pub fn get_max_message_size(&self) -> u64{
+		self.max_message_size
+	}
+
+	///Sets the incoming message limit for every connection accepted after this call. See `Client::set_max_message_size`
+	#[inline]
+	pub fn set_max_message_size(&mut self, size: u64){
+		self.max_message_size = size;
+	}
+
+	#[inline]
+	pub fn get_keepalive(&self) -> Option<Keepalive>{
+		self.keepalive
+	}
+
+	///Sets pings for every connection accepted after this call. See `Client::set_keepalive`
+	#[inline]
+	pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>){
+		self.keepalive = keepalive;
+	}
+
+	#[inline]
+	pub fn get_padding(&self) -> Padding{
+		self.padding
+	}
+
+	///Sets record padding for every connection accepted after this call. See `Client::set_padding`
+	#[inline]
+	pub fn set_padding(&mut self, padding: Padding){
+		self.padding = padding;
+	}
+
+	#[inline]
+	pub fn get_cover_traffic(&self) -> Option<CoverTraffic>{
+		self.cover_traffic
+	}
+
+	///Sets cover traffic for every connection accepted after this call. See `Client::set_cover_traffic`
+	#[inline]
+	pub fn set_cover_traffic(&mut self, cover_traffic: Option<CoverTraffic>){
+		self.cover_traffic = cover_traffic;
+	}
+
+	#[inline]
+	pub fn get_handshake_concurrency(&self) -> usize{
+		self.handshake_concurrency
+	}
+
+	///Sets how many handshakes `incoming` may run at the same time. Streams created before the call keep their limit
+	#[inline]
+	pub fn set_handshake_concurrency(&mut self, limit: usize){
+		self.handshake_concurrency = limit.max(1);
+	}
+
+	#[inline]
+	pub fn get_handshake_deadlines(&self) -> HandshakeDeadlines{
+		self.handshake_deadlines
+	}
+
+	///Sets time limits for handshakes started after this call. A client that misses them is disconnected
+	#[inline]
+	pub fn set_handshake_deadlines(&mut self, deadlines: HandshakeDeadlines){
+		self.handshake_deadlines = deadlines;
+	}
+
+	///Sets the hook that decides which peers are let in. Rejected peers get `Alert::Rejected` with the reason
+	#[inline]
+	pub fn set_admission(&mut self, admission: impl Admission + 'static){
+		self.admission = Some(Arc::new(admission));
+	}
+
+	#[inline]
+	pub fn remove_admission(&mut self){
+		self.admission = None;
+	}
+
+	#[inline]
+	pub fn get_retry_cookies(&self) -> RetryCookies{
+		self.retry_cookies
+	}
+
+	///Sets when clients are sent back for a retry cookie before the server does any KEM work for them. Off by default
+	#[inline]
+	pub fn set_retry_cookies(&mut self, mode: RetryCookies){
+		self.retry_cookies = mode;
+	}
+
+	#[inline]
+	pub fn get_puzzles(&self) -> Option<PuzzlePolicy>{
+		self.puzzles
+	}
+
+	///Sets when and how hard clients have to work before the server does any KEM work for them, None turns puzzles off.
+	///While a puzzle is asked for, a retry cookie isn't enough
+	#[inline]
+	pub fn set_puzzles(&mut self, policy: Option<PuzzlePolicy>){
+		self.puzzles = policy;
+	}
+
+	///Number of connections that are being handshaked or were accepted and aren't dropped yet
+	#[inline]
+	pub fn get_live_connections(&self) -> usize{
+		self.slots.get_live()
+	}
+
+	#[inline]
+	pub fn get_max_connections(&self) -> Option<usize>{
+		self.slots.get_limit()
+	}
+
+	///Sets how many connections may be live at the same time, None means no limit. Every accepted `Client` holds its slot until it's dropped.
+	///Takes effect on running `incoming` streams too, live connections over a lowered limit aren't closed
+	#[inline]
+	pub fn set_max_connections(&mut self, limit: Option<usize>){
+		self.slots.set_limit(limit);
+	}
+
+	#[inline]
+	pub fn get_when_full(&self) -> WhenFull{
+		self.when_full
+	}
+
+	///Sets what happens to new connections once the limit is reached. Streams created before the call keep the old behaviour
+	#[inline]
+	pub fn set_when_full(&mut self, when_full: WhenFull){
+		self.when_full = when_full;
+	}
+
+	#[inline]
+	fn acceptor(&self) -> Acceptor{
+		Acceptor{
+			listener: self.listener.clone(),
+			firewall: self.firewall.clone(),
+			slots: self.slots.clone(),
+			when_full: self.when_full,
+			shutdown: self.shutdown.clone()
+		}
+	}
+
+	///Returns a handle to the live connections: lookup by `ConnectionId`, broadcast, sending to one of them and disconnecting them
+	#[inline]
+	pub fn connections(&self) -> Connections{
+		Connections::new(self.registry.clone())
+	}
+
+	///Returns a handle that stops the server cleanly, see `ShutdownHandle::shutdown`
+	#[inline]
+	pub fn shutdown_handle(&self) -> ShutdownHandle{
+		ShutdownHandle::new(self.shutdown.clone(), self.registry.clone())
+	}
+
+	#[inline]
+	pub fn get_compression(&self) -> &[Compression]{
+		&self.compression
+	}
+
+	///Sets the compression algorithms handshaked clients may choose from, see `Client::set_compression`. None by default.
+	///Algorithms whose feature is off are left out
+	#[inline]
+	pub fn set_compression(&mut self, algorithms: Vec<Compression>){
+		self.compression = algorithms.into_iter().filter(|compression| compression.is_available()).collect();
+	}
+
+	#[inline]
+	pub fn get_connection_limits(&self) -> BandwidthLimits{
+		self.connection_limits
+	}
+
+	///Sets the bandwidth limits every connection accepted from now on starts with. Running connections keep theirs,
+	///they can be changed one by one with `Connections::throttle`
+	#[inline]
+	pub fn set_connection_limits(&mut self, limits: BandwidthLimits){
+		self.connection_limits = limits;
+	}
+
+	///Returns the bandwidth limits of all the server's connections together. They can be changed at any time
+	#[inline]
+	pub fn throttle(&self) -> Arc<Throttle>{
+		self.throttle.clone()
+	}
+
+	///Returns the firewall that drops connections before any handshake work is done.
+	///It's shared with the running `listen*` calls and `incoming` streams, so rules and counters can be changed and read at any time
+	#[inline]
+	pub fn firewall(&self) -> Arc<Firewall>{
+		self.firewall.clone()
+	}
+
+	#[inline]
+	fn handshake_settings(&self, password: Option<[u8; 32]>) -> HandshakeSettings{
+		HandshakeSettings{
+			password,
+			max_message_size: self.max_message_size,
+			keepalive: self.keepalive,
+			padding: self.padding,
+			cover_traffic: self.cover_traffic,
+			throttle: self.throttle.clone(),
+			connection_limits: self.connection_limits,
+			compression: self.compression.clone(),
+			deadlines: self.handshake_deadlines,
+			admission: self.admission.clone(),
+			retry_cookies: self.retry_cookies,
+			puzzles: self.puzzles,
+			cookie_jar: self.cookie_jar.clone(),
+			solved_puzzles: self.solved_puzzles.clone(),
+			running_handshakes: self.running_handshakes.clone(),
+			registry: self.registry.clone(),
+			shutdown: self.shutdown.clone()
+		}
+	}
+
+	///just listens for incoming connections wihout any checkings and returns Client instance.
+	///Only the peer address is checked by the admission hook, rejected peers get `Alert::Rejected` as their first record.
+	///Once the server is shutting down it never returns
 	pub async fn listen(&mut self) -> client::Client{
+		let acceptor = self.acceptor();
+
 		loop {
-			let sokandaddr = self.listener.accept().await;
-			
-			if sokandaddr.is_err(){
+			let Some((sock, addr, slot)) = acceptor.accept().await else {
+				return std::future::pending().await;
+			};
+
+			let deadline = Deadline::start(self.handshake_deadlines);
+			if let Verdict::Reject(reason) = check_peer(&self.admission, addr, &deadline).await {
+				async_std::task::spawn(refuse_unhandshaked(sock, Alert::Rejected(reason), slot));
 				continue;
 			}
 
-			let (sock, _) = sokandaddr.unwrap();
+			let mut client = client::Client::from_stream(sock, crate::default_chacha20_cipher());
+			client.set_max_message_size(self.max_message_size);
+			client.set_keepalive(self.keepalive);
+			client.set_padding(self.padding);
+			client.set_cover_traffic(self.cover_traffic);
+			client.set_server_throttle(self.throttle.clone());
+			client.throttle().set_limits(self.connection_limits);
+			client.set_registration(self.registry.register(&client, addr, slot));
 
-			return client::Client::from_stream(sock, crate::default_chacha20_cipher());
+			return client;
 		}
 	}
 
-	///Listens and handshakes incoming connections if password matches(if it is)
+	///Listens and handshakes incoming connections if password matches(if it is). Returns None once the server is shutting down
 	pub async fn listen_handshaked(&mut self, break_on_fail: bool, password: Option<[u8; 32]>) -> Option<client::Client> {
+		let acceptor = self.acceptor();
+
 		loop {
-			let sokandaddr = self.listener.accept().await;
-			
-			if sokandaddr.is_err(){
-				continue;
+			let (sock, addr, slot) = acceptor.accept().await?;
+
+			match handshake(sock, addr, slot, self.handshake_settings(password)).await{
+				Ok(client) => return Some(client),
+				//somebody who doesn't speak korneplod at all or a client sent for a cookie or a puzzle, not a failed handshake
+				Err(e) if e.kind() == ErrorKind::Unsupported || e.kind() == ErrorKind::Interrupted => continue,
+				Err(_) if break_on_fail => return None,
+				Err(_) => continue
 			}
+		}
+	}
 
-			let (mut sock, _) = sokandaddr.unwrap();
+	///Returns an endless stream of handshaked connections. Every handshake runs as a separate task,
+	///so a stalled client only takes one of `get_handshake_concurrency` slots and doesn't hold up the others.
+	///Failed handshakes are skipped. The stream ends once the server is shutting down
+	pub fn incoming(&self, password: Option<[u8; 32]>) -> impl Stream<Item = client::Client> + Send + 'static {
+		let settings = self.handshake_settings(password);
 
-			let mut check_buf = [0u8; 3];
-			//1
-			let _ = sock.read(&mut check_buf).await;//r1
+		futures::stream::unfold(self.acceptor(), |acceptor| async move {
+			let accepted = acceptor.accept().await?;
+			Some((accepted, acceptor))
+		})
+		.map(move |(sock, addr, slot)| async_std::task::spawn(handshake(sock, addr, slot, settings.clone())))
+		.buffer_unordered(self.handshake_concurrency)
+		.filter_map(|res| async move { res.ok() })
+	}
 
-			if !check_buf.eq(&[2u8, 2u8, 8]){///////////////
-				continue;
-			}
+	///Runs `router` over every connection from `incoming`, each one on its own task. Returns once the server is shutting down
+	pub async fn serve(&self, password: Option<[u8; 32]>, router: Router){
+		self.serve_with(password, move |client| {
+			let router = router.clone();
+			async move { router.run(client).await }
+		}).await
+	}
 
-			let mut buf = [0u8; 1568];//missing nonce
-			//3
-			if sock.read(&mut buf).await.is_err(){//r2
-				if break_on_fail{
-					return None;
-				}
-				continue;
-			}
-			//4
-			let mut rng = rand::thread_rng();
-			let enc_key = crate::kem::enc_key_from_bytes(buf.to_vec());
-			let ae = crate::kem::encapsulate(&mut rng, &enc_key);
-
-			continue_or_break!(ae.is_none(), break_on_fail);
-			let (encapsulated, key) = ae.unwrap();
-
-			//5
-			continue_or_break!(sock.write_all(&encapsulated).await.is_err(), break_on_fail);//w1
-			let en = crate::kem::encapsulate(&mut rng, &enc_key);
-
-			continue_or_break!(en.is_none(), break_on_fail);
-			let (encapsulated_nonce, nonce) = en.unwrap();
-			//6
-			continue_or_break!(sock.write_all(&encapsulated_nonce).await.is_err(), break_on_fail);//w2
-			let nonce = crate::tools::derive_nonce(&nonce);
-
-			let cipher = chacha20::ChaCha20::new_from_slices(&key, &nonce);
-			res_to_none!(cipher);
-			let mut cipher = cipher.unwrap();
-			let mut check_buf =[0u8; 19];
-			//7
-			if sock.read(&mut check_buf).await.is_err() {//r3
-				if break_on_fail{
-					return None;
-				}
-				continue;
-			}
-			let check_buf_copy = check_buf;
-			cipher.apply_keystream(&mut check_buf);
-			//8
-			continue_or_break!(!(check_buf[0] == 2 && check_buf[1] == 2 && check_buf[2] == 8), break_on_fail);
-			res_to_none!(sock.write_all(&check_buf_copy).await);//w3
-
-			if password.is_none(){
-				let cipher = chacha20::ChaCha20::new_from_slices(&key, &nonce);
-				res_to_none!(cipher);
-				let cipher = cipher.unwrap();
-				return Some(client::Client::from_stream(sock, cipher));
-			}
+	///Runs `broker` over every connection from `incoming`, each one on its own task. Returns once the server is shutting down
+	pub async fn serve_broker(&self, password: Option<[u8; 32]>, broker: Broker){
+		self.serve_with(password, move |client| {
+			let broker = broker.clone();
+			async move { broker.run(client).await }
+		}).await
+	}
+
+	///Spawns the future `run` makes of every connection from `incoming`. Returns once the server is shutting down
+	async fn serve_with<F, Fut>(&self, password: Option<[u8; 32]>, run: F)
+	where F: Fn(client::Client) -> Fut, Fut: Future<Output = io::Result<()>> + Send + 'static{
+		self.incoming(password).for_each(|client| {
+			let connection = run(client);
+			async_std::task::spawn(async move {
+				let _ = connection.await;
+			});
+
+			async {}
+		}).await
+	}
+}
+
+///Alert sent before the handshake is done, goes unencrypted
+fn plain_alert(alert: &Alert) -> Vec<u8>{
+	let alert_bytes = alert.as_bytes();
+	let len = alert_bytes.len().min(u16::MAX as usize);
+
+	let mut answer = vec![client::HANDSHAKE_ALERT];
+	answer.extend_from_slice(&(len as u16).to_be_bytes());
+	answer.extend_from_slice(&alert_bytes[..len]);
+
+	answer
+}
+
+///Tells a peer over the connection limit that the server is busy.
+///Its first flight is drained for a moment, so the alert isn't lost in a reset
+async fn refuse_busy(mut sock: TcpStream){
+	let _ = async_std::future::timeout(BUSY_LINGER, async {
+		sock.write_all(&plain_alert(&Alert::ServerBusy)).await?;
+		sock.shutdown(std::net::Shutdown::Write)?;
+
+		drain(sock).await
+	}).await;
+}
+
+///Tells a peer of `listen` why it's refused. There's no handshake, so the alert goes as a record with the default cipher.
+///Like in `refuse_busy` its records are drained for a moment, the connection keeps its slot meanwhile
+async fn refuse_unhandshaked(sock: TcpStream, alert: Alert, _slot: Slot){
+	let writer = client::Client::from_stream(sock.clone(), crate::default_chacha20_cipher()).get_writer();
+
+	let _ = async_std::future::timeout(BUSY_LINGER, async {
+		writer.close(&alert).await?;
+		drain(sock).await
+	}).await;
+}
+
+///Reads and throws away whatever the peer sends until it closes the connection
+async fn drain(mut sock: TcpStream) -> io::Result<()>{
+	let mut buf = [0u8; 2048];
+	while sock.read(&mut buf).await? != 0 {}
+
+	Ok(())
+}
+
+///Runs the admission hook's `check_peer` within a step of `deadline`. A hook that takes longer rejects the peer,
+///so a stalled hook doesn't keep the connection's slot
+async fn check_peer(admission: &Option<Arc<dyn Admission>>, addr: SocketAddr, deadline: &Deadline) -> Verdict{
+	let Some(admission) = admission else {
+		return Verdict::Accept;
+	};
+
+	deadline.run(async { Ok(admission.check_peer(addr).await) }).await
+		.unwrap_or_else(|_| Verdict::Reject("admission check timed out".to_string()))
+}
+
+///Server side of the handshake. Fails with `ErrorKind::Unsupported` if the peer doesn't even start with the korneplod greeting
+///and with `ErrorKind::Interrupted` if the peer was sent for a retry cookie or a puzzle.
+///The socket is shut down on failure, so the peer sees the connection closed right away
+async fn handshake(sock: TcpStream, addr: SocketAddr, slot: Slot, settings: HandshakeSettings) -> io::Result<client::Client> {
+	let teardown = sock.clone();
+	let _running = Running::start(&settings.running_handshakes);
+	let registry = settings.registry.clone();
+	let shutdown = settings.shutdown.clone();
+
+	let mut res = handshake_steps(sock, settings).await.map(|mut client| {
+		client.set_registration(registry.register(&client, addr, slot));
+		client
+	});
+
+	//registered before the check, so the connection is either seen by the shutdown or sees it here
+	if let Ok(client) = &res && shutdown.is_set() {
+		let _ = client.get_writer().close(&Alert::CloseNotify).await;
+		res = Err(Alert::CloseNotify.into());
+	}
+
+	if res.is_err(){
+		let _ = teardown.shutdown(std::net::Shutdown::Both);
+	}
+
+	res
+}
+
+///What the client brought to a busy server with its first flight
+#[derive(PartialEq, Eq)]
+enum Proof{
+	Nothing,
+	Cookie([u8; COOKIE_LEN]),
+	Solution([u8; CHALLENGE_LEN], u64)
+}
+
+///Counts a handshake as running for as long as it lives, even if its future is dropped halfway
+struct Running(Arc<AtomicUsize>);
+
+impl Running{
+	#[inline]
+	fn start(counter: &Arc<AtomicUsize>) -> Running{
+		counter.fetch_add(1, Ordering::Relaxed);
+		Running(counter.clone())
+	}
+}
+
+impl Drop for Running{
+	#[inline]
+	fn drop(&mut self){
+		self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+async fn handshake_steps(mut sock: TcpStream, settings: HandshakeSettings) -> io::Result<client::Client> {
+	let deadline = Deadline::start(settings.deadlines);
+	let addr = sock.peer_addr()?;
+
+	let verdict = check_peer(&settings.admission, addr, &deadline).await;
+	let mut check_buf = [0u8; 3];
+	//1
+	let greeting = deadline.read_exact(&mut sock, &mut check_buf).await;//r1
+
+	let proof = match check_buf{
+		_ if greeting.is_err() => return Err(Error::new(ErrorKind::Unsupported, "not a korneplod handshake")),
+		client::GREETING => Proof::Nothing,
+		client::GREETING_WITH_COOKIE => {
+			let mut cookie = [0u8; COOKIE_LEN];
+			deadline.read_exact(&mut sock, &mut cookie).await?;
+			Proof::Cookie(cookie)
+		}
+		client::GREETING_WITH_SOLUTION => {
+			let mut challenge = [0u8; CHALLENGE_LEN];
+			let mut solution = [0u8; 8];
+			deadline.read_exact(&mut sock, &mut challenge).await?;
+			deadline.read_exact(&mut sock, &mut solution).await?;
+			Proof::Solution(challenge, u64::from_be_bytes(solution))
+		}
+		_ => return Err(Error::new(ErrorKind::Unsupported, "not a korneplod handshake"))
+	};
+
+	let mut buf = [0u8; 1568];//missing nonce
+	//3
+	deadline.read_exact(&mut sock, &mut buf).await?;//r2
+
+	//cookies and puzzles are only asked for when the server is busy, but the ones that were sent are always checked.
+	//Only used puzzles are kept, the client comes back on a new connection
+	let ip = addr.ip();
+	let proven = match &proof{
+		Proof::Nothing => false,
+		Proof::Cookie(cookie) => settings.cookie_jar.check(&ip, cookie),
+		Proof::Solution(challenge, solution) => puzzle::check(&settings.cookie_jar, &settings.solved_puzzles, &ip, challenge, *solution)
+	};
+
+	let running = settings.running_handshakes.load(Ordering::Relaxed);
+	let difficulty = settings.puzzles.map(|policy| policy.difficulty(running)).unwrap_or(0);
+	//a puzzle solved when the server was calmer isn't enough once it asks for more
+	let solved = proven && matches!(&proof, Proof::Solution(challenge, _) if puzzle::get_difficulty(challenge) >= difficulty);
+
+	if difficulty > 0 && !solved {
+		let challenge = puzzle::issue(&settings.cookie_jar, &ip, difficulty);
+		deadline.write_all(&mut sock, &[&[client::HANDSHAKE_PUZZLE][..], &challenge[..]].concat()).await?;
+
+		return Err(Error::new(ErrorKind::Interrupted, "the client was sent for a puzzle"));
+	}
+
+	if !proven && (proof != Proof::Nothing || settings.retry_cookies.required(running)) {
+		let cookie = settings.cookie_jar.issue(&ip);
+		deadline.write_all(&mut sock, &[&[client::HANDSHAKE_RETRY][..], &cookie[..]].concat()).await?;
+
+		return Err(Error::new(ErrorKind::Interrupted, "the client was sent for a retry cookie"));
+	}
+
+	//the client is only told now, when it waits for an answer, so the alert isn't lost in a reset
+	if let Verdict::Reject(reason) = verdict {
+		let alert = Alert::Rejected(reason);
+		deadline.write_all(&mut sock, &plain_alert(&alert)).await?;
+		return Err(alert.into());
+	}
+
+	//4
+	let enc_key = kem::enc_key_from_bytes(buf.to_vec());
+	let (encapsulated, key) = kem::encapsulate(&mut rand::thread_rng(), &enc_key)
+		.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Cannot encapsulate key"))?;
+
+	//5
+	deadline.write_all(&mut sock, &[&[client::HANDSHAKE_PROCEED][..], &encapsulated[..]].concat()).await?;//w1
+	let (encapsulated_nonce, nonce) = kem::encapsulate(&mut rand::thread_rng(), &enc_key)
+		.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Cannot encapsulate nonce"))?;
+
+	//6
+	deadline.write_all(&mut sock, &encapsulated_nonce).await?;//w2
+	let nonce = crate::tools::derive_nonce(&nonce);
 
+	let mut cipher = client::handshake_cipher(&key, &nonce);
+	let mut check_buf =[0u8; 19];
+	//7
+	deadline.read_exact(&mut sock, &mut check_buf).await?;//r3
+
+	let check_buf_copy = check_buf;
+	cipher.apply_keystream(&mut check_buf);
+	//8
+	if !(check_buf[0] == 2 && check_buf[1] == 2 && check_buf[2] == 8) {
+		return Err(Error::new(ErrorKind::InvalidData, "the client's check doesn't match"));
+	}
+	deadline.write_all(&mut sock, &check_buf_copy).await?;//w3
+
+	let authenticated = match settings.password{
+		None => Ok(false),
+		Some(password) => {
 			let mut password_buf = [0u8; 32];
-			continue_or_break!(sock.read(&mut password_buf).await.is_err(), break_on_fail);//r4
-			
-			cipher.apply_keystream(&mut password_buf);
-//maybe here
+			deadline.read_exact(&mut sock, &mut password_buf).await?;//r4
+
+			client::password_cipher(&key, &nonce).apply_keystream(&mut password_buf);
+
+			match password_buf == password{
+				true => Ok(true),
+				false => Err(Verdict::Reject("wrong password".to_string()))
+			}
+		}
+	};
+
+	let mut client = client::Client::from_session(sock, &key, &nonce, false);
+	client.set_max_message_size(settings.max_message_size);
+	client.set_keepalive(settings.keepalive);
+	client.set_padding(settings.padding);
+	client.set_cover_traffic(settings.cover_traffic);
+	client.set_server_throttle(settings.throttle.clone());
+	client.throttle().set_limits(settings.connection_limits);
+	//r5
+	deadline.run(client.take_offer(&settings.compression)).await?;
+
+	let verdict = match (authenticated, &settings.admission){
+		(Err(verdict), _) => verdict,
+		(Ok(authenticated), Some(admission)) => admission.check_identity(&Identity{ addr, authenticated }).await,
+		(Ok(_), None) => Verdict::Accept
+	};
+
+	//w4
+	match verdict{
+		Verdict::Accept => deadline.run(client.send_welcome()).await?,
+		Verdict::Reject(reason) => {
+			let alert = Alert::Rejected(reason);
+			let _ = deadline.run(client.send_alert(&alert)).await;
+
+			return Err(alert.into());
+		}
+	}
+
+	Ok(client)
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn incoming_test(){
+		use crate::Message;
+		use std::io::Write;
+
+		let (server, addr) = testing::bind();
+		let mut incoming = Box::pin(server.incoming(None));
 
-			continue_or_break!(password_buf != password.unwrap(), break_on_fail);
+		//sends the greeting and goes quiet, the others must get through anyway
+		let mut stalled = std::net::TcpStream::connect(addr).unwrap();
+		stalled.write_all(&[2u8, 2u8, 8u8]).unwrap();
 
-			//9
-			let cipher = chacha20::ChaCha20::new_from_slices(&key, &buf[ buf.len() - 12..buf.len() ]);
-			res_to_none!(cipher);
+		let clients = (0..3u16).map(|i| testing::spawn(async move {
+			let mut client = testing::connect(addr).await;
+			client.send_message(Message::new(Vec::new(), i)).await.unwrap();
+		})).collect::<Vec<_>>();
 
-			let cipher = cipher.unwrap();
-			res_to_none!(sock.write_all(&password_buf).await);//w4
+		let mut codes = futures::executor::block_on(async_std::future::timeout(std::time::Duration::from_secs(10), async {
+			let mut codes = Vec::new();
 
-			return Some(client::Client::from_stream(sock, cipher));
+			for _ in 0..3 {
+				let mut client = incoming.next().await.unwrap();
+				codes.push(client.get_message().await.unwrap().get_code());
+			}
+
+			codes
+		})).unwrap();
+
+		codes.sort();
+		assert_eq!(codes, vec![0, 1, 2]);
+
+		for client in clients {
+			client.join().unwrap();
 		}
-	}	
+	}
+
+	#[test]
+	fn max_connections_test(){
+		use crate::client::Client;
+
+		let connect = |addr, step| testing::spawn(async move {
+			let mut client = Client::connect(addr, None).await.unwrap();
+			client.set_handshake_deadlines(HandshakeDeadlines{ step, total: step, min_rate: 0 });
+			client.handshake(None).await.map(|_| client)
+		});
+
+		let (mut server, addr) = testing::bind();
+		server.set_max_connections(Some(1));
+		server.set_when_full(WhenFull::RefuseBusy);
+		let mut incoming = Box::pin(server.incoming(None));
+
+		let first = connect(addr, Duration::from_secs(5));
+		let accepted = futures::executor::block_on(incoming.next()).unwrap();
+		let first = first.join().unwrap().unwrap();
+		assert_eq!(server.get_live_connections(), 1);
+
+		//the stream has to run to turn the second one away
+		let later = testing::spawn(async move { incoming.next().await.is_some() });
+
+		let err = connect(addr, Duration::from_secs(5)).join().unwrap().err().unwrap();
+		assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+		assert_eq!(Alert::from_error(&err), Some(&Alert::ServerBusy));
+
+		//dropping the accepted side gives the slot back
+		drop(accepted);
+		assert_eq!(server.get_live_connections(), 0);
+
+		let third = connect(addr, Duration::from_secs(5));
+		assert!(later.join().unwrap());
+		third.join().unwrap().unwrap();
+		drop(first);
+
+		//by default the server just stops accepting
+		let (mut server, other_addr) = testing::bind();
+		server.set_max_connections(Some(1));
+		let mut incoming = Box::pin(server.incoming(None));
+
+		let first = connect(other_addr, Duration::from_secs(5));
+		let accepted = futures::executor::block_on(incoming.next()).unwrap();
+		first.join().unwrap().unwrap();
+
+		let later = testing::spawn(async move { incoming.next().await.is_some() });
+
+		let err = connect(other_addr, Duration::from_millis(300)).join().unwrap().err().unwrap();
+		assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+		drop(accepted);
+		connect(other_addr, Duration::from_secs(5)).join().unwrap().unwrap();
+		assert!(later.join().unwrap());
+	}
 }
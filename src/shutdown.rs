@@ -0,0 +1,188 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use event_listener::Event;
+
+use crate::alert::Alert;
+use crate::registry::Registry;
+
+///Set once, when the server starts going down
+pub(crate) struct Signal{
+	set: AtomicBool,
+	event: Event
+}
+
+impl Signal{
+	#[inline]
+	pub(crate) fn new() -> Arc<Signal>{
+		Arc::new(Signal{ set: AtomicBool::new(false), event: Event::new() })
+	}
+
+	#[inline]
+	pub(crate) fn is_set(&self) -> bool{
+		self.set.load(Ordering::SeqCst)
+	}
+
+	#[inline]
+	pub(crate) fn set(&self){
+		self.set.store(true, Ordering::SeqCst);
+		self.event.notify(usize::MAX);
+	}
+
+	pub(crate) async fn wait(&self){
+		loop {
+			let set = self.event.listen();
+
+			if self.is_set(){
+				return;
+			}
+
+			set.await;
+		}
+	}
+}
+
+///Stops a `Server` cleanly. Clones stop the same server
+#[derive(Clone)]
+pub struct ShutdownHandle{
+	signal: Arc<Signal>,
+	registry: Arc<Registry>
+}
+
+impl ShutdownHandle{
+	#[inline]
+	pub(crate) fn new(signal: Arc<Signal>, registry: Arc<Registry>) -> ShutdownHandle{
+		ShutdownHandle{ signal, registry }
+	}
+
+	#[inline]
+	pub fn is_shutting_down(&self) -> bool{
+		self.signal.is_set()
+	}
+
+	///Stops accepting: `incoming` streams end and `listen_handshaked` returns None. Every live connection can't start sending anything new
+	///and gets `Alert::CloseNotify` once the messages it's sending right now, split ones included, have gone out.
+	///Then waits for the connections to be dropped, which happens once the peers have read everything and hung up.
+	///All of that takes up to `drain`, connections still there after it are closed by force.
+	///
+	///Returns the number of connections that had to be closed by force
+	pub async fn shutdown(&self, drain: Duration) -> usize{
+		let end = Instant::now() + drain;
+		self.signal.set();
+
+		let notify = self.registry.snapshot().into_iter().map(|(_, entry)| async move {
+			let _ = entry.writer.finish(&Alert::CloseNotify).await;
+		});
+
+		let _ = async_std::future::timeout(remaining(end), futures::future::join_all(notify)).await;
+		let _ = async_std::future::timeout(remaining(end), self.registry.emptied()).await;
+
+		let left = self.registry.snapshot();
+
+		for (_, entry) in &left {
+			let _ = entry.stream.shutdown(std::net::Shutdown::Both);
+		}
+
+		left.len()
+	}
+}
+
+#[inline]
+fn remaining(end: Instant) -> Duration{
+	end.saturating_duration_since(Instant::now())
+}
+
+#[cfg(test)]
+mod tests{
+	use crate::testing;
+
+	#[test]
+	fn shutdown_test(){
+		use crate::{alert::Alert, Message};
+		use crate::lanes::Priority;
+		use futures::StreamExt;
+		use std::time::Duration;
+
+		//big enough to be stuck halfway out while the peer doesn't read
+		const BULK: usize = 4 * 1024 * 1024;
+
+		let (server, addr) = testing::bind();
+		let handle = server.shutdown_handle();
+		let mut incoming = Box::pin(server.incoming(None));
+
+		//echoes until the peer hangs up, code 1 makes it hold the connection without reading, code 2 makes it send a split message
+		let server_side = testing::spawn(async move {
+			while let Some(mut client) = incoming.next().await {
+				async_std::task::spawn(async move {
+					while let Ok(mes) = client.get_message().await {
+						if mes.get_code() == 1 {
+							async_std::task::sleep(Duration::from_secs(10)).await;
+							return;
+						}
+
+						if mes.get_code() == 2 {
+							client.send_message_with_priority(Message::new(vec![7u8; BULK], 2), Priority::Low).await.unwrap();
+							continue;
+						}
+
+						if client.send_message(mes).await.is_err(){
+							return;
+						}
+					}
+				});
+			}
+		});
+
+		futures::executor::block_on(async {
+			let mut polite = testing::connect(addr).await;
+			polite.send_message(Message::new("echo", 0)).await.unwrap();
+			assert_eq!(polite.get_message().await.unwrap().get_content(), b"echo");
+
+			let mut stubborn = testing::connect(addr).await;
+			stubborn.send_message(Message::new("hold on", 1)).await.unwrap();
+
+			let mut busy = testing::connect(addr).await;
+			busy.send_message(Message::new("send a lot", 2)).await.unwrap();
+			async_std::task::sleep(Duration::from_millis(100)).await;
+
+			let shutting = testing::spawn(async move { handle.shutdown(Duration::from_secs(2)).await });
+
+			let err = polite.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::CloseNotify));
+			drop(polite);
+
+			//the message that was halfway out when the shutdown came is finished before the alert
+			let bulk = busy.get_message().await.unwrap();
+			assert!(bulk.get_content().len() == BULK && bulk.get_content().iter().all(|&byte| byte == 7));
+			let err = busy.get_message().await.err().unwrap();
+			assert_eq!(Alert::from_error(&err), Some(&Alert::CloseNotify));
+			drop(busy);
+
+			//the polite and the busy ones were drained, the one held by the server had to be closed
+			assert_eq!(shutting.join().unwrap(), 1);
+			drop(stubborn);
+		});
+
+		//the stream has ended
+		server_side.join().unwrap();
+	}
+}
@@ -0,0 +1,126 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use event_listener::Event;
+
+///Counts live connections of a server against its limit
+pub(crate) struct Slots{
+	live: AtomicUsize,
+	limit: AtomicUsize,
+	released: Event
+}
+
+///A taken slot, given back on drop
+pub(crate) struct Slot(Arc<Slots>);
+
+impl Slots{
+	#[inline]
+	pub(crate) fn new(limit: Option<usize>) -> Arc<Slots>{
+		Arc::new(Slots{
+			live: AtomicUsize::new(0),
+			limit: AtomicUsize::new(limit.unwrap_or(usize::MAX)),
+			released: Event::new()
+		})
+	}
+
+	#[inline]
+	pub(crate) fn get_live(&self) -> usize{
+		self.live.load(Ordering::Relaxed)
+	}
+
+	#[inline]
+	pub(crate) fn get_limit(&self) -> Option<usize>{
+		match self.limit.load(Ordering::Relaxed){
+			usize::MAX => None,
+			limit => Some(limit)
+		}
+	}
+
+	///Connections over a lowered limit aren't closed, new ones just wait until there's room
+	#[inline]
+	pub(crate) fn set_limit(&self, limit: Option<usize>){
+		self.limit.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+		self.released.notify(usize::MAX);
+	}
+
+	pub(crate) fn try_take(self: &Arc<Self>) -> Option<Slot>{
+		self.live.fetch_update(Ordering::AcqRel, Ordering::Relaxed, |live| {
+			(live < self.limit.load(Ordering::Relaxed)).then_some(live + 1)
+		}).ok()?;
+
+		Some(Slot(self.clone()))
+	}
+
+	///Waits until a slot is free
+	pub(crate) async fn take(self: &Arc<Self>) -> Slot{
+		loop {
+			if let Some(slot) = self.try_take(){
+				return slot;
+			}
+
+			//listening before the second try, so a release between the two isn't missed
+			let released = self.released.listen();
+
+			if let Some(slot) = self.try_take(){
+				return slot;
+			}
+
+			released.await;
+		}
+	}
+}
+
+impl Drop for Slot{
+	#[inline]
+	fn drop(&mut self){
+		self.0.live.fetch_sub(1, Ordering::AcqRel);
+		self.0.released.notify(1);
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	#[test]
+	fn slots_test(){
+		let slots = Slots::new(Some(2));
+
+		let first = slots.try_take().unwrap();
+		let _second = slots.try_take().unwrap();
+		assert!(slots.try_take().is_none());
+		assert_eq!(slots.get_live(), 2);
+
+		let waiting = std::thread::spawn({
+			let slots = slots.clone();
+			move || futures::executor::block_on(slots.take())
+		});
+
+		std::thread::sleep(std::time::Duration::from_millis(50));
+		drop(first);
+
+		let _third = waiting.join().unwrap();
+		assert_eq!(slots.get_live(), 2);
+
+		slots.set_limit(None);
+		assert!(slots.try_take().is_some());
+		assert_eq!(slots.get_live(), 2);
+	}
+}
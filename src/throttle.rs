@@ -0,0 +1,422 @@
+/*
+Korneplod
+Copyright (C) 2025 grygory zhimolost'
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Bandwidth limits of connections.
+//!
+//! Every connection has its own `Throttle` (`Client::throttle`), connections of a `Server` also share the server's one (`Server::throttle`).
+//! A record is paid for in both before it's written, and a received one before the next record is read, so a sender over the limit
+//! waits in `send_message` and a receiver over the limit stops reading, which makes the peer's sending wait too. Nothing is dropped,
+//! and a send or a receive given up on while it waits leaves the connection as it was.
+//!
+//! A record bigger than the burst waits until the bucket is full and leaves it in debt, the next records wait for the debt to be paid off
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use event_listener::Event;
+
+///Token bucket in bytes: `burst` bytes at once, refilled at `per_second`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bandwidth{
+	pub burst: u64,
+	pub per_second: u64
+}
+
+///Limits of both directions, None is unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthLimits{
+	pub send: Option<Bandwidth>,
+	pub receive: Option<Bandwidth>
+}
+
+struct Bucket{
+	limit: Option<Bandwidth>,
+	//below zero after a record bigger than the burst
+	tokens: f64,
+	updated: Instant
+}
+
+impl Bucket{
+	#[inline]
+	fn new(limit: Option<Bandwidth>) -> Bucket{
+		Bucket{ limit, tokens: limit.map_or(0.0, |limit| limit.burst as f64), updated: Instant::now() }
+	}
+
+	#[inline]
+	fn refill(&mut self, limit: &Bandwidth, now: Instant){
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * limit.per_second as f64).min(limit.burst as f64);
+		self.updated = now;
+	}
+}
+
+///One direction
+struct Limiter{
+	bucket: Mutex<Bucket>,
+	//a new limit can make the waiting shorter
+	changed: Event
+}
+
+impl Limiter{
+	#[inline]
+	fn new(limit: Option<Bandwidth>) -> Limiter{
+		Limiter{ bucket: Mutex::new(Bucket::new(limit)), changed: Event::new() }
+	}
+
+	#[inline]
+	fn bucket(&self) -> MutexGuard<'_, Bucket>{
+		self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	#[inline]
+	fn get_limit(&self) -> Option<Bandwidth>{
+		self.bucket().limit
+	}
+
+	fn set_limit(&self, limit: Option<Bandwidth>){
+		let mut bucket = self.bucket();
+		let now = Instant::now();
+
+		match (bucket.limit, limit){
+			(Some(old), Some(new)) => {
+				bucket.refill(&old, now);
+				bucket.tokens = bucket.tokens.min(new.burst as f64);
+			},
+			_ => *bucket = Bucket::new(limit)
+		}
+
+		bucket.limit = limit;
+		drop(bucket);
+
+		self.changed.notify(usize::MAX);
+	}
+
+	///Waits until `bytes` can go through
+	async fn take(&self, bytes: u64){
+		loop {
+			let changed = self.changed.listen();
+
+			let wait = {
+				let mut bucket = self.bucket();
+				let Some(limit) = bucket.limit else { return };
+
+				bucket.refill(&limit, Instant::now());
+
+				let needed = bytes.min(limit.burst) as f64;
+				if bucket.tokens >= needed {
+					bucket.tokens -= bytes as f64;
+					return;
+				}
+
+				match limit.per_second{
+					0 => None,
+					per_second => Some(Duration::from_secs_f64((needed - bucket.tokens) / per_second as f64))
+				}
+			};
+
+			match wait{
+				Some(wait) => {
+					futures_lite::future::or(async_std::task::sleep(wait), changed).await;
+				},
+				None => changed.await
+			}
+		}
+	}
+
+	///Gives back what `take` took, as far as the burst allows
+	fn refund(&self, bytes: u64){
+		let mut bucket = self.bucket();
+		let Some(limit) = bucket.limit else { return };
+
+		bucket.refill(&limit, Instant::now());
+		bucket.tokens = (bucket.tokens + bytes as f64).min(limit.burst as f64);
+		drop(bucket);
+
+		self.changed.notify(usize::MAX);
+	}
+}
+
+//Refunds a payment to the connection's throttle if the server's one is given up on
+struct Refund<'a>{
+	limiter: &'a Limiter,
+	bytes: u64
+}
+
+impl Drop for Refund<'_>{
+	#[inline]
+	fn drop(&mut self){
+		self.limiter.refund(self.bytes);
+	}
+}
+
+//Pays `bytes` to both, nothing stays paid if it's given up on while it waits for the second
+async fn pay(first: &Limiter, second: Option<&Limiter>, bytes: u64){
+	first.take(bytes).await;
+
+	let Some(second) = second else { return };
+	let refund = Refund{ limiter: first, bytes };
+
+	second.take(bytes).await;
+	std::mem::forget(refund);
+}
+
+///Caps the bytes a connection, or all connections of a server, send and receive.
+///Every method takes `&self`, so the limits can be changed while the connections are running
+pub struct Throttle{
+	send: Limiter,
+	receive: Limiter
+}
+
+impl Default for Throttle{
+	#[inline]
+	fn default() -> Throttle{
+		Throttle::new(BandwidthLimits::default())
+	}
+}
+
+impl Throttle{
+	#[inline]
+	pub fn new(limits: BandwidthLimits) -> Throttle{
+		Throttle{ send: Limiter::new(limits.send), receive: Limiter::new(limits.receive) }
+	}
+
+	#[inline]
+	pub fn get_limits(&self) -> BandwidthLimits{
+		BandwidthLimits{ send: self.send.get_limit(), receive: self.receive.get_limit() }
+	}
+
+	///Takes effect at once, for the records already waiting too
+	pub fn set_limits(&self, limits: BandwidthLimits){
+		self.send.set_limit(limits.send);
+		self.receive.set_limit(limits.receive);
+	}
+
+	#[inline]
+	pub fn set_send_limit(&self, limit: Option<Bandwidth>){
+		self.send.set_limit(limit);
+	}
+
+	#[inline]
+	pub fn set_receive_limit(&self, limit: Option<Bandwidth>){
+		self.receive.set_limit(limit);
+	}
+}
+
+///Throttles a connection pays to, its own one and the server's one
+pub(crate) struct Throttles{
+	connection: Arc<Throttle>,
+	server: Mutex<Option<Arc<Throttle>>>
+}
+
+impl Throttles{
+	#[inline]
+	pub(crate) fn new() -> Arc<Throttles>{
+		Arc::new(Throttles{ connection: Arc::new(Throttle::default()), server: Mutex::new(None) })
+	}
+
+	#[inline]
+	pub(crate) fn get_connection(&self) -> Arc<Throttle>{
+		self.connection.clone()
+	}
+
+	#[inline]
+	pub(crate) fn set_server(&self, throttle: Arc<Throttle>){
+		*self.server.lock().unwrap_or_else(|e| e.into_inner()) = Some(throttle);
+	}
+
+	#[inline]
+	fn get_server(&self) -> Option<Arc<Throttle>>{
+		self.server.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+
+	pub(crate) async fn sent(&self, bytes: u64){
+		let server = self.get_server();
+		pay(&self.connection.send, server.as_ref().map(|server| &server.send), bytes).await;
+	}
+
+	pub(crate) async fn received(&self, bytes: u64){
+		let server = self.get_server();
+		pay(&self.connection.receive, server.as_ref().map(|server| &server.receive), bytes).await;
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::testing;
+
+	#[test]
+	fn throttle_test(){
+		futures::executor::block_on(async {
+			const LIMIT: Bandwidth = Bandwidth{ burst: 1000, per_second: 10_000 };
+
+			let throttle = Throttle::new(BandwidthLimits{ send: Some(LIMIT), receive: None });
+			assert_eq!(throttle.get_limits(), BandwidthLimits{ send: Some(LIMIT), receive: None });
+
+			//the burst goes at once, unlimited direction never waits
+			let start = Instant::now();
+			throttle.send.take(1000).await;
+			throttle.receive.take(1_000_000).await;
+			let burst = start.elapsed();
+
+			//2000 bytes more take 200ms
+			let start = Instant::now();
+			throttle.send.take(1000).await;
+			throttle.send.take(1000).await;
+			let paced = start.elapsed();
+			assert!(paced >= Duration::from_millis(180) && burst * 4 < paced, "{burst:?} {paced:?}");
+
+			//bigger than the burst: waits for a full bucket and goes into debt
+			let start = Instant::now();
+			throttle.send.take(3000).await;
+			throttle.send.take(1).await;
+			assert!(start.elapsed() >= Duration::from_millis(280));
+
+			//a stopped bucket waits until the limit is lifted
+			throttle.set_send_limit(Some(Bandwidth{ burst: 1000, per_second: 0 }));
+			let start = Instant::now();
+			futures::join!(throttle.send.take(1000), async {
+				async_std::task::sleep(Duration::from_millis(50)).await;
+				throttle.set_send_limit(None);
+			});
+			assert!(start.elapsed() >= Duration::from_millis(50));
+		});
+	}
+
+	#[test]
+	fn refund_test(){
+		futures::executor::block_on(async {
+			let throttles = Throttles::new();
+			throttles.get_connection().set_send_limit(Some(Bandwidth{ burst: 1000, per_second: 1 }));
+
+			//the server's bucket is empty and never refills
+			let server = Arc::new(Throttle::new(BandwidthLimits{ send: Some(Bandwidth{ burst: 1000, per_second: 0 }), receive: None }));
+			server.send.take(1000).await;
+			throttles.set_server(server.clone());
+
+			assert!(async_std::future::timeout(Duration::from_millis(50), throttles.sent(600)).await.is_err());
+
+			//the connection's bucket got its 600 back, without them it'd take 10 minutes
+			assert!(async_std::future::timeout(Duration::from_secs(1), throttles.connection.send.take(1000)).await.is_ok());
+		});
+	}
+
+	#[test]
+	fn cancelled_wait_test(){
+		use crate::Message;
+
+		//the burst is enough for one record, the next one waits practically forever
+		const LIMIT: Bandwidth = Bandwidth{ burst: 300, per_second: 1 };
+		const TIMEOUT: Duration = Duration::from_millis(100);
+
+		let (mut server, addr) = testing::bind();
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+			client.throttle().set_receive_limit(Some(LIMIT));
+
+			assert_eq!(client.get_message().await.unwrap().get_code(), 1);
+			assert_eq!(client.get_message().await.unwrap().get_code(), 2);
+			let err = client.get_message_with_timeout(TIMEOUT).await.err().unwrap();
+			assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+			client.throttle().set_receive_limit(None);
+			assert_eq!(client.get_message().await.unwrap().get_code(), 3);
+			assert_eq!(client.get_message().await.unwrap().get_code(), 4);
+
+			//the one given up on by the peer never came
+			assert_eq!(client.get_message().await.unwrap().get_code(), 6);
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+
+			for code in 1..=3 {
+				client.send_message(Message::new(vec![0u8; 200], code)).await.unwrap();
+			}
+
+			client.throttle().set_send_limit(Some(LIMIT));
+			client.send_message(Message::new(vec![0u8; 200], 4)).await.unwrap();
+			let err = client.send_message_with_timeout(Message::new(vec![0u8; 200], 5), TIMEOUT).await.err().unwrap();
+			assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+			client.throttle().set_send_limit(None);
+			client.send_message(Message::new(vec![0u8; 200], 6)).await.unwrap();
+
+			server_side.join().unwrap();
+		});
+	}
+
+	#[test]
+	fn throttled_connection_test(){
+		use crate::Message;
+
+		//50KB at once, then 200KB more take a second
+		const LIMIT: Bandwidth = Bandwidth{ burst: 50_000, per_second: 200_000 };
+		const CHUNK: usize = 50_000;
+
+		let (mut server, addr) = testing::bind();
+		server.set_connection_limits(BandwidthLimits{ send: None, receive: Some(LIMIT) });
+		let (throttle, connections) = (server.throttle(), server.connections());
+
+		let server_side = testing::spawn(async move {
+			let mut client = server.listen_handshaked(true, None).await.unwrap();
+			assert_eq!(client.throttle().get_limits().receive, Some(LIMIT));
+
+			//the last one is paid for before the next read, so four of them are waited for
+			let start = Instant::now();
+			for _ in 0..5 {
+				assert_eq!(client.get_message().await.unwrap().get_content().len(), CHUNK);
+			}
+			let limited = start.elapsed();
+			assert!(limited >= Duration::from_millis(700));
+
+			//lifted while running
+			connections.throttle(client.get_connection_id().unwrap()).unwrap().set_receive_limit(None);
+			let start = Instant::now();
+			for _ in 0..5 {
+				assert_eq!(client.get_message().await.unwrap().get_content().len(), CHUNK);
+			}
+			let lifted = start.elapsed();
+			assert!(lifted * 2 < limited, "{lifted:?} {limited:?}");
+
+			//the whole server is limited now
+			throttle.set_send_limit(Some(LIMIT));
+			for _ in 0..5 {
+				client.send_message(Message::new(vec![0u8; CHUNK], 1)).await.unwrap();
+			}
+		});
+
+		futures::executor::block_on(async {
+			let mut client = testing::connect(addr).await;
+			assert_eq!(client.throttle().get_limits(), BandwidthLimits::default());
+
+			for _ in 0..10 {
+				client.send_message(Message::new(vec![0u8; CHUNK], 1)).await.unwrap();
+			}
+
+			let start = Instant::now();
+			for _ in 0..5 {
+				assert_eq!(client.get_message().await.unwrap().get_content().len(), CHUNK);
+			}
+			assert!(start.elapsed() >= Duration::from_millis(900));
+
+			server_side.join().unwrap();
+		});
+	}
+}